@@ -4,6 +4,7 @@ An example of a stream using mini-redis
 Before running this, start the mini-redis server (cloned from https://github.com/tokio-rs/mini-redis/tree/master) and ensure the port is correct (command should be RUST_LOG=debug cargo run --bin mini-redis-server -- --port 6379)
 
 Have to clone it because our implementation of mini-redis does not include an implementation of subscriber, which is needed here
+    Update: our own server now supports PUBLISH/SUBSCRIBE as well, so this also works against it (cargo run --bin server)
 */
 
 use tokio_stream::StreamExt;
@@ -3,6 +3,7 @@ We will use the previous code to generate a stream, then modify the stream
 This will use functions we have used with iterators, like take(), filter() and map()
 
 Remember to start mini-redis server before trying this (see previous section for how, need to clone it from github)
+    Or just start our own server, which now supports PUBLISH/SUBSCRIBE (cargo run --bin server)
 */
 
 use mini_redis::client;
@@ -62,9 +62,36 @@ async fn main() {
 async fn process(socket: TcpStream, db: ShardedDb) {
     let mut connection = Connection::new(socket);
 
-    while let Some(frame) = connection.read_frame().await.unwrap() {
-        let response = match Command::from_frame(frame).unwrap() {
-            Set(cmd) => {
+    loop {
+        let frame = match connection.read_frame().await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return,
+            //the client closed the connection, so this task is done
+            Err(err) => {
+                println!("Closing connection: {}", err);
+                let _ = connection
+                    .write_frame(&Frame::Error(format!("ERR Protocol error: {}", err)))
+                    .await;
+                return;
+                /*
+                read_frame only fails when the bytes sent are not valid RESP (the redis protocol)
+                Whatever is left in the connection's buffer can't be trusted after that, so we tell the client what went wrong and hang up
+                Note how this only ends THIS task; every other connection keeps running, unlike the old unwrap() which would panic
+                */
+            }
+        };
+
+        let name = command_name(&frame);
+        //grab the name before from_frame consumes the frame, so it can be used in the error reply below
+
+        let response = match Command::from_frame(frame) {
+            Err(err) => Frame::Error(format!("ERR {}", err)),
+            /*
+            from_frame fails when the frame is not a command at all, or when a command has too few or too many arguments
+            Instead of panicking, the error is sent back as a Frame::Error
+            Redis errors start with a prefix (ERR here) so clients can tell what kind of error it is
+            */
+            Ok(Set(cmd)) => {
                 let key = cmd.key().to_string();
                 let value = cmd.value().clone();
 
@@ -93,7 +120,7 @@ async fn process(socket: TcpStream, db: ShardedDb) {
 
                 Frame::Simple("OK".to_string())
             }
-            Get(cmd) => {
+            Ok(Get(cmd)) => {
                 let key = cmd.key().to_string();
 
                 let mut hasher = DefaultHasher::new();
@@ -109,10 +136,26 @@ async fn process(socket: TcpStream, db: ShardedDb) {
                     Frame::Null
                 }
             }
-            cmd => panic!("unimplemented {:?}", cmd),
+            Ok(_) => Frame::Error(format!("ERR unknown command '{}'", name)),
+            //any other command is not supported by this server; the client is told so and the connection stays open
         };
 
         // Write the response to the client
-        connection.write_frame(&response).await.unwrap();
+        if let Err(err) = connection.write_frame(&response).await {
+            println!("Failed to write response: {}", err);
+            return;
+        }
     }
-}
\ No newline at end of file
+}
+
+fn command_name(frame: &Frame) -> String {
+    match frame {
+        Frame::Array(parts) => match parts.first() {
+            Some(Frame::Bulk(name)) => String::from_utf8_lossy(name).to_lowercase(),
+            Some(Frame::Simple(name)) => name.to_lowercase(),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    }
+}
+//commands arrive as an array frame, the first entry of which is the name of the command (i.e. "get" or "set")
\ No newline at end of file
@@ -0,0 +1,104 @@
+/*
+Throughput benchmark for the server, run against one that is already up:
+cargo run --release --bin server
+cargo run --release --bin bench
+*/
+
+use bytes::{BufMut, BytesMut};
+use std::io;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_official_tutorial_code_minis::codec::RespCodec;
+use tokio_official_tutorial_code_minis::config::Config;
+use tokio_official_tutorial_code_minis::frame::Frame;
+use tokio_util::codec::Decoder;
+
+/// Commands sent at each depth, half SETs and half GETs.
+const REQUESTS: usize = 100_000;
+
+/// Commands in flight at once: 1 waits for every reply before sending the
+/// next command, the others pipeline that many at a time.
+const DEPTHS: [usize; 3] = [1, 16, 256];
+
+#[tokio::main]
+async fn main() {
+    let config = Config::load_or_exit(Config::default());
+    println!("{} requests per depth against {}", REQUESTS, config.addr());
+    println!("{:>6} {:>14} {:>10}", "depth", "requests/sec", "seconds");
+    for depth in DEPTHS {
+        let mut socket = match TcpStream::connect(config.addr()).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                eprintln!("Failed to connect to {}: {}", config.addr(), err);
+                std::process::exit(1);
+            }
+        };
+        let started = Instant::now();
+        if let Err(err) = run(&mut socket, depth).await {
+            eprintln!("Benchmark failed at depth {}: {}", depth, err);
+            std::process::exit(1);
+        }
+        let elapsed = started.elapsed().as_secs_f64();
+        println!(
+            "{:>6} {:>14.0} {:>10.3}",
+            depth,
+            REQUESTS as f64 / elapsed,
+            elapsed
+        );
+    }
+}
+
+/// Sends `REQUESTS` commands in batches of `depth`, reading every reply of
+/// a batch before sending the next one.
+async fn run(socket: &mut TcpStream, depth: usize) -> io::Result<()> {
+    let mut out = Vec::new();
+    let mut buffer = BytesMut::with_capacity(64 * 1024);
+    let mut sent = 0;
+    while sent < REQUESTS {
+        let batch = depth.min(REQUESTS - sent);
+        out.clear();
+        for i in sent..sent + batch {
+            let key = format!("bench:{}", i % 1000);
+            if i % 2 == 0 {
+                encode(&mut out, &[b"SET", key.as_bytes(), b"value"]);
+            } else {
+                encode(&mut out, &[b"GET", key.as_bytes()]);
+            }
+        }
+        socket.write_all(&out).await?;
+
+        let mut replies = 0;
+        while replies < batch {
+            replies += count_replies(&mut buffer)?;
+            if replies < batch && socket.read_buf(&mut buffer).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        sent += batch;
+    }
+    Ok(())
+}
+
+/// Consumes every complete reply in `buffer` and returns how many there were.
+fn count_replies(buffer: &mut BytesMut) -> io::Result<usize> {
+    let mut codec = RespCodec::default();
+    let mut count = 0;
+    while let Some(frame) = codec.decode(buffer)? {
+        if let Frame::Error(err) = frame {
+            return Err(io::Error::other(err));
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Appends a command, as a RESP array of bulk strings, to `out`.
+fn encode(out: &mut Vec<u8>, args: &[&[u8]]) {
+    out.put_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.put_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.put_slice(arg);
+        out.put_slice(b"\r\n");
+    }
+}
@@ -5,8 +5,16 @@ cargo run --bin client
 
 
 use bytes::Bytes;
-use mini_redis::client;
+use futures::{SinkExt, StreamExt};
+use mini_redis::client::{self, Client};
+use std::collections::HashMap;
+use tokio::net::TcpStream;
 use tokio::sync::{mpsc, oneshot};
+use tokio_official_tutorial_code_minis::codec::RespCodec;
+use tokio_official_tutorial_code_minis::config::Config;
+use tokio_official_tutorial_code_minis::frame::Frame;
+use tokio_official_tutorial_code_minis::slot::{key_slot, KEY_SLOTS};
+use tokio_util::codec::Framed;
 
 #[derive(Debug)]
 enum Command {
@@ -34,12 +42,119 @@ the one off message is sent with a oneshot channel
     This will be used by the manager to send back values to the originator task
 */
 
+/// Sends each command to the node serving its key when the server is part of
+/// a cluster, and to the one server otherwise.
+struct Router {
+    /// Address of the node serving each hash slot.
+    slots: Vec<String>,
+    /// One connection per node, opened the first time a key routes there.
+    clients: HashMap<String, Client>,
+}
+
+/// How many MOVED replies a command follows before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+impl Router {
+    /// Learns the slot map from the server at `seed`.
+    async fn connect(seed: String) -> mini_redis::Result<Router> {
+        let mut router = Router {
+            slots: vec![seed.clone(); KEY_SLOTS as usize],
+            clients: HashMap::new(),
+        };
+        /*
+        mini_redis::client::Client only knows a few commands, so CLUSTER SLOTS is sent as a raw frame through RespCodec
+        every entry of the reply is [first slot, last slot, [host, port, node id]]
+        a server without cluster support replies with an error, and keeps serving every slot itself
+        */
+        let mut connection = Framed::new(TcpStream::connect(&seed).await?, RespCodec::default());
+        let request = ["CLUSTER", "SLOTS"].map(|arg| Frame::Bulk(arg.into()));
+        connection.send(Frame::Array(request.to_vec())).await?;
+        if let Some(Frame::Array(ranges)) = connection.next().await.transpose()? {
+            for range in ranges {
+                let Frame::Array(range) = range else {
+                    return Err("malformed CLUSTER SLOTS reply".into());
+                };
+                match &range[..] {
+                    [Frame::Integer(first), Frame::Integer(last), Frame::Array(node), ..] => {
+                        let addr = match &node[..] {
+                            [Frame::Bulk(host), Frame::Integer(port), ..] => {
+                                format!("{}:{}", String::from_utf8_lossy(host), port)
+                            }
+                            _ => return Err("malformed CLUSTER SLOTS reply".into()),
+                        };
+                        for slot in *first as usize..=*last as usize {
+                            router.slots[slot] = addr.clone();
+                        }
+                    }
+                    _ => return Err("malformed CLUSTER SLOTS reply".into()),
+                }
+            }
+        }
+        Ok(router)
+    }
+
+    /// The connection to the node serving `key`.
+    async fn client(&mut self, key: &str) -> mini_redis::Result<&mut Client> {
+        let addr = &self.slots[key_slot(key.as_bytes()) as usize];
+        if !self.clients.contains_key(addr) {
+            let client = client::connect(addr).await?;
+            self.clients.insert(addr.clone(), client);
+        }
+        Ok(self.clients.get_mut(addr).unwrap())
+    }
+
+    /// Updates the slot map if `err` is a `MOVED slot host:port` reply, and
+    /// returns whether it was.
+    fn follow_moved(&mut self, err: &mini_redis::Error) -> bool {
+        let message = err.to_string();
+        let mut words = message.split(' ');
+        match (words.next(), words.next(), words.next()) {
+            (Some("MOVED"), Some(slot), Some(addr)) => match slot.parse::<u16>() {
+                Ok(slot) if slot < KEY_SLOTS => {
+                    self.slots[slot as usize] = addr.to_string();
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    async fn get(&mut self, key: &str) -> mini_redis::Result<Option<Bytes>> {
+        for _ in 0..MAX_REDIRECTS {
+            match self.client(key).await?.get(key).await {
+                Err(err) if self.follow_moved(&err) => continue,
+                result => return result,
+            }
+        }
+        Err("too many redirects".into())
+    }
+
+    async fn set(&mut self, key: &str, value: Bytes) -> mini_redis::Result<()> {
+        for _ in 0..MAX_REDIRECTS {
+            match self.client(key).await?.set(key, value.clone()).await {
+                Err(err) if self.follow_moved(&err) => continue,
+                result => return result,
+            }
+        }
+        Err("too many redirects".into())
+    }
+}
+/*
+the router is what the manager task below talks to instead of a single client
+    a key is hashed to one of 16384 slots, and the slot map says which node serves that slot
+    if the map is out of date, the node replies with MOVED and the router retries where it is told to
+*/
+
 #[tokio::main]
 async fn main() {
+    let config = Config::load_or_exit(Config::default());
+    //the server address comes from the shared config (127.0.0.1:6379 unless overridden with --port etc.)
+
     let (sender_cloneable, mut receiver) = mpsc::channel(32);
 
     let manager = tokio::spawn(async move {
-        let mut client = client::connect("127.0.0.1:6379").await.unwrap();
+        let mut router = Router::connect(config.addr().to_string()).await.unwrap();
 
         use Command::*;
         while let Some(command) = receiver.recv().await {
@@ -51,13 +166,13 @@ async fn main() {
                     //response here is not used but in a production environment, we would probably want a 200 or something to be sent back to the originator, indicating that the set operation was a success
 
                 } => {
-                    client.set(&key, value).await.unwrap();
+                    router.set(&key, value).await.unwrap();
                 }
                 Get { 
                     key,
                     response
                 } => {
-                    let value = client.get(&key).await;
+                    let value = router.get(&key).await;
                     println!("Received key: {:?}", value);
                     response.send(value).unwrap();
                     /*
@@ -4,10 +4,17 @@ Client below attempts to connect with the echo server
 
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio_official_tutorial_code_minis::config::Config;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let socket = TcpStream::connect("127.0.0.1:6142").await?;
+    let config = Config::load_or_exit(Config {
+        port: 6142,
+        ..Config::default()
+    });
+    //the client reads the same config as the echo servers, so it finds them on whatever address they were given
+
+    let socket = TcpStream::connect(config.addr()).await?;
     let (mut rd, mut wr) = io::split(socket);
     //uses the split function from tokio io library to split the read functionality from the write functionality
 
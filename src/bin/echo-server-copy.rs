@@ -6,6 +6,7 @@ use tokio::{
     io::{self},
     net::TcpListener,
 };
+use tokio_official_tutorial_code_minis::config::Config;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
@@ -14,9 +15,15 @@ async fn main() -> io::Result<()> {
     and it is smart enough to realize this so does not return an error even though we are not returning a result
     */
 
-    let listener = TcpListener::bind("127.0.0.1:6142").await?;
-    //listens on port 6142
-    println!("Listening on port 6142");
+    let config = Config::load_or_exit(Config {
+        port: 6142,
+        ..Config::default()
+    });
+    //reads the address to listen on from the shared config (flags like --port, env vars or a config file), falling back to port 6142
+
+    let listener = TcpListener::bind(config.addr()).await?;
+    //listens on port 6142 unless configured otherwise
+    println!("Listening on {}", config.addr());
     loop {
         let (mut socket, _) = listener.accept().await?;
         //accepts all incoming connections
@@ -5,10 +5,16 @@ Instead we use read and write
 
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
+use tokio_official_tutorial_code_minis::config::Config;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:6142").await?;
+    let config = Config::load_or_exit(Config {
+        port: 6142,
+        ..Config::default()
+    });
+    //same shared config as the copy echo server, port 6142 unless configured otherwise
+    let listener = TcpListener::bind(config.addr()).await?;
 
     loop {
         let (mut socket, _) = listener.accept().await?;
@@ -0,0 +1,462 @@
+use crate::cmd::{AclCommand, Command};
+use crate::error::CommandError;
+use crate::frame::Frame;
+use crate::glob::glob_match;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::{Arc, RwLock};
+
+/// The user connections start out as, and that AUTH with only a password
+/// logs in as.
+pub const DEFAULT_USER: &str = "default";
+
+/// The users clients can log in as, and what each of them may do.
+///
+/// A new connection is logged in as the default user if it needs no
+/// password, and must AUTH first otherwise. Permissions are looked up on
+/// every command, so ACL SETUSER applies to connections already logged in
+/// as the user too.
+///
+/// Like Redis, only the SHA-256 of each password is kept.
+#[derive(Clone)]
+pub struct Acl {
+    users: Arc<RwLock<BTreeMap<String, User>>>,
+    /// Every command, as listed by `CommandTable::names`, then the aliases.
+    names: Arc<[&'static str]>,
+}
+
+#[derive(Clone)]
+struct User {
+    enabled: bool,
+    /// Any password is accepted.
+    nopass: bool,
+    passwords: BTreeSet<[u8; 32]>,
+    /// Commands the user may run, by the name they are invoked by, so an
+    /// alias is allowed separately from the command it is parsed as.
+    commands: BTreeSet<&'static str>,
+    /// Glob patterns of the keys the user may access.
+    keys: Vec<String>,
+}
+
+impl Acl {
+    /// Creates the default user, allowed every command in `names` and
+    /// every alias, with `requirepass` as its password or without one.
+    pub fn new(requirepass: Option<&str>, names: &[&'static str]) -> Acl {
+        let aliases = Command::ALIASES.iter().map(|&(alias, _)| alias);
+        let names: Vec<_> = names.iter().copied().chain(aliases).collect();
+        let mut user = User {
+            enabled: true,
+            nopass: requirepass.is_none(),
+            passwords: BTreeSet::new(),
+            commands: names.iter().copied().collect(),
+            keys: vec!["*".to_string()],
+        };
+        user.passwords.extend(requirepass.map(hash));
+        Acl {
+            users: Arc::new(RwLock::new(BTreeMap::from([(
+                DEFAULT_USER.to_string(),
+                user,
+            )]))),
+            names: names.into(),
+        }
+    }
+
+    /// The user a new connection is logged in as, if it need not AUTH.
+    pub fn initial_user(&self) -> Option<String> {
+        let users = self.users.read().unwrap();
+        let user = users.get(DEFAULT_USER)?;
+        (user.enabled && user.nopass).then(|| DEFAULT_USER.to_string())
+    }
+
+    /// Checks the password of `name`, or of the default user if `None`,
+    /// and returns the name of the user to log in as.
+    pub fn authenticate(&self, name: Option<&str>, password: &str) -> Result<String, CommandError> {
+        let users = self.users.read().unwrap();
+        let user = users.get(name.unwrap_or(DEFAULT_USER));
+        if name.is_none() && user.is_some_and(|user| user.nopass && user.passwords.is_empty()) {
+            return Err(CommandError::NoPasswordConfigured);
+        }
+        match user {
+            Some(user)
+                if user.enabled && (user.nopass || user.passwords.contains(&hash(password))) =>
+            {
+                Ok(name.unwrap_or(DEFAULT_USER).to_string())
+            }
+            _ => Err(CommandError::WrongPass),
+        }
+    }
+
+    /// Checks that `name` may run `cmd`, invoked as `invoked`, on its keys.
+    pub fn check(&self, name: &str, invoked: &str, cmd: &Command) -> Result<(), CommandError> {
+        let users = self.users.read().unwrap();
+        // Deleting a user logs its connections out.
+        let user = users.get(name).ok_or(CommandError::NoAuth)?;
+        if !user.commands.contains(invoked) {
+            let known = self.names.iter().find(|&&known| known == invoked);
+            return Err(CommandError::NoPermission {
+                user: name.to_string(),
+                command: known.copied().unwrap_or(cmd.name()),
+            });
+        }
+        let allowed = |key: &str| {
+            let mut patterns = user.keys.iter();
+            patterns.any(|pattern| glob_match(pattern.as_bytes(), key.as_bytes()))
+        };
+        if !cmd.keys().into_iter().all(allowed) {
+            return Err(CommandError::NoKeyPermission);
+        }
+        Ok(())
+    }
+
+    /// Runs an ACL subcommand other than WHOAMI, which needs to know the
+    /// connection.
+    pub fn command(&self, cmd: AclCommand) -> Result<Frame, CommandError> {
+        let frame = match cmd {
+            AclCommand::SetUser { name, rules } => {
+                let mut users = self.users.write().unwrap();
+                let mut user = users.get(&name).cloned().unwrap_or_else(User::new);
+                for rule in &rules {
+                    user.apply(rule, &self.names)
+                        .map_err(|reason| CommandError::AclRule(rule.clone(), reason))?;
+                }
+                users.insert(name, user);
+                Frame::Simple("OK".to_string())
+            }
+            AclCommand::GetUser(name) => match self.users.read().unwrap().get(&name) {
+                Some(user) => user.to_frame(&self.names),
+                None => Frame::Null,
+            },
+            AclCommand::DelUser(names) => {
+                if names.iter().any(|name| name == DEFAULT_USER) {
+                    return Err(CommandError::Invalid(
+                        "The 'default' user cannot be removed",
+                    ));
+                }
+                let mut users = self.users.write().unwrap();
+                let removed = names.iter().filter(|&name| users.remove(name).is_some());
+                Frame::Integer(removed.count() as i64)
+            }
+            AclCommand::List => {
+                let users = self.users.read().unwrap();
+                let lines = users.iter().map(|(name, user)| {
+                    let mut line = format!("user {} {}", name, user.flags().join(" "));
+                    for password in &user.passwords {
+                        let _ = write!(line, " #{}", hex(password));
+                    }
+                    for pattern in &user.keys {
+                        let _ = write!(line, " ~{}", pattern);
+                    }
+                    let _ = write!(line, " {}", user.commands_rule(&self.names));
+                    Frame::Bulk(line.into())
+                });
+                Frame::Array(lines.collect())
+            }
+            AclCommand::WhoAmI => unreachable!("WHOAMI is answered by `process`"),
+        };
+        Ok(frame)
+    }
+}
+
+impl User {
+    /// A user as ACL SETUSER first creates it: disabled, and allowed
+    /// nothing.
+    fn new() -> User {
+        User {
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            commands: BTreeSet::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    /// Applies one ACL SETUSER rule, or says what is wrong with it.
+    /// `names` are the commands `+@all` allows.
+    fn apply(&mut self, rule: &str, names: &[&'static str]) -> Result<(), &'static str> {
+        match &rule.to_lowercase()[..] {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec!["*".to_string()],
+            "resetkeys" => self.keys.clear(),
+            "allcommands" | "+@all" => self.commands = names.iter().copied().collect(),
+            "nocommands" | "-@all" => self.commands.clear(),
+            "reset" => *self = User::new(),
+            _ => match rule.split_at(rule.chars().next().map_or(0, char::len_utf8)) {
+                (">", password) => {
+                    self.nopass = false;
+                    self.passwords.insert(hash(password));
+                }
+                ("<", password) => {
+                    if !self.passwords.remove(&hash(password)) {
+                        return Err("no such password");
+                    }
+                }
+                ("#", digest) => {
+                    self.nopass = false;
+                    self.passwords
+                        .insert(unhex(digest).ok_or("invalid password hash")?);
+                }
+                ("!", digest) => {
+                    let digest = unhex(digest).ok_or("invalid password hash")?;
+                    if !self.passwords.remove(&digest) {
+                        return Err("no such password");
+                    }
+                }
+                ("~", pattern) => self.keys.push(pattern.to_string()),
+                ("+", name) if !name.starts_with('@') => {
+                    self.commands.insert(command_name(name, names)?);
+                }
+                ("-", name) if !name.starts_with('@') => {
+                    self.commands.remove(command_name(name, names)?);
+                }
+                ("+" | "-", _) => return Err("Unknown command category"),
+                _ => return Err("Syntax error"),
+            },
+        }
+        Ok(())
+    }
+
+    fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    /// The commands allowed, as the shortest of `+@all -...` and
+    /// `-@all +...`.
+    fn commands_rule(&self, names: &[&'static str]) -> String {
+        let mut rule = String::new();
+        if self.commands.len() * 2 > names.len() {
+            rule.push_str("+@all");
+            for name in names {
+                if !self.commands.contains(name) {
+                    let _ = write!(rule, " -{}", name);
+                }
+            }
+        } else {
+            rule.push_str("-@all");
+            for name in &self.commands {
+                let _ = write!(rule, " +{}", name);
+            }
+        }
+        rule
+    }
+
+    /// The ACL GETUSER reply.
+    fn to_frame(&self, names: &[&'static str]) -> Frame {
+        let bulk = |s: String| Frame::Bulk(s.into());
+        let flags = self.flags().into_iter().map(|flag| bulk(flag.to_string()));
+        let passwords = self.passwords.iter().map(|password| bulk(hex(password)));
+        let keys: Vec<String> = self
+            .keys
+            .iter()
+            .map(|pattern| format!("~{}", pattern))
+            .collect();
+        Frame::Map(vec![
+            (bulk("flags".to_string()), Frame::Array(flags.collect())),
+            (
+                bulk("passwords".to_string()),
+                Frame::Array(passwords.collect()),
+            ),
+            (
+                bulk("commands".to_string()),
+                bulk(self.commands_rule(names)),
+            ),
+            (bulk("keys".to_string()), bulk(keys.join(" "))),
+        ])
+    }
+}
+
+/// The one of `names` that is `name`, ignoring case.
+fn command_name(name: &str, names: &[&'static str]) -> Result<&'static str, &'static str> {
+    names
+        .iter()
+        .copied()
+        .find(|known| known.eq_ignore_ascii_case(name))
+        .ok_or("Unknown command")
+}
+
+fn hash(password: &str) -> [u8; 32] {
+    Sha256::digest(password.as_bytes()).into()
+}
+
+fn hex(digest: &[u8; 32]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(s: &str) -> Option<[u8; 32]> {
+    // `from_str_radix` would take a sign too.
+    if s.len() != 64 || !s.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut digest = [0; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::CommandTable;
+
+    fn set_user(acl: &Acl, name: &str, rules: &[&str]) {
+        let rules = rules.iter().map(|rule| rule.to_string()).collect();
+        let name = name.to_string();
+        acl.command(AclCommand::SetUser { name, rules }).unwrap();
+    }
+
+    /// Checks that `user` may run `args`.
+    fn check(acl: &Acl, user: &str, args: &[&str]) -> Result<(), CommandError> {
+        let args = args.iter().map(|arg| Frame::Bulk(arg.to_string().into()));
+        let frame = Frame::Array(args.collect());
+        let (name, cmd) = Command::from_frame_named(frame, &CommandTable::new()).unwrap();
+        acl.check(user, &name, &cmd)
+    }
+
+    #[test]
+    fn authenticate_checks_the_user_and_its_passwords() {
+        let names = CommandTable::new().names().to_vec();
+        let acl = Acl::new(None, &names);
+        assert_eq!(acl.initial_user().as_deref(), Some(DEFAULT_USER));
+        let err = acl.authenticate(None, "anything").unwrap_err();
+        assert!(matches!(err, CommandError::NoPasswordConfigured));
+        assert_eq!(
+            acl.authenticate(Some("default"), "anything").unwrap(),
+            "default"
+        );
+
+        let acl = Acl::new(Some("secret"), &names);
+        assert_eq!(acl.initial_user(), None);
+        assert_eq!(acl.authenticate(None, "secret").unwrap(), "default");
+        let err = acl.authenticate(None, "wrong").unwrap_err();
+        assert!(matches!(err, CommandError::WrongPass));
+
+        set_user(&acl, "alice", &["on", ">pw"]);
+        set_user(&acl, "bob", &["on", "nopass"]);
+        assert_eq!(acl.authenticate(Some("alice"), "pw").unwrap(), "alice");
+        assert_eq!(acl.authenticate(Some("bob"), "anything").unwrap(), "bob");
+        set_user(&acl, "alice", &["off"]);
+        for (user, password) in [("alice", "pw"), ("alice", "wrong"), ("carol", "pw")] {
+            let err = acl.authenticate(Some(user), password).unwrap_err();
+            assert!(matches!(err, CommandError::WrongPass), "{}", user);
+        }
+    }
+
+    #[test]
+    fn check_refuses_commands_and_keys_not_allowed() {
+        let acl = Acl::new(None, CommandTable::new().names());
+        set_user(&acl, "alice", &["on", "nopass", "+get", "~cache:*"]);
+        check(&acl, "alice", &["GET", "cache:1"]).unwrap();
+        check(&acl, "alice", &["PING"]).unwrap_err();
+
+        let err = check(&acl, "alice", &["SET", "cache:1", "v"]).unwrap_err();
+        assert!(matches!(
+            err,
+            CommandError::NoPermission { user, command: "set" } if user == "alice"
+        ));
+        let err = check(&acl, "alice", &["GET", "other"]).unwrap_err();
+        assert!(matches!(err, CommandError::NoKeyPermission));
+
+        // Deleting a user logs its connections out.
+        acl.command(AclCommand::DelUser(vec!["alice".to_string()]))
+            .unwrap();
+        let err = check(&acl, "alice", &["GET", "cache:1"]).unwrap_err();
+        assert!(matches!(err, CommandError::NoAuth));
+    }
+
+    #[test]
+    fn aliases_are_allowed_apart_from_their_command() {
+        let acl = Acl::new(None, CommandTable::new().names());
+        set_user(&acl, "alice", &["on", "nopass", "allkeys", "+incr"]);
+        check(&acl, "alice", &["INCR", "n"]).unwrap();
+        for args in [&["INCRBY", "n", "2"][..], &["DECR", "n"]] {
+            let err = check(&acl, "alice", args).unwrap_err();
+            assert!(
+                matches!(err, CommandError::NoPermission { .. }),
+                "{:?}",
+                args
+            );
+        }
+
+        set_user(
+            &acl,
+            "bob",
+            &["on", "nopass", "allkeys", "+@all", "-decrby"],
+        );
+        check(&acl, "bob", &["DECRBY", "n", "2"]).unwrap_err();
+        for args in [&["INCRBY", "n", "2"][..], &["INCR", "n"], &["DECR", "n"]] {
+            check(&acl, "bob", args).unwrap();
+        }
+    }
+
+    #[test]
+    fn rules_edit_the_user() {
+        let names = ["get", "set"];
+        let mut user = User::new();
+        let digest = hex(&hash("b"));
+        for rule in ["on", ">a", &format!("#{}", digest), "~k*", "+GET"] {
+            user.apply(rule, &names).unwrap();
+        }
+        assert!(user.enabled && !user.nopass);
+        assert_eq!(user.passwords, BTreeSet::from([hash("a"), hash("b")]));
+        assert_eq!(user.keys, ["k*"]);
+        assert_eq!(user.commands, BTreeSet::from(["get"]));
+
+        user.apply("<a", &names).unwrap();
+        user.apply(&format!("!{}", digest), &names).unwrap();
+        assert!(user.passwords.is_empty());
+        assert_eq!(user.apply("<a", &names), Err("no such password"));
+        let rule = format!("!{}", digest);
+        assert_eq!(user.apply(&rule, &names), Err("no such password"));
+        assert_eq!(user.apply("#abc", &names), Err("invalid password hash"));
+        assert_eq!(user.apply("+del", &names), Err("Unknown command"));
+        assert_eq!(
+            user.apply("-@admin", &names),
+            Err("Unknown command category")
+        );
+        assert_eq!(user.apply("get", &names), Err("Syntax error"));
+
+        user.apply("-get", &names).unwrap();
+        assert!(user.commands.is_empty());
+        user.apply("nopass", &names).unwrap();
+        user.apply("reset", &names).unwrap();
+        assert!(!user.enabled && !user.nopass && user.keys.is_empty());
+    }
+
+    #[test]
+    fn commands_rule_is_the_shorter_of_both_forms() {
+        let names = ["get", "set", "del"];
+        let mut user = User::new();
+        assert_eq!(user.commands_rule(&names), "-@all");
+        user.commands.insert("get");
+        assert_eq!(user.commands_rule(&names), "-@all +get");
+        user.commands.insert("set");
+        assert_eq!(user.commands_rule(&names), "+@all -del");
+        user.commands.insert("del");
+        assert_eq!(user.commands_rule(&names), "+@all");
+    }
+
+    #[test]
+    fn unhex_takes_only_sha256_hex_digests() {
+        let digest = hash("password");
+        assert_eq!(unhex(&hex(&digest)), Some(digest));
+        assert_eq!(unhex(&hex(&digest).to_uppercase()), Some(digest));
+        assert_eq!(unhex(&hex(&digest)[2..]), None);
+        for bad in ["g".repeat(64), "+1".repeat(32), "é".repeat(32)] {
+            assert_eq!(unhex(&bad), None, "{}", bad);
+        }
+    }
+}
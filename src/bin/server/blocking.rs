@@ -0,0 +1,184 @@
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::Notify;
+
+/// Clients blocked in BLPOP and BRPOP, queued per key in the order they
+/// blocked.
+///
+/// A push hands its elements straight to the clients at the head of the
+/// key's queue, while the shard holding the list is still locked, so no
+/// other client can pop them first and clients are served in FIFO order.
+/// Each client then wakes up through a `Notify`, the same abstraction over
+/// wakers as in `10_13_using_notify_abstraction_to_wake.rs`.
+#[derive(Clone, Default)]
+pub struct Blocking {
+    keys: Arc<Mutex<HashMap<(usize, String), Queue>>>,
+}
+
+/// The clients blocked on a key, longest waiting first.
+type Queue = VecDeque<Arc<Waiter>>;
+
+struct Waiter {
+    /// Whether the client pops from the head of lists, as BLPOP does.
+    front: bool,
+    /// The key and element handed to the client, set once.
+    popped: OnceLock<(String, Bytes)>,
+    notify: Notify,
+}
+
+impl Blocking {
+    /// Queues a client on `keys` of database `db` until the returned
+    /// `Watch` is dropped.
+    ///
+    /// Call it with the shards of `keys` still locked from finding their
+    /// lists empty, so that no push in between goes unnoticed.
+    pub fn watch(&self, db: usize, keys: &[String], front: bool) -> Watch {
+        let waiter = Arc::new(Waiter {
+            front,
+            popped: OnceLock::new(),
+            notify: Notify::new(),
+        });
+        let mut map = self.keys.lock().unwrap();
+        let mut watched: Vec<(usize, String)> = Vec::with_capacity(keys.len());
+        for key in keys {
+            let key = (db, key.clone());
+            if watched.contains(&key) {
+                continue;
+            }
+            map.entry(key.clone())
+                .or_default()
+                .push_back(waiter.clone());
+            watched.push(key);
+        }
+        Watch {
+            blocking: self.clone(),
+            keys: watched,
+            waiter,
+        }
+    }
+
+    /// Hands elements of the list at `key` of database `db` to the clients
+    /// blocked on it, longest waiting first, for as long as `pop` returns
+    /// one. `pop` is given whether to pop from the head of the list.
+    pub fn serve(&self, db: usize, key: &str, mut pop: impl FnMut(bool) -> Option<Bytes>) {
+        let mut map = self.keys.lock().unwrap();
+        let map_key = (db, key.to_string());
+        let Some(queue) = map.get_mut(&map_key) else {
+            return;
+        };
+        while let Some(waiter) = queue.front() {
+            // Clients blocked on several keys may have been served through
+            // another one already.
+            if waiter.popped.get().is_none() {
+                let Some(value) = pop(waiter.front) else {
+                    break;
+                };
+                let _ = waiter.popped.set((key.to_string(), value));
+                waiter.notify.notify_one();
+            }
+            queue.pop_front();
+        }
+        if queue.is_empty() {
+            map.remove(&map_key);
+        }
+    }
+
+    /// Keys of database `db` clients are blocked on.
+    pub fn keys(&self, db: usize) -> Vec<String> {
+        let map = self.keys.lock().unwrap();
+        map.keys()
+            .filter(|(key_db, _)| *key_db == db)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+}
+
+/// A client's place in the queues of a set of keys.
+///
+/// Dropping it, because the client got a value, timed out or disconnected,
+/// takes the client out of the queues. An element handed to a client that
+/// disconnects before replying is lost, as its pop is already written to
+/// the AOF.
+pub struct Watch {
+    blocking: Blocking,
+    keys: Vec<(usize, String)>,
+    waiter: Arc<Waiter>,
+}
+
+impl Watch {
+    /// Waits until an element is handed to the client, and returns it with
+    /// the key it was popped from.
+    pub async fn popped(&self) -> (String, Bytes) {
+        loop {
+            if let Some(popped) = self.waiter.popped.get() {
+                return popped.clone();
+            }
+            self.waiter.notify.notified().await;
+        }
+    }
+
+    /// Leaves the queues, returning the element the client was handed just
+    /// before, if any.
+    pub fn cancel(self) -> Option<(String, Bytes)> {
+        let waiter = self.waiter.clone();
+        // Nothing is handed over once out of the queues.
+        drop(self);
+        waiter.popped.get().cloned()
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let mut map = self.blocking.keys.lock().unwrap();
+        for key in &self.keys {
+            if let Some(queue) = map.get_mut(key) {
+                queue.retain(|waiter| !Arc::ptr_eq(waiter, &self.waiter));
+                if queue.is_empty() {
+                    map.remove(key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn pop(list: &mut VecDeque<Bytes>) -> impl FnMut(bool) -> Option<Bytes> + '_ {
+        |front| match front {
+            true => list.pop_front(),
+            false => list.pop_back(),
+        }
+    }
+
+    #[test]
+    fn pushes_go_to_the_longest_waiting_client() {
+        let blocking = Blocking::default();
+        let keys = ["a".to_string(), "b".to_string()];
+        let first = blocking.watch(0, &keys, true);
+        let second = blocking.watch(0, &keys[..1], false);
+        let third = blocking.watch(0, &keys[..1], true);
+        let mut list: VecDeque<Bytes> = ["1", "2"].map(Bytes::from).into();
+        blocking.serve(0, "a", pop(&mut list));
+        assert_eq!(
+            first.popped().now_or_never(),
+            Some(("a".into(), "1".into()))
+        );
+        assert_eq!(
+            second.popped().now_or_never(),
+            Some(("a".into(), "2".into()))
+        );
+        assert_eq!(third.popped().now_or_never(), None);
+        // Served through "a", so left out of "b".
+        list.push_back("3".into());
+        blocking.serve(0, "b", pop(&mut list));
+        assert_eq!(list.len(), 1);
+        blocking.serve(0, "a", pop(&mut list));
+        assert_eq!(third.cancel(), Some(("a".into(), "3".into())));
+        drop((first, second));
+        assert!(blocking.keys(0).is_empty());
+    }
+}
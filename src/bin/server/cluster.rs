@@ -0,0 +1,767 @@
+use crate::cmd::ClusterCommand;
+use crate::codec::RespCodec;
+use crate::connection::Connection;
+use crate::error::CommandError;
+use crate::frame::Frame;
+use crate::parse::Parse;
+use crate::replication::random_id;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_official_tutorial_code_minis::config;
+use tokio_official_tutorial_code_minis::slot::{key_slot, KEY_SLOTS};
+use tracing::{debug, info, warn};
+
+/// How often every known node is pinged over the bus.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A node not heard from for this long is flagged as failing.
+const NODE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long one exchange on the bus may take.
+const BUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// This server's view of the cluster: the nodes in it, and which of them
+/// serves each hash slot.
+///
+/// Nodes gossip over the cluster bus, a second port speaking RESP. Once a
+/// second, each node sends every node it knows a PING carrying its own
+/// description followed by those of the nodes it knows, and gets the same
+/// back in a PONG. Meeting one member of a cluster is thus enough to learn
+/// of all of them.
+///
+/// A node announces the slots it claims along with a config epoch. When
+/// claims overlap, the higher epoch wins, and the lower node id breaks
+/// ties. Claiming slots takes an epoch above any seen so far, so the latest
+/// claim wins. Keys are not moved when a slot changes hands.
+#[derive(Clone)]
+pub struct Cluster {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    myself: String,
+    state: Mutex<State>,
+    /// Rebuilt from `state` whenever a claim changes, so that routing a
+    /// command does not contend with gossip.
+    routing: RwLock<Routing>,
+}
+
+struct State {
+    /// The highest epoch seen in the cluster.
+    current_epoch: u64,
+    /// Every known node by id, this one included.
+    nodes: HashMap<String, Node>,
+}
+
+#[derive(Clone)]
+struct Node {
+    id: String,
+    host: String,
+    port: u16,
+    bus_port: u16,
+    /// The config epoch of `slots`.
+    epoch: u64,
+    /// The slots the node claims, some of which newer claims may override.
+    slots: Slots,
+    /// When the node was first heard of, and when it last talked to this
+    /// one over the bus.
+    added: Instant,
+    last_seen: Option<Instant>,
+}
+
+/// The node serving each slot.
+#[derive(Default)]
+struct Routing {
+    /// Index in `nodes` of the owner of each slot.
+    owners: Vec<Option<usize>>,
+    nodes: Vec<Owner>,
+}
+
+struct Owner {
+    id: String,
+    host: String,
+    port: u16,
+}
+
+impl Cluster {
+    /// Creates a cluster with this node, reachable at `host`, alone in it.
+    pub fn new(host: String, port: u16, bus_port: u16) -> Cluster {
+        let myself = random_id();
+        info!("Cluster node id {}", myself);
+        let node = Node {
+            id: myself.clone(),
+            host,
+            port,
+            bus_port,
+            epoch: 0,
+            slots: Slots::new(),
+            added: Instant::now(),
+            last_seen: None,
+        };
+        let cluster = Cluster {
+            inner: Arc::new(Inner {
+                myself: myself.clone(),
+                state: Mutex::new(State {
+                    current_epoch: 0,
+                    nodes: HashMap::from([(myself, node)]),
+                }),
+                routing: RwLock::new(Routing::default()),
+            }),
+        };
+        cluster.reroute(&mut cluster.inner.state.lock().unwrap());
+        cluster
+    }
+
+    /// Checks that this node serves the keys of a command, which must all
+    /// be in the same slot.
+    pub fn check(&self, keys: &[&str]) -> Result<(), CommandError> {
+        let mut slots = keys.iter().map(|key| key_slot(key.as_bytes()));
+        let Some(slot) = slots.next() else {
+            return Ok(());
+        };
+        if slots.any(|other| other != slot) {
+            return Err(CommandError::CrossSlot);
+        }
+        let routing = self.inner.routing.read().unwrap();
+        match routing.owners[slot as usize].map(|i| &routing.nodes[i]) {
+            Some(owner) if owner.id == self.inner.myself => Ok(()),
+            Some(owner) => Err(CommandError::Moved {
+                slot,
+                addr: format!("{}:{}", owner.host, owner.port),
+            }),
+            None => Err(CommandError::ClusterDown),
+        }
+    }
+
+    /// Claims the slots in `ranges`, none of which may be served by another
+    /// node.
+    pub fn add_slots(&self, ranges: &[(u16, u16)]) -> Result<(), CommandError> {
+        let mut state = self.inner.state.lock().unwrap();
+        {
+            let routing = self.inner.routing.read().unwrap();
+            let busy = ranges
+                .iter()
+                .flat_map(|&(first, last)| first..=last)
+                .find(|&slot| routing.owners[slot as usize].is_some());
+            if let Some(slot) = busy {
+                return Err(CommandError::SlotBusy(slot));
+            }
+        }
+        state.current_epoch += 1;
+        let epoch = state.current_epoch;
+        let me = state.nodes.get_mut(&self.inner.myself).unwrap();
+        me.epoch = epoch;
+        for &(first, last) in ranges {
+            (first..=last).for_each(|slot| me.slots.insert(slot));
+        }
+        self.reroute(&mut state);
+        Ok(())
+    }
+
+    /// Introduces this node to the one with its bus at `host` and
+    /// `bus_port`, in the background.
+    pub fn meet(&self, host: String, bus_port: u16) {
+        let cluster = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = ping(&cluster, &host, bus_port).await {
+                warn!("Failed to meet cluster node {}:{}: {}", host, bus_port, err);
+            }
+        });
+    }
+
+    pub fn command(&self, cmd: ClusterCommand) -> Result<Frame, CommandError> {
+        let frame = match cmd {
+            ClusterCommand::Info => Frame::text(self.info()),
+            ClusterCommand::MyId => Frame::Bulk(self.inner.myself.clone().into()),
+            ClusterCommand::KeySlot(key) => Frame::Integer(key_slot(key.as_bytes()) as i64),
+            ClusterCommand::Slots => self.slots(),
+            ClusterCommand::Nodes => Frame::text(self.nodes()),
+            ClusterCommand::Meet {
+                host,
+                port,
+                bus_port,
+            } => {
+                let bus_port = bus_port.or(config::default_bus_port(port));
+                let bus_port = bus_port.ok_or(CommandError::Invalid(
+                    "Invalid node address specified: no cluster bus port at the port + 10000",
+                ))?;
+                self.meet(host, bus_port);
+                Frame::Simple("OK".to_string())
+            }
+            ClusterCommand::AddSlots(ranges) => {
+                self.add_slots(&ranges)?;
+                Frame::Simple("OK".to_string())
+            }
+        };
+        Ok(frame)
+    }
+
+    /// The CLUSTER INFO reply.
+    fn info(&self) -> String {
+        let state = self.inner.state.lock().unwrap();
+        let routing = self.inner.routing.read().unwrap();
+        let failing = |i: usize| state.nodes[&routing.nodes[i].id].is_failing(&self.inner.myself);
+        let assigned = routing.owners.iter().flatten().count();
+        let fail = routing
+            .owners
+            .iter()
+            .flatten()
+            .filter(|&&i| failing(i))
+            .count();
+        let state_ok = assigned == KEY_SLOTS as usize && fail == 0;
+        format!(
+            "cluster_enabled:1\r\ncluster_state:{}\r\ncluster_slots_assigned:{}\r\n\
+             cluster_slots_ok:{}\r\ncluster_slots_fail:{}\r\ncluster_known_nodes:{}\r\n\
+             cluster_size:{}\r\ncluster_current_epoch:{}\r\ncluster_my_epoch:{}\r\n",
+            if state_ok { "ok" } else { "fail" },
+            assigned,
+            assigned - fail,
+            fail,
+            state.nodes.len(),
+            routing.nodes.len(),
+            state.current_epoch,
+            state.nodes[&self.inner.myself].epoch,
+        )
+    }
+
+    /// The CLUSTER SLOTS reply: each range of slots served by one node,
+    /// with its address and id.
+    fn slots(&self) -> Frame {
+        let routing = self.inner.routing.read().unwrap();
+        let mut ranges = Vec::new();
+        for (i, owner) in routing.nodes.iter().enumerate() {
+            let slots = (0..KEY_SLOTS).filter(|&slot| routing.owners[slot as usize] == Some(i));
+            for (first, last) in ranges_of(slots) {
+                ranges.push((first, last, owner));
+            }
+        }
+        ranges.sort_by_key(|&(first, ..)| first);
+        let ranges = ranges.into_iter().map(|(first, last, owner)| {
+            Frame::Array(vec![
+                Frame::Integer(first as i64),
+                Frame::Integer(last as i64),
+                Frame::Array(vec![
+                    Frame::Bulk(owner.host.clone().into()),
+                    Frame::Integer(owner.port as i64),
+                    Frame::Bulk(owner.id.clone().into()),
+                ]),
+            ])
+        });
+        Frame::Array(ranges.collect())
+    }
+
+    /// The CLUSTER NODES reply, one line per node in the Redis format.
+    fn nodes(&self) -> String {
+        let state = self.inner.state.lock().unwrap();
+        let routing = self.inner.routing.read().unwrap();
+        let mut nodes: Vec<&Node> = state.nodes.values().collect();
+        nodes.sort_by(|a, b| (&a.host, a.port).cmp(&(&b.host, b.port)));
+
+        let mut out = String::new();
+        for node in nodes {
+            let myself = node.id == self.inner.myself;
+            let flags = if myself {
+                "myself,master"
+            } else if node.is_failing(&self.inner.myself) {
+                "master,fail"
+            } else {
+                "master"
+            };
+            let pong = node.last_seen.map_or(0, unix_ms);
+            let link = if myself || !node.is_failing(&self.inner.myself) {
+                "connected"
+            } else {
+                "disconnected"
+            };
+            let _ = write!(
+                out,
+                "{} {}:{}@{} {} - 0 {} {} {}",
+                node.id, node.host, node.port, node.bus_port, flags, pong, node.epoch, link,
+            );
+            let index = routing.nodes.iter().position(|owner| owner.id == node.id);
+            let slots = (0..KEY_SLOTS)
+                .filter(|&slot| index.is_some() && routing.owners[slot as usize] == index);
+            for (first, last) in ranges_of(slots) {
+                if first == last {
+                    let _ = write!(out, " {}", first);
+                } else {
+                    let _ = write!(out, " {}-{}", first, last);
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// The gossip message of kind `kind` (PING or PONG) this node sends.
+    fn message(&self, kind: &str) -> Frame {
+        let state = self.inner.state.lock().unwrap();
+        let me = &state.nodes[&self.inner.myself];
+        let others = state.nodes.values().filter(|node| node.id != me.id);
+        let mut parts = vec![
+            Frame::Bulk(kind.to_string().into()),
+            Frame::Bulk(state.current_epoch.to_string().into()),
+        ];
+        for node in std::iter::once(me).chain(others) {
+            parts.push(Frame::Bulk(node.to_string().into()));
+        }
+        Frame::Array(parts)
+    }
+
+    /// Merges a gossip message into what this node knows, and returns its
+    /// kind.
+    fn receive(&self, frame: Frame) -> mini_redis::Result<String> {
+        let mut parse = Parse::new(frame)?;
+        let kind = parse.next_string()?;
+        let epoch = parse.next_int()?;
+        let mut nodes = Vec::new();
+        while parse.remaining() > 0 {
+            let line = parse.next_string()?;
+            nodes.push(line.parse::<Node>().map_err(|()| "malformed node")?);
+        }
+        if nodes.is_empty() {
+            return Err("gossip without a sender".into());
+        }
+
+        let now = Instant::now();
+        let mut state = self.inner.state.lock().unwrap();
+        state.current_epoch = state.current_epoch.max(epoch);
+        let mut changed = false;
+        for (i, mut node) in nodes.into_iter().enumerate() {
+            // The first node is the sender, which has the last word on its
+            // own claims; what others say about a node only counts with a
+            // newer epoch.
+            let sender = i == 0;
+            if node.id == self.inner.myself {
+                continue;
+            }
+            state.current_epoch = state.current_epoch.max(node.epoch);
+            if let Some(known) = state.nodes.get_mut(&node.id) {
+                if sender {
+                    known.last_seen = Some(now);
+                }
+                if (sender || node.epoch > known.epoch) && !node.same_claims(known) {
+                    node.added = known.added;
+                    node.last_seen = known.last_seen;
+                    *known = node;
+                    changed = true;
+                }
+                continue;
+            }
+            let same_addr = |known: &Node| (&known.host, known.port) == (&node.host, node.port);
+            if !sender && state.nodes.values().any(same_addr) {
+                // Possibly a node that restarted under a new id; only its own
+                // word replaces the old one.
+                continue;
+            }
+            let myself = &self.inner.myself;
+            state
+                .nodes
+                .retain(|id, known| id == myself || !same_addr(known));
+            info!(
+                "Discovered cluster node {} at {}:{}",
+                node.id, node.host, node.port
+            );
+            node.added = now;
+            node.last_seen = sender.then_some(now);
+            state.nodes.insert(node.id.clone(), node);
+            changed = true;
+        }
+        if changed {
+            self.reroute(&mut state);
+        }
+        Ok(kind)
+    }
+
+    /// Rebuilds the routing table from the claims in `state`, dropping the
+    /// claims of this node that lost to newer ones.
+    fn reroute(&self, state: &mut State) {
+        let mut owners: Vec<Option<&Node>> = vec![None; KEY_SLOTS as usize];
+        for node in state.nodes.values() {
+            for slot in node.slots.iter() {
+                let owner = &mut owners[slot as usize];
+                if owner.is_none_or(|owner| node.outranks(owner)) {
+                    *owner = Some(node);
+                }
+            }
+        }
+
+        let mut routing = Routing::default();
+        let mut indexes = HashMap::new();
+        for owner in owners.iter() {
+            let index = owner.map(|node| {
+                *indexes.entry(&node.id).or_insert_with(|| {
+                    routing.nodes.push(Owner {
+                        id: node.id.clone(),
+                        host: node.host.clone(),
+                        port: node.port,
+                    });
+                    routing.nodes.len() - 1
+                })
+            });
+            routing.owners.push(index);
+        }
+        let myself = &self.inner.myself;
+        let lost: Vec<u16> = state.nodes[myself]
+            .slots
+            .iter()
+            .filter(|&slot| owners[slot as usize].is_some_and(|node| node.id != *myself))
+            .collect();
+
+        let me = state.nodes.get_mut(myself).unwrap();
+        for slot in lost {
+            info!("Lost slot {} to a newer claim", slot);
+            me.slots.remove(slot);
+        }
+        *self.inner.routing.write().unwrap() = routing;
+    }
+}
+
+impl Node {
+    /// Whether the claims of `self` win over those of `other`.
+    fn outranks(&self, other: &Node) -> bool {
+        (self.epoch, Reverse(&self.id)) > (other.epoch, Reverse(&other.id))
+    }
+
+    fn is_failing(&self, myself: &str) -> bool {
+        self.id != myself && self.last_seen.unwrap_or(self.added).elapsed() > NODE_TIMEOUT
+    }
+
+    fn same_claims(&self, other: &Node) -> bool {
+        (
+            &self.host,
+            self.port,
+            self.bus_port,
+            self.epoch,
+            &self.slots,
+        ) == (
+            &other.host,
+            other.port,
+            other.bus_port,
+            other.epoch,
+            &other.slots,
+        )
+    }
+}
+
+/// The form nodes are described in gossip:
+/// `id host port bus-port epoch slots`.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {}",
+            self.id, self.host, self.port, self.bus_port, self.epoch, self.slots
+        )
+    }
+}
+
+impl FromStr for Node {
+    type Err = ();
+
+    fn from_str(line: &str) -> Result<Node, ()> {
+        let words: Vec<&str> = line.split(' ').collect();
+        let [id, host, port, bus_port, epoch, slots] = words[..] else {
+            return Err(());
+        };
+        Ok(Node {
+            id: id.to_string(),
+            host: host.to_string(),
+            port: port.parse().map_err(|_| ())?,
+            bus_port: bus_port.parse().map_err(|_| ())?,
+            epoch: epoch.parse().map_err(|_| ())?,
+            slots: slots.parse()?,
+            added: Instant::now(),
+            last_seen: None,
+        })
+    }
+}
+
+/// A set of hash slots, one bit each.
+#[derive(Clone, PartialEq, Eq)]
+struct Slots(Box<[u64]>);
+
+impl Slots {
+    fn new() -> Slots {
+        Slots(vec![0; KEY_SLOTS as usize / 64].into())
+    }
+
+    fn contains(&self, slot: u16) -> bool {
+        self.0[slot as usize / 64] & 1 << (slot % 64) != 0
+    }
+
+    fn insert(&mut self, slot: u16) {
+        self.0[slot as usize / 64] |= 1 << (slot % 64);
+    }
+
+    fn remove(&mut self, slot: u16) {
+        self.0[slot as usize / 64] &= !(1 << (slot % 64));
+    }
+
+    fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..KEY_SLOTS).filter(|&slot| self.contains(slot))
+    }
+}
+
+/// `first-last` ranges separated by commas, or `-` for no slots.
+impl fmt::Display for Slots {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ranges = ranges_of(self.iter());
+        if ranges.is_empty() {
+            return "-".fmt(f);
+        }
+        for (i, (first, last)) in ranges.into_iter().enumerate() {
+            let sep = if i > 0 { "," } else { "" };
+            write!(f, "{}{}-{}", sep, first, last)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Slots {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Slots, ()> {
+        let mut slots = Slots::new();
+        if s == "-" {
+            return Ok(slots);
+        }
+        for range in s.split(',') {
+            let (first, last) = range.split_once('-').ok_or(())?;
+            let first: u16 = first.parse().map_err(|_| ())?;
+            let last: u16 = last.parse().map_err(|_| ())?;
+            if first > last || last >= KEY_SLOTS {
+                return Err(());
+            }
+            (first..=last).for_each(|slot| slots.insert(slot));
+        }
+        Ok(slots)
+    }
+}
+
+/// Groups ascending slots into ranges of consecutive ones.
+fn ranges_of(slots: impl Iterator<Item = u16>) -> Vec<(u16, u16)> {
+    let mut ranges: Vec<(u16, u16)> = Vec::new();
+    for slot in slots {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == slot => *last = slot,
+            _ => ranges.push((slot, slot)),
+        }
+    }
+    ranges
+}
+
+fn unix_ms(when: Instant) -> u64 {
+    let at = SystemTime::now() - when.elapsed();
+    at.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Answers PINGs from other nodes on the cluster bus.
+pub async fn serve_bus(listener: TcpListener, cluster: Cluster) {
+    info!("Cluster bus on {:?}", listener.local_addr().ok());
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                let cluster = cluster.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = answer(socket, &cluster).await {
+                        debug!("cluster bus connection failed: {}", err);
+                    }
+                });
+            }
+            Err(err) => {
+                warn!("Failed to accept a cluster bus connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+async fn answer(socket: TcpStream, cluster: &Cluster) -> mini_redis::Result<()> {
+    let mut connection = Connection::new(socket, RespCodec::default());
+    loop {
+        let frame = tokio::time::timeout(BUS_TIMEOUT, connection.read_frame())
+            .await
+            .map_err(|_| "timed out")?;
+        let Some(frame) = frame? else {
+            return Ok(());
+        };
+        if cluster.receive(frame)? != "PING" {
+            return Err("expected PING".into());
+        }
+        connection.write_frame(&cluster.message("PONG")).await?;
+    }
+}
+
+/// Background task pinging every known node once a second.
+pub async fn gossip(cluster: Cluster) {
+    let mut interval = tokio::time::interval(PING_INTERVAL);
+    loop {
+        interval.tick().await;
+        let peers: Vec<(String, u16)> = {
+            let state = cluster.inner.state.lock().unwrap();
+            let others = state
+                .nodes
+                .values()
+                .filter(|node| node.id != cluster.inner.myself);
+            others
+                .map(|node| (node.host.clone(), node.bus_port))
+                .collect()
+        };
+        for (host, bus_port) in peers {
+            let cluster = cluster.clone();
+            tokio::spawn(async move {
+                if let Err(err) = ping(&cluster, &host, bus_port).await {
+                    debug!("Ping to cluster node {}:{} failed: {}", host, bus_port, err);
+                }
+            });
+        }
+    }
+}
+
+/// Sends a PING to the bus at `host` and `bus_port` and merges the PONG.
+async fn ping(cluster: &Cluster, host: &str, bus_port: u16) -> mini_redis::Result<()> {
+    let exchange = async {
+        let socket = TcpStream::connect((host, bus_port)).await?;
+        let mut connection = Connection::new(socket, RespCodec::default());
+        connection.write_frame(&cluster.message("PING")).await?;
+        let frame = connection.read_frame().await?.ok_or("connection closed")?;
+        if cluster.receive(frame)? != "PONG" {
+            return Err("expected PONG".into());
+        }
+        Ok(())
+    };
+    tokio::time::timeout(BUS_TIMEOUT, exchange)
+        .await
+        .map_err(|_| "timed out")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sorts below any random id, so it wins ties against this node.
+    const LOW: &str = "0000000000000000000000000000000000000000";
+    /// Sorts above any random id, so it loses ties against this node.
+    const HIGH: &str = "ffffffffffffffffffffffffffffffffffffffff";
+
+    /// A PING from `id`, at port 7001 or 7002, claiming `slots` at `epoch`.
+    fn ping_from(id: &str, epoch: u64, slots: &str) -> Frame {
+        let port = if id == LOW { 7001 } else { 7002 };
+        let node = format!(
+            "{} 127.0.0.1 {} {} {} {}",
+            id,
+            port,
+            port + 10000,
+            epoch,
+            slots
+        );
+        let parts = ["PING", &epoch.to_string(), &node];
+        Frame::Array(
+            parts
+                .iter()
+                .map(|part| Frame::Bulk(part.to_string().into()))
+                .collect(),
+        )
+    }
+
+    fn my_slots(cluster: &Cluster) -> String {
+        let state = cluster.inner.state.lock().unwrap();
+        state.nodes[&cluster.inner.myself].slots.to_string()
+    }
+
+    /// A key in a slot below 100.
+    fn low_key() -> String {
+        let mut keys = (0..).map(|i| format!("key{}", i));
+        keys.find(|key| key_slot(key.as_bytes()) < 100).unwrap()
+    }
+
+    #[test]
+    fn slots_round_trip_through_their_text_form() {
+        for text in ["-", "0-0", "0-5,100-200,16383-16383", "0-16383"] {
+            assert_eq!(text.parse::<Slots>().unwrap().to_string(), text);
+        }
+        let slots: Slots = "3-4,5-6,9-9".parse().unwrap();
+        assert_eq!(slots.to_string(), "3-6,9-9");
+        assert_eq!(slots.iter().collect::<Vec<_>>(), [3, 4, 5, 6, 9]);
+        for bad in ["", "5", "6-5", "0-16384", "a-b", "0-5,"] {
+            assert!(bad.parse::<Slots>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn check_routes_keys_to_the_owner_of_their_slot() {
+        let cluster = Cluster::new("127.0.0.1".to_string(), 7000, 17000);
+        assert!(matches!(
+            cluster.check(&["a"]),
+            Err(CommandError::ClusterDown)
+        ));
+        cluster.check(&[]).unwrap();
+
+        cluster.add_slots(&[(0, 99)]).unwrap();
+        let low = low_key();
+        cluster.check(&[&low, &low]).unwrap();
+        let err = cluster.check(&[&low, "{a}"]).unwrap_err();
+        assert!(matches!(err, CommandError::CrossSlot));
+        assert!(matches!(
+            cluster.add_slots(&[(99, 100)]),
+            Err(CommandError::SlotBusy(99))
+        ));
+
+        cluster.receive(ping_from(LOW, 5, "100-16383")).unwrap();
+        let slot = key_slot(b"a");
+        let err = cluster.check(&["a"]).unwrap_err();
+        assert!(matches!(
+            err,
+            CommandError::Moved { slot: moved, addr } if moved == slot && addr == "127.0.0.1:7001"
+        ));
+    }
+
+    #[test]
+    fn receive_settles_claims_by_epoch_then_node_id() {
+        let cluster = Cluster::new("127.0.0.1".to_string(), 7000, 17000);
+        cluster.add_slots(&[(0, 16383)]).unwrap();
+        let low = low_key();
+
+        // A tie on the epoch goes to the lower node id.
+        cluster.receive(ping_from(HIGH, 1, "0-16383")).unwrap();
+        cluster.check(&[&low]).unwrap();
+        cluster.receive(ping_from(LOW, 1, "0-99")).unwrap();
+        assert!(matches!(
+            cluster.check(&[&low]),
+            Err(CommandError::Moved { .. })
+        ));
+        cluster.check(&["a"]).unwrap();
+        // The slots this node lost are no longer among its claims.
+        assert_eq!(my_slots(&cluster), "100-16383");
+
+        // A newer epoch wins whatever the node id.
+        cluster.receive(ping_from(HIGH, 2, "0-16383")).unwrap();
+        for key in [&low[..], "a"] {
+            let err = cluster.check(&[key]).unwrap_err();
+            assert!(matches!(err, CommandError::Moved { addr, .. } if addr == "127.0.0.1:7002"));
+        }
+        assert_eq!(my_slots(&cluster), "-");
+        assert_eq!(cluster.inner.state.lock().unwrap().current_epoch, 2);
+    }
+
+    #[test]
+    fn meet_needs_a_bus_port() {
+        let cluster = Cluster::new("127.0.0.1".to_string(), 7000, 17000);
+        let meet = ClusterCommand::Meet {
+            host: "127.0.0.1".to_string(),
+            port: 60000,
+            bus_port: None,
+        };
+        assert!(matches!(
+            cluster.command(meet),
+            Err(CommandError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,1182 @@
+use crate::db::until_unix_ms;
+use crate::error::CommandError;
+use crate::frame::Frame;
+use crate::module::{self, CommandHandler, CommandSpec, CommandTable, Flag, KeyPositions};
+use crate::parse::{Parse, ParseError};
+use bytes::Bytes;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_official_tutorial_code_minis::slot::KEY_SLOTS;
+
+/// Commands understood by the server.
+///
+/// `mini_redis::Command` has no variants for the expiry commands and gives
+/// no way to tell an arity mistake from a malformed frame, so frames are
+/// parsed here instead.
+#[derive(Debug)]
+pub enum Command {
+    /// A command of a registered `CommandHandler`, with its arguments after
+    /// the name, and the keys among them.
+    Module {
+        handler: Arc<dyn CommandHandler>,
+        args: Parse,
+        keys: Vec<String>,
+    },
+    SetNx {
+        key: String,
+        value: Bytes,
+    },
+    GetSet {
+        key: String,
+        value: Bytes,
+    },
+    MGet {
+        keys: Vec<String>,
+    },
+    MSet {
+        pairs: Vec<(String, Bytes)>,
+    },
+    /// INCR, DECR, INCRBY and DECRBY.
+    IncrBy {
+        key: String,
+        delta: i64,
+    },
+    Append {
+        key: String,
+        value: Bytes,
+    },
+    Strlen {
+        key: String,
+    },
+    GetRange {
+        key: String,
+        start: i64,
+        end: i64,
+    },
+    HSet {
+        key: String,
+        pairs: Vec<(Bytes, Bytes)>,
+    },
+    HGet {
+        key: String,
+        field: Bytes,
+    },
+    HGetAll {
+        key: String,
+    },
+    /// LPUSH when `front` is set, RPUSH otherwise.
+    Push {
+        key: String,
+        values: Vec<Bytes>,
+        front: bool,
+    },
+    /// LPOP when `front` is set, RPOP otherwise.
+    Pop {
+        key: String,
+        count: Option<u64>,
+        front: bool,
+    },
+    /// BLPOP when `front` is set, BRPOP otherwise. Pops from the first
+    /// non-empty list in `keys`; `timeout` is `None` to wait forever.
+    BPop {
+        keys: Vec<String>,
+        front: bool,
+        timeout: Option<Duration>,
+    },
+    LRange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    SAdd {
+        key: String,
+        members: Vec<Bytes>,
+    },
+    SMembers {
+        key: String,
+    },
+    SIsMember {
+        key: String,
+        member: Bytes,
+    },
+    ZAdd {
+        key: String,
+        pairs: Vec<(f64, Bytes)>,
+    },
+    ZRange {
+        key: String,
+        start: i64,
+        stop: i64,
+        with_scores: bool,
+    },
+    ZScore {
+        key: String,
+        member: Bytes,
+    },
+    Del {
+        keys: Vec<String>,
+    },
+    Exists {
+        keys: Vec<String>,
+    },
+    Type {
+        key: String,
+    },
+    Ttl {
+        key: String,
+    },
+    Pttl {
+        key: String,
+    },
+    Expire {
+        key: String,
+        ttl: Duration,
+    },
+    Persist {
+        key: String,
+    },
+    Keys {
+        pattern: String,
+    },
+    /// Lists some keys from `cursor` on, as returned by the previous SCAN,
+    /// or from the start if it is 0.
+    Scan {
+        cursor: u64,
+        pattern: Option<String>,
+        count: usize,
+    },
+    DbSize,
+    RandomKey,
+    FlushDb,
+    FlushAll,
+    /// Switches the connection to database `db`.
+    Select {
+        db: usize,
+    },
+    /// Moves `key` to database `db`, unless it already has such a key.
+    Move {
+        key: String,
+        db: usize,
+    },
+    SwapDb {
+        first: usize,
+        second: usize,
+    },
+    Save,
+    BgSave,
+    BgRewriteAof,
+    Publish {
+        channel: String,
+        message: Bytes,
+    },
+    Subscribe {
+        channels: Vec<String>,
+    },
+    Unsubscribe {
+        channels: Vec<String>,
+    },
+    PSubscribe {
+        patterns: Vec<String>,
+    },
+    PUnsubscribe {
+        patterns: Vec<String>,
+    },
+    Ping {
+        message: Option<Bytes>,
+    },
+    Info {
+        section: Option<String>,
+    },
+    /// Changes the number of shards, migrating keys in the background.
+    Reshard {
+        shards: usize,
+    },
+    Multi,
+    Exec,
+    Discard,
+    Watch {
+        keys: Vec<String>,
+    },
+    Unwatch,
+    /// Starts replicating the primary at `host` and `port`, or stops
+    /// replicating on `None`, from REPLICAOF NO ONE.
+    ReplicaOf {
+        primary: Option<(String, u16)>,
+    },
+    /// Sent by a replica to get the stream of writes after `offset` in the
+    /// history `replid`. `offset` is `None` to ask for a full copy.
+    Psync {
+        replid: String,
+        offset: Option<u64>,
+    },
+    ReplConf(ReplConf),
+    Cluster(ClusterCommand),
+    /// Logs the connection in as `username`, or the default user.
+    Auth {
+        username: Option<String>,
+        password: String,
+    },
+    /// Switches the connection to RESP `protover`, logging it in first if
+    /// `auth` is given, and replies with details about the server.
+    Hello {
+        protover: Option<u64>,
+        auth: Option<(String, String)>,
+    },
+    Acl(AclCommand),
+    CommandInfo(CommandInfo),
+}
+
+/// The REPLCONF options replicas send to their primary.
+#[derive(Debug)]
+pub enum ReplConf {
+    /// The port the replica serves clients on, shown by INFO.
+    ListeningPort(u16),
+    /// The replica has applied the stream up to this offset.
+    Ack(u64),
+}
+
+/// The ACL subcommands.
+#[derive(Debug)]
+pub enum AclCommand {
+    WhoAmI,
+    List,
+    GetUser(String),
+    /// Creates the user if needed, then applies `rules` in order.
+    SetUser {
+        name: String,
+        rules: Vec<String>,
+    },
+    DelUser(Vec<String>),
+}
+
+/// The COMMAND subcommands, COMMAND alone being `List`.
+#[derive(Debug)]
+pub enum CommandInfo {
+    List,
+    Count,
+    Info(Vec<String>),
+}
+
+/// The CLUSTER subcommands.
+#[derive(Debug)]
+pub enum ClusterCommand {
+    Info,
+    MyId,
+    KeySlot(String),
+    Slots,
+    Nodes,
+    /// Joins the node serving clients at `host` and `port`, whose bus is at
+    /// `bus_port`, or `port` + 10000 if not given.
+    Meet {
+        host: String,
+        port: u16,
+        bus_port: Option<u16>,
+    },
+    /// Claims slots, given as inclusive ranges.
+    AddSlots(Vec<(u16, u16)>),
+}
+
+impl Command {
+    /// Every value `name` can return, in the order INFO lists them, but
+    /// for the names of registered commands.
+    pub const NAMES: &'static [&'static str] = &[
+        "setnx",
+        "getset",
+        "mget",
+        "mset",
+        "incrby",
+        "append",
+        "strlen",
+        "getrange",
+        "hset",
+        "hget",
+        "hgetall",
+        "lpush",
+        "rpush",
+        "lpop",
+        "rpop",
+        "blpop",
+        "brpop",
+        "lrange",
+        "sadd",
+        "smembers",
+        "sismember",
+        "zadd",
+        "zrange",
+        "zscore",
+        "del",
+        "exists",
+        "type",
+        "ttl",
+        "pttl",
+        "expire",
+        "persist",
+        "keys",
+        "scan",
+        "dbsize",
+        "randomkey",
+        "flushdb",
+        "flushall",
+        "select",
+        "move",
+        "swapdb",
+        "save",
+        "bgsave",
+        "bgrewriteaof",
+        "publish",
+        "subscribe",
+        "unsubscribe",
+        "psubscribe",
+        "punsubscribe",
+        "ping",
+        "info",
+        "reshard",
+        "multi",
+        "exec",
+        "discard",
+        "watch",
+        "unwatch",
+        "replicaof",
+        "psync",
+        "replconf",
+        "cluster",
+        "auth",
+        "hello",
+        "acl",
+        "command",
+    ];
+
+    /// Commands parsed as another one, with the name `name` gives them.
+    pub const ALIASES: &'static [(&'static str, &'static str)] = &[
+        ("incr", "incrby"),
+        ("decr", "incrby"),
+        ("decrby", "incrby"),
+        ("pexpireat", "expire"),
+        ("slaveof", "replicaof"),
+    ];
+
+    /// What COMMAND reports about every command `Command` parses, aliases
+    /// included.
+    pub const SPECS: &'static [CommandSpec] = {
+        use Flag::*;
+        const NONE: KeyPositions = KeyPositions::NONE;
+        const KEY: KeyPositions = KeyPositions::single(1);
+        const ALL_KEYS: KeyPositions = KeyPositions::all_from(1);
+        const PAIRS: KeyPositions = KeyPositions {
+            first: 1,
+            last: -1,
+            step: 2,
+        };
+        // The timeout follows the keys.
+        const BLOCKING: KeyPositions = KeyPositions {
+            first: 1,
+            last: -2,
+            step: 1,
+        };
+        &[
+            CommandSpec::new("setnx", 3, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("getset", 3, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("mget", -2, &[ReadOnly, Fast], ALL_KEYS),
+            CommandSpec::new("mset", -3, &[Write, DenyOom], PAIRS),
+            CommandSpec::new("incr", 2, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("decr", 2, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("incrby", 3, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("decrby", 3, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("append", 3, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("strlen", 2, &[ReadOnly, Fast], KEY),
+            CommandSpec::new("getrange", 4, &[ReadOnly], KEY),
+            CommandSpec::new("hset", -4, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("hget", 3, &[ReadOnly, Fast], KEY),
+            CommandSpec::new("hgetall", 2, &[ReadOnly], KEY),
+            CommandSpec::new("lpush", -3, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("rpush", -3, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("lpop", -2, &[Write, Fast], KEY),
+            CommandSpec::new("rpop", -2, &[Write, Fast], KEY),
+            CommandSpec::new("blpop", -3, &[Write], BLOCKING),
+            CommandSpec::new("brpop", -3, &[Write], BLOCKING),
+            CommandSpec::new("lrange", 4, &[ReadOnly], KEY),
+            CommandSpec::new("sadd", -3, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("smembers", 2, &[ReadOnly], KEY),
+            CommandSpec::new("sismember", 3, &[ReadOnly, Fast], KEY),
+            CommandSpec::new("zadd", -4, &[Write, DenyOom, Fast], KEY),
+            CommandSpec::new("zrange", -4, &[ReadOnly], KEY),
+            CommandSpec::new("zscore", 3, &[ReadOnly, Fast], KEY),
+            CommandSpec::new("del", -2, &[Write], ALL_KEYS),
+            CommandSpec::new("exists", -2, &[ReadOnly, Fast], ALL_KEYS),
+            CommandSpec::new("type", 2, &[ReadOnly, Fast], KEY),
+            CommandSpec::new("ttl", 2, &[ReadOnly, Fast], KEY),
+            CommandSpec::new("pttl", 2, &[ReadOnly, Fast], KEY),
+            CommandSpec::new("expire", 3, &[Write, Fast], KEY),
+            CommandSpec::new("pexpireat", 3, &[Write, Fast], KEY),
+            CommandSpec::new("persist", 2, &[Write, Fast], KEY),
+            CommandSpec::new("keys", 2, &[ReadOnly], NONE),
+            CommandSpec::new("scan", -2, &[ReadOnly], NONE),
+            CommandSpec::new("dbsize", 1, &[ReadOnly, Fast], NONE),
+            CommandSpec::new("randomkey", 1, &[ReadOnly], NONE),
+            CommandSpec::new("flushdb", -1, &[Write], NONE),
+            CommandSpec::new("flushall", -1, &[Write], NONE),
+            CommandSpec::new("select", 2, &[Fast], NONE),
+            CommandSpec::new("move", 3, &[Write, Fast], KEY),
+            CommandSpec::new("swapdb", 3, &[Write, Fast], NONE),
+            CommandSpec::new("save", 1, &[Admin], NONE),
+            CommandSpec::new("bgsave", 1, &[Admin], NONE),
+            CommandSpec::new("bgrewriteaof", 1, &[Admin], NONE),
+            CommandSpec::new("publish", 3, &[PubSub, Fast], NONE),
+            CommandSpec::new("subscribe", -2, &[PubSub], NONE),
+            CommandSpec::new("unsubscribe", -1, &[PubSub], NONE),
+            CommandSpec::new("psubscribe", -2, &[PubSub], NONE),
+            CommandSpec::new("punsubscribe", -1, &[PubSub], NONE),
+            CommandSpec::new("ping", -1, &[Fast], NONE),
+            CommandSpec::new("info", -1, &[], NONE),
+            CommandSpec::new("reshard", 2, &[Admin], NONE),
+            CommandSpec::new("multi", 1, &[Fast], NONE),
+            CommandSpec::new("exec", 1, &[], NONE),
+            CommandSpec::new("discard", 1, &[Fast], NONE),
+            CommandSpec::new("watch", -2, &[Fast], ALL_KEYS),
+            CommandSpec::new("unwatch", 1, &[Fast], NONE),
+            CommandSpec::new("replicaof", 3, &[Admin], NONE),
+            CommandSpec::new("slaveof", 3, &[Admin], NONE),
+            CommandSpec::new("psync", 3, &[Admin], NONE),
+            CommandSpec::new("replconf", -3, &[Admin], NONE),
+            CommandSpec::new("cluster", -2, &[], NONE),
+            CommandSpec::new("auth", -2, &[Fast], NONE),
+            CommandSpec::new("hello", -1, &[Fast], NONE),
+            CommandSpec::new("acl", -2, &[Admin], NONE),
+            CommandSpec::new("command", -1, &[], NONE),
+        ]
+    };
+
+    /// Parses a command frame, looking the name up in `commands` first.
+    /// Errors are meant to be sent back to the client.
+    pub fn from_frame(frame: Frame, commands: &CommandTable) -> Result<Command, CommandError> {
+        Command::from_frame_named(frame, commands).map(|(_, command)| command)
+    }
+
+    /// Like `from_frame`, but also returns the lowercase name the command
+    /// was invoked by, which for an alias is not `name`.
+    pub fn from_frame_named(
+        frame: Frame,
+        commands: &CommandTable,
+    ) -> Result<(String, Command), CommandError> {
+        let mut parse = Parse::new(frame)?;
+        let name = match parse.next_string() {
+            Ok(name) => name.to_lowercase(),
+            Err(ParseError::EndOfStream) => {
+                return Err(CommandError::Protocol("empty command".into()))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let parsed = match (commands.get(&name), commands.spec(&name)) {
+            (Some(handler), _) => module::parse_command(handler, parse).map(Some),
+            (None, Some(spec)) => spec
+                .check_arity(1 + parse.remaining())
+                .and_then(|()| Command::parse_args(&name, &mut parse)),
+            (None, None) => Ok(None),
+        };
+        match parsed {
+            Ok(Some(command)) => Ok((name, command)),
+            Ok(None) => Err(CommandError::UnknownCommand(name)),
+            Err(err) => Err(CommandError::from_parse(&name, err)),
+        }
+    }
+
+    /// The lowercase command name, as used in error replies.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Module { handler, .. } => handler.name(),
+            Command::SetNx { .. } => "setnx",
+            Command::GetSet { .. } => "getset",
+            Command::MGet { .. } => "mget",
+            Command::MSet { .. } => "mset",
+            Command::IncrBy { .. } => "incrby",
+            Command::Append { .. } => "append",
+            Command::Strlen { .. } => "strlen",
+            Command::GetRange { .. } => "getrange",
+            Command::HSet { .. } => "hset",
+            Command::HGet { .. } => "hget",
+            Command::HGetAll { .. } => "hgetall",
+            Command::Push { front: true, .. } => "lpush",
+            Command::Push { front: false, .. } => "rpush",
+            Command::Pop { front: true, .. } => "lpop",
+            Command::Pop { front: false, .. } => "rpop",
+            Command::BPop { front: true, .. } => "blpop",
+            Command::BPop { front: false, .. } => "brpop",
+            Command::LRange { .. } => "lrange",
+            Command::SAdd { .. } => "sadd",
+            Command::SMembers { .. } => "smembers",
+            Command::SIsMember { .. } => "sismember",
+            Command::ZAdd { .. } => "zadd",
+            Command::ZRange { .. } => "zrange",
+            Command::ZScore { .. } => "zscore",
+            Command::Del { .. } => "del",
+            Command::Exists { .. } => "exists",
+            Command::Type { .. } => "type",
+            Command::Ttl { .. } => "ttl",
+            Command::Pttl { .. } => "pttl",
+            Command::Expire { .. } => "expire",
+            Command::Persist { .. } => "persist",
+            Command::Keys { .. } => "keys",
+            Command::Scan { .. } => "scan",
+            Command::DbSize => "dbsize",
+            Command::RandomKey => "randomkey",
+            Command::FlushDb => "flushdb",
+            Command::FlushAll => "flushall",
+            Command::Select { .. } => "select",
+            Command::Move { .. } => "move",
+            Command::SwapDb { .. } => "swapdb",
+            Command::Save => "save",
+            Command::BgSave => "bgsave",
+            Command::BgRewriteAof => "bgrewriteaof",
+            Command::Publish { .. } => "publish",
+            Command::Subscribe { .. } => "subscribe",
+            Command::Unsubscribe { .. } => "unsubscribe",
+            Command::PSubscribe { .. } => "psubscribe",
+            Command::PUnsubscribe { .. } => "punsubscribe",
+            Command::Ping { .. } => "ping",
+            Command::Info { .. } => "info",
+            Command::Reshard { .. } => "reshard",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Watch { .. } => "watch",
+            Command::Unwatch => "unwatch",
+            Command::ReplicaOf { .. } => "replicaof",
+            Command::Psync { .. } => "psync",
+            Command::ReplConf(_) => "replconf",
+            Command::Cluster(_) => "cluster",
+            Command::Auth { .. } => "auth",
+            Command::Hello { .. } => "hello",
+            Command::Acl(_) => "acl",
+            Command::CommandInfo(_) => "command",
+        }
+    }
+
+    /// The keys the command reads or writes, whose shards must be locked
+    /// while it runs.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Command::SetNx { key, .. }
+            | Command::GetSet { key, .. }
+            | Command::IncrBy { key, .. }
+            | Command::Append { key, .. }
+            | Command::Strlen { key }
+            | Command::GetRange { key, .. }
+            | Command::HSet { key, .. }
+            | Command::HGet { key, .. }
+            | Command::HGetAll { key }
+            | Command::Push { key, .. }
+            | Command::Pop { key, .. }
+            | Command::LRange { key, .. }
+            | Command::SAdd { key, .. }
+            | Command::SMembers { key }
+            | Command::SIsMember { key, .. }
+            | Command::ZAdd { key, .. }
+            | Command::ZRange { key, .. }
+            | Command::ZScore { key, .. }
+            | Command::Type { key }
+            | Command::Ttl { key }
+            | Command::Pttl { key }
+            | Command::Expire { key, .. }
+            | Command::Persist { key }
+            | Command::Move { key, .. } => vec![key],
+            Command::Module { keys, .. }
+            | Command::MGet { keys }
+            | Command::BPop { keys, .. }
+            | Command::Del { keys }
+            | Command::Exists { keys } => keys.iter().map(String::as_str).collect(),
+            Command::MSet { pairs } => pairs.iter().map(|(key, _)| key.as_str()).collect(),
+            // WATCH only reads versions, which `Transaction` does itself, and
+            // the commands over the whole keyspace lock shards themselves.
+            Command::Keys { .. }
+            | Command::Scan { .. }
+            | Command::DbSize
+            | Command::RandomKey
+            | Command::FlushDb
+            | Command::FlushAll
+            | Command::Select { .. }
+            | Command::SwapDb { .. }
+            | Command::Save
+            | Command::BgSave
+            | Command::BgRewriteAof
+            | Command::Publish { .. }
+            | Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::PSubscribe { .. }
+            | Command::PUnsubscribe { .. }
+            | Command::Ping { .. }
+            | Command::Info { .. }
+            | Command::Reshard { .. }
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch
+            | Command::ReplicaOf { .. }
+            | Command::Psync { .. }
+            | Command::ReplConf(_)
+            | Command::Cluster(_)
+            | Command::Auth { .. }
+            | Command::Hello { .. }
+            | Command::Acl(_)
+            | Command::CommandInfo(_) => Vec::new(),
+        }
+    }
+
+    /// Whether the command may be queued between MULTI and EXEC.
+    ///
+    /// SAVE and INFO lock shards themselves, which would deadlock with the
+    /// shards EXEC already holds, subscribing, HELLO and PSYNC change the
+    /// protocol, and AUTH and ACL are about the connection rather than the
+    /// data.
+    pub fn is_allowed_in_transaction(&self) -> bool {
+        !matches!(
+            self,
+            Command::Save
+                | Command::Info { .. }
+                | Command::Subscribe { .. }
+                | Command::Unsubscribe { .. }
+                | Command::PSubscribe { .. }
+                | Command::PUnsubscribe { .. }
+                | Command::Psync { .. }
+                | Command::ReplConf(_)
+                | Command::Auth { .. }
+                | Command::Hello { .. }
+                | Command::Acl(_)
+        )
+    }
+
+    /// Whether the command modifies the keyspace.
+    pub fn is_write(&self) -> bool {
+        if let Command::Module { handler, .. } = self {
+            return handler.flags().contains(&Flag::Write);
+        }
+        matches!(
+            self,
+            Command::SetNx { .. }
+                | Command::GetSet { .. }
+                | Command::MSet { .. }
+                | Command::IncrBy { .. }
+                | Command::Append { .. }
+                | Command::HSet { .. }
+                | Command::Push { .. }
+                | Command::Pop { .. }
+                | Command::BPop { .. }
+                | Command::SAdd { .. }
+                | Command::ZAdd { .. }
+                | Command::Del { .. }
+                | Command::Expire { .. }
+                | Command::Persist { .. }
+                | Command::FlushDb
+                | Command::FlushAll
+                | Command::Move { .. }
+                | Command::SwapDb { .. }
+        )
+    }
+
+    /// Whether the command can grow the data set, and so is refused once
+    /// `maxmemory` is reached and nothing can be evicted.
+    pub fn uses_memory(&self) -> bool {
+        if let Command::Module { handler, .. } = self {
+            return handler.flags().contains(&Flag::DenyOom);
+        }
+        self.is_write()
+            && !matches!(
+                self,
+                Command::Pop { .. }
+                    | Command::BPop { .. }
+                    | Command::Del { .. }
+                    | Command::Expire { .. }
+                    | Command::Persist { .. }
+                    | Command::FlushDb
+                    | Command::FlushAll
+                    | Command::Move { .. }
+                    | Command::SwapDb { .. }
+            )
+    }
+
+    /// Returns `None` if `name` is not a known command.
+    fn parse_args(name: &str, parse: &mut Parse) -> Result<Option<Command>, ParseError> {
+        let command = match name {
+            "setnx" => Command::SetNx {
+                key: parse.next_string()?,
+                value: parse.next_bytes()?,
+            },
+            "getset" => Command::GetSet {
+                key: parse.next_string()?,
+                value: parse.next_bytes()?,
+            },
+            "mget" => Command::MGet {
+                keys: at_least_one(parse)?,
+            },
+            "mset" => Command::MSet {
+                pairs: pairs(parse, |parse| {
+                    Ok((parse.next_string()?, parse.next_bytes()?))
+                })?,
+            },
+            "incr" => Command::IncrBy {
+                key: parse.next_string()?,
+                delta: 1,
+            },
+            "decr" => Command::IncrBy {
+                key: parse.next_string()?,
+                delta: -1,
+            },
+            "incrby" => Command::IncrBy {
+                key: parse.next_string()?,
+                delta: parse.next_signed()?,
+            },
+            "decrby" => Command::IncrBy {
+                key: parse.next_string()?,
+                delta: parse
+                    .next_signed()?
+                    .checked_neg()
+                    .ok_or(ParseError::NotInteger)?,
+            },
+            "append" => Command::Append {
+                key: parse.next_string()?,
+                value: parse.next_bytes()?,
+            },
+            "strlen" => Command::Strlen {
+                key: parse.next_string()?,
+            },
+            "getrange" => Command::GetRange {
+                key: parse.next_string()?,
+                start: parse.next_signed()?,
+                end: parse.next_signed()?,
+            },
+            "hset" => Command::HSet {
+                key: parse.next_string()?,
+                pairs: pairs(parse, |parse| {
+                    Ok((parse.next_bytes()?, parse.next_bytes()?))
+                })?,
+            },
+            "hget" => Command::HGet {
+                key: parse.next_string()?,
+                field: parse.next_bytes()?,
+            },
+            "hgetall" => Command::HGetAll {
+                key: parse.next_string()?,
+            },
+            "lpush" | "rpush" => Command::Push {
+                key: parse.next_string()?,
+                values: at_least_one_bytes(parse)?,
+                front: name == "lpush",
+            },
+            "lpop" | "rpop" => Command::Pop {
+                key: parse.next_string()?,
+                count: match parse.next_int() {
+                    Ok(count) => Some(count),
+                    Err(ParseError::EndOfStream) => None,
+                    Err(err) => return Err(err),
+                },
+                front: name == "lpop",
+            },
+            "blpop" | "brpop" => {
+                // The timeout comes last, after a variable number of keys.
+                let mut keys = vec![parse.next_string()?];
+                while parse.remaining() > 1 {
+                    keys.push(parse.next_string()?);
+                }
+                let timeout = match parse.next_float() {
+                    Ok(secs) if secs < 0.0 => {
+                        return Err(ParseError::Invalid("timeout is negative"))
+                    }
+                    Ok(0.0) => None,
+                    Ok(secs) => match Duration::try_from_secs_f64(secs) {
+                        // The deadline has to fit in an `Instant` too.
+                        Ok(timeout) if Instant::now().checked_add(timeout).is_some() => {
+                            Some(timeout)
+                        }
+                        _ => return Err(ParseError::Invalid("timeout is out of range")),
+                    },
+                    Err(ParseError::NotFloat) => {
+                        return Err(ParseError::Invalid(
+                            "timeout is not a float or out of range",
+                        ))
+                    }
+                    Err(err) => return Err(err),
+                };
+                Command::BPop {
+                    keys,
+                    front: name == "blpop",
+                    timeout,
+                }
+            }
+            "lrange" => Command::LRange {
+                key: parse.next_string()?,
+                start: parse.next_signed()?,
+                stop: parse.next_signed()?,
+            },
+            "sadd" => Command::SAdd {
+                key: parse.next_string()?,
+                members: at_least_one_bytes(parse)?,
+            },
+            "smembers" => Command::SMembers {
+                key: parse.next_string()?,
+            },
+            "sismember" => Command::SIsMember {
+                key: parse.next_string()?,
+                member: parse.next_bytes()?,
+            },
+            "zadd" => Command::ZAdd {
+                key: parse.next_string()?,
+                pairs: pairs(parse, |parse| {
+                    Ok((parse.next_float()?, parse.next_bytes()?))
+                })?,
+            },
+            "zrange" => Command::ZRange {
+                key: parse.next_string()?,
+                start: parse.next_signed()?,
+                stop: parse.next_signed()?,
+                with_scores: match parse.next_string() {
+                    Ok(s) if s.eq_ignore_ascii_case("WITHSCORES") => true,
+                    Ok(_) => return Err(ParseError::Syntax),
+                    Err(ParseError::EndOfStream) => false,
+                    Err(err) => return Err(err),
+                },
+            },
+            "zscore" => Command::ZScore {
+                key: parse.next_string()?,
+                member: parse.next_bytes()?,
+            },
+            "del" => Command::Del {
+                keys: at_least_one(parse)?,
+            },
+            "exists" => Command::Exists {
+                keys: at_least_one(parse)?,
+            },
+            "type" => Command::Type {
+                key: parse.next_string()?,
+            },
+            "ttl" => Command::Ttl {
+                key: parse.next_string()?,
+            },
+            "pttl" => Command::Pttl {
+                key: parse.next_string()?,
+            },
+            // Deadlines already past delete the key, as in Redis.
+            "expire" => Command::Expire {
+                key: parse.next_string()?,
+                ttl: Duration::from_millis(expire_ms(parse, 1000)?.max(0) as u64),
+            },
+            "pexpireat" => Command::Expire {
+                key: parse.next_string()?,
+                ttl: until_unix_ms(parse.next_signed()?.max(0) as u64),
+            },
+            "persist" => Command::Persist {
+                key: parse.next_string()?,
+            },
+            "keys" => Command::Keys {
+                pattern: parse.next_string()?,
+            },
+            "scan" => {
+                let cursor = parse
+                    .next_string()?
+                    .parse()
+                    .map_err(|_| ParseError::Invalid("invalid cursor"))?;
+                let (mut pattern, mut count) = (None, 10);
+                while parse.remaining() > 0 {
+                    let option = parse.next_string()?;
+                    if option.eq_ignore_ascii_case("MATCH") {
+                        pattern = Some(parse.next_string()?);
+                    } else if option.eq_ignore_ascii_case("COUNT") {
+                        count = match parse.next_int()? {
+                            0 => return Err(ParseError::Syntax),
+                            count => count as usize,
+                        };
+                    } else {
+                        return Err(ParseError::Syntax);
+                    }
+                }
+                Command::Scan {
+                    cursor,
+                    pattern,
+                    count,
+                }
+            }
+            "dbsize" => Command::DbSize,
+            "randomkey" => Command::RandomKey,
+            "flushall" | "flushdb" => {
+                // Flushing is always synchronous, whichever mode is asked.
+                match parse.next_string() {
+                    Ok(mode)
+                        if mode.eq_ignore_ascii_case("ASYNC")
+                            || mode.eq_ignore_ascii_case("SYNC") => {}
+                    Ok(_) => return Err(ParseError::Syntax),
+                    Err(ParseError::EndOfStream) => {}
+                    Err(err) => return Err(err),
+                }
+                if name == "flushdb" {
+                    Command::FlushDb
+                } else {
+                    Command::FlushAll
+                }
+            }
+            "select" => Command::Select {
+                db: parse.next_int()? as usize,
+            },
+            "move" => Command::Move {
+                key: parse.next_string()?,
+                db: parse.next_int()? as usize,
+            },
+            "swapdb" => Command::SwapDb {
+                first: parse.next_int()? as usize,
+                second: parse.next_int()? as usize,
+            },
+            "save" => Command::Save,
+            "bgsave" => Command::BgSave,
+            "bgrewriteaof" => Command::BgRewriteAof,
+            "publish" => Command::Publish {
+                channel: parse.next_string()?,
+                message: parse.next_bytes()?,
+            },
+            "subscribe" => Command::Subscribe {
+                channels: at_least_one(parse)?,
+            },
+            "unsubscribe" => Command::Unsubscribe {
+                channels: remaining(parse)?,
+            },
+            "psubscribe" => Command::PSubscribe {
+                patterns: at_least_one(parse)?,
+            },
+            "punsubscribe" => Command::PUnsubscribe {
+                patterns: remaining(parse)?,
+            },
+            "ping" => Command::Ping {
+                message: match parse.next_bytes() {
+                    Ok(message) => Some(message),
+                    Err(ParseError::EndOfStream) => None,
+                    Err(err) => return Err(err),
+                },
+            },
+            "info" => Command::Info {
+                section: match parse.next_string() {
+                    Ok(section) => Some(section.to_lowercase()),
+                    Err(ParseError::EndOfStream) => None,
+                    Err(err) => return Err(err),
+                },
+            },
+            "reshard" => Command::Reshard {
+                shards: match parse.next_int()? {
+                    0 => return Err(ParseError::Invalid("number of shards must be positive")),
+                    shards => shards as usize,
+                },
+            },
+            "multi" => Command::Multi,
+            "exec" => Command::Exec,
+            "discard" => Command::Discard,
+            "watch" => Command::Watch {
+                keys: at_least_one(parse)?,
+            },
+            "unwatch" => Command::Unwatch,
+            "replicaof" | "slaveof" => {
+                let host = parse.next_string()?;
+                let port = parse.next_string()?;
+                let primary = if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one")
+                {
+                    None
+                } else {
+                    let port = port
+                        .parse()
+                        .map_err(|_| ParseError::Invalid("Invalid master port"))?;
+                    Some((host, port))
+                };
+                Command::ReplicaOf { primary }
+            }
+            "psync" => Command::Psync {
+                replid: parse.next_string()?,
+                offset: u64::try_from(parse.next_signed()?).ok(),
+            },
+            "replconf" => {
+                let option = parse.next_string()?;
+                if option.eq_ignore_ascii_case("listening-port") {
+                    Command::ReplConf(ReplConf::ListeningPort(port(parse)?))
+                } else if option.eq_ignore_ascii_case("ack") {
+                    Command::ReplConf(ReplConf::Ack(parse.next_int()?))
+                } else {
+                    return Err(ParseError::Syntax);
+                }
+            }
+            "cluster" => Command::Cluster(cluster_command(parse)?),
+            "auth" => {
+                let first = parse.next_string()?;
+                match parse.remaining() {
+                    0 => Command::Auth {
+                        username: None,
+                        password: first,
+                    },
+                    _ => Command::Auth {
+                        username: Some(first),
+                        password: parse.next_string()?,
+                    },
+                }
+            }
+            "hello" => {
+                let protover = match parse.remaining() {
+                    0 => None,
+                    _ => Some(parse.next_int()?),
+                };
+                let mut auth = None;
+                while parse.remaining() > 0 {
+                    let option = parse.next_string()?;
+                    if option.eq_ignore_ascii_case("AUTH") {
+                        auth = Some((parse.next_string()?, parse.next_string()?));
+                    } else if option.eq_ignore_ascii_case("SETNAME") {
+                        // Nothing reads client names back, so they are not
+                        // kept.
+                        parse.next_string()?;
+                    } else {
+                        return Err(ParseError::Syntax);
+                    }
+                }
+                Command::Hello { protover, auth }
+            }
+            "acl" => Command::Acl(acl_command(parse)?),
+            "command" => Command::CommandInfo(match parse.remaining() {
+                0 => CommandInfo::List,
+                _ => command_info(parse)?,
+            }),
+            _ => return Ok(None),
+        };
+
+        parse.finish()?;
+        Ok(Some(command))
+    }
+}
+
+/// Reads an expire time relative to now, given in units of `unit_ms`
+/// milliseconds, and returns it in milliseconds. As in Redis, the deadline
+/// must fit in signed Unix milliseconds.
+pub fn expire_ms(parse: &mut Parse, unit_ms: i64) -> Result<i64, ParseError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    parse
+        .next_signed()?
+        .checked_mul(unit_ms)
+        .filter(|ms| ms.checked_add(now).is_some())
+        .ok_or(ParseError::Invalid("invalid expire time"))
+}
+
+fn acl_command(parse: &mut Parse) -> Result<AclCommand, ParseError> {
+    let subcommand = parse.next_string()?.to_lowercase();
+    let command = match &subcommand[..] {
+        "whoami" => AclCommand::WhoAmI,
+        "list" => AclCommand::List,
+        "getuser" => AclCommand::GetUser(parse.next_string()?),
+        "setuser" => AclCommand::SetUser {
+            name: parse.next_string()?,
+            rules: remaining(parse)?,
+        },
+        "deluser" => AclCommand::DelUser(at_least_one(parse)?),
+        _ => return Err(ParseError::Syntax),
+    };
+    Ok(command)
+}
+
+fn command_info(parse: &mut Parse) -> Result<CommandInfo, ParseError> {
+    let subcommand = parse.next_string()?.to_lowercase();
+    let command = match &subcommand[..] {
+        "count" => CommandInfo::Count,
+        "info" => CommandInfo::Info(remaining(parse)?),
+        _ => return Err(ParseError::Syntax),
+    };
+    Ok(command)
+}
+
+fn cluster_command(parse: &mut Parse) -> Result<ClusterCommand, ParseError> {
+    let subcommand = parse.next_string()?.to_lowercase();
+    let command = match &subcommand[..] {
+        "info" => ClusterCommand::Info,
+        "myid" => ClusterCommand::MyId,
+        "keyslot" => ClusterCommand::KeySlot(parse.next_string()?),
+        "slots" => ClusterCommand::Slots,
+        "nodes" => ClusterCommand::Nodes,
+        "meet" => ClusterCommand::Meet {
+            host: parse.next_string()?,
+            port: port(parse)?,
+            bus_port: match parse.remaining() {
+                0 => None,
+                _ => Some(port(parse)?),
+            },
+        },
+        "addslots" => {
+            let mut ranges = vec![];
+            while ranges.is_empty() || parse.remaining() > 0 {
+                let slot = slot(parse)?;
+                ranges.push((slot, slot));
+            }
+            ClusterCommand::AddSlots(ranges)
+        }
+        "addslotsrange" => ClusterCommand::AddSlots(pairs(parse, |parse| {
+            let (first, last) = (slot(parse)?, slot(parse)?);
+            if first > last {
+                return Err(ParseError::Invalid(
+                    "start slot number is greater than end slot",
+                ));
+            }
+            Ok((first, last))
+        })?),
+        _ => return Err(ParseError::Syntax),
+    };
+    Ok(command)
+}
+
+fn port(parse: &mut Parse) -> Result<u16, ParseError> {
+    u16::try_from(parse.next_int()?).map_err(|_| ParseError::Invalid("port is out of range"))
+}
+
+fn slot(parse: &mut Parse) -> Result<u16, ParseError> {
+    match u16::try_from(parse.next_int()?) {
+        Ok(slot) if slot < KEY_SLOTS => Ok(slot),
+        _ => Err(ParseError::Invalid("Invalid or out of range slot")),
+    }
+}
+
+/// Reads all remaining entries as strings.
+fn remaining(parse: &mut Parse) -> Result<Vec<String>, ParseError> {
+    let mut values = Vec::new();
+    loop {
+        match parse.next_string() {
+            Ok(value) => values.push(value),
+            Err(ParseError::EndOfStream) => return Ok(values),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Like `remaining`, but there must be at least one entry.
+fn at_least_one(parse: &mut Parse) -> Result<Vec<String>, ParseError> {
+    let values = remaining(parse)?;
+    if values.is_empty() {
+        return Err(ParseError::EndOfStream);
+    }
+    Ok(values)
+}
+
+/// Reads all remaining entries as bytes; there must be at least one.
+fn at_least_one_bytes(parse: &mut Parse) -> Result<Vec<Bytes>, ParseError> {
+    let mut values = vec![parse.next_bytes()?];
+    while parse.remaining() > 0 {
+        values.push(parse.next_bytes()?);
+    }
+    Ok(values)
+}
+
+/// Reads all remaining entries two at a time with `read`; there must be at
+/// least one pair and no entry left over.
+fn pairs<T>(
+    parse: &mut Parse,
+    read: impl Fn(&mut Parse) -> Result<T, ParseError>,
+) -> Result<Vec<T>, ParseError> {
+    if parse.remaining() == 0 || !parse.remaining().is_multiple_of(2) {
+        return Err(ParseError::EndOfStream);
+    }
+    let mut values = Vec::with_capacity(parse.remaining() / 2);
+    while parse.remaining() > 0 {
+        values.push(read(parse)?);
+    }
+    Ok(values)
+}
@@ -0,0 +1,87 @@
+use crate::codec::RespCodec;
+use crate::frame::{Frame, Protocol};
+use futures::{SinkExt, StreamExt};
+use std::io;
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// Replies held back for a batched flush are written out once they reach
+/// this size, so a long pipeline does not buffer all of its replies.
+const MAX_PENDING: usize = 64 * 1024;
+
+/// Reads and writes `Frame`s on a socket through a `RespCodec`.
+///
+/// Unlike `mini_redis::Connection`, which cannot be tuned, the codec caps
+/// the bulk strings and nesting a peer may send and accepts inline
+/// commands. Replies are encoded recursively, so arrays can hold arrays,
+/// in the protocol the client picked with HELLO, RESP2 until it does.
+///
+/// Replies can also be queued with `queue_frame` and sent with a single
+/// `flush`, which is how pipelined commands are answered.
+pub struct Connection {
+    framed: Framed<TcpStream, RespCodec>,
+}
+
+impl Connection {
+    pub fn new(socket: TcpStream, codec: RespCodec) -> Connection {
+        let mut framed = Framed::with_capacity(socket, codec, 4 * 1024);
+        framed.set_backpressure_boundary(MAX_PENDING);
+        Connection { framed }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.framed.codec().protocol()
+    }
+
+    /// Switches the encoding of the frames written from now on.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.framed.codec_mut().set_protocol(protocol);
+    }
+
+    /// Reads the next frame. `Ok(None)` means the peer closed the
+    /// connection between two frames.
+    pub async fn read_frame(&mut self) -> mini_redis::Result<Option<Frame>> {
+        Ok(self.framed.next().await.transpose()?)
+    }
+
+    /// Whether the read buffer already holds a whole frame, or bytes that
+    /// can never become one, so `read_frame` returns without waiting.
+    pub fn has_buffered_frame(&self) -> bool {
+        self.framed.codec().has_frame(self.framed.read_buffer())
+    }
+
+    /// Bytes of every frame read so far, which is how a replica counts its
+    /// offset in the replication stream.
+    pub fn bytes_read(&self) -> u64 {
+        self.framed.codec().bytes_read()
+    }
+
+    /// Whether queuing another frame first sends the queued ones, because
+    /// they reached `MAX_PENDING` bytes.
+    pub fn queue_is_full(&self) -> bool {
+        self.framed.write_buffer().len() >= MAX_PENDING
+    }
+
+    /// Writes `frame`, after any queued replies, and flushes them all.
+    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        Ok(self.framed.send(frame).await?)
+    }
+
+    /// Writes data that is already RESP, such as the replication stream,
+    /// after any queued replies, and flushes them all.
+    pub async fn write_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        self.framed.write_buffer_mut().extend_from_slice(data);
+        self.flush().await
+    }
+
+    /// Queues `frame` to be sent by the next `flush`, or sooner once
+    /// `MAX_PENDING` bytes are queued.
+    pub async fn queue_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        Ok(self.framed.feed(frame).await?)
+    }
+
+    /// Sends every queued reply to the socket.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        Ok(SinkExt::<&Frame>::flush(&mut self.framed).await?)
+    }
+}
@@ -0,0 +1,1469 @@
+use crate::error::CommandError;
+use crate::hasher::ShardHasher;
+use crate::value::Value;
+use bytes::{Bytes, BytesMut};
+use indexmap::IndexMap;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::ops::DerefMut;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_official_tutorial_code_minis::config::MaxMemoryPolicy;
+use tracing::info;
+
+/// Keys moved per lock of an old shard while resharding, so commands
+/// waiting on it are held up for one batch at most.
+const MIGRATION_BATCH: usize = 256;
+
+/// The keyspace, split into shards that are locked independently.
+///
+/// The number of shards can change while the server runs. `reshard` puts a
+/// new table of shards in place and moves the keys over from the old one in
+/// the background. Until that is done, locking a key's shard also locks the
+/// old shard it used to live in, and the key is moved on the spot if it is
+/// still there, so commands only ever see keys in the new table.
+#[derive(Clone)]
+pub struct ShardedDb {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    tables: RwLock<Tables>,
+    /// Held for the whole of a resharding, and by anything that needs to
+    /// visit every key exactly once while walking the shards.
+    frozen: tokio::sync::Mutex<()>,
+    resharding: AtomicBool,
+    /// Old shards emptied so far by the running resharding.
+    migrated: AtomicUsize,
+    /// Given to every shard of the database, old or new.
+    used: UsedMemory,
+}
+
+type Table = Vec<Mutex<Shard>>;
+
+struct Tables {
+    current: Arc<Table>,
+    /// The table keys are being moved out of, while resharding.
+    previous: Option<Arc<Table>>,
+    /// Maps keys to shards, the same way for both tables.
+    hasher: Box<dyn ShardHasher>,
+    /// Number of tables made current so far, after the first. The previous
+    /// table is of the generation before.
+    generation: u64,
+}
+
+impl Tables {
+    fn hash(&self, key: &str) -> u64 {
+        self.hasher.hash(key.as_bytes())
+    }
+
+    /// See `Layout::lock`.
+    fn lock<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> LockedShards<'_> {
+        let hashes: Vec<u64> = keys
+            .into_iter()
+            .map(|key| self.hash(key.as_ref()))
+            .collect();
+        let previous = self.previous.as_deref();
+        LockedShards {
+            tables: self,
+            previous: previous.map_or_else(BTreeMap::new, |table| lock_table(table, &hashes)),
+            guards: lock_table(&self.current, &hashes),
+        }
+    }
+
+    /// See `ShardedDb::scan`.
+    fn scan(&self, cursor: u64, count: usize, mut f: impl FnMut(&str)) -> u64 {
+        let previous = self.previous.as_ref();
+        let previous = previous.map(|table| (self.generation.wrapping_sub(1), table));
+        let walk: Vec<(u64, &Arc<Table>)> = previous
+            .into_iter()
+            .chain([(self.generation, &self.current)])
+            .collect();
+        let mut at = cursor
+            .checked_sub(1)
+            .and_then(|cursor| ScanCursor::decode(cursor, &walk))
+            .unwrap_or_default();
+        let now = Instant::now();
+        let mut keys_left = count;
+        let mut shards_left = count.saturating_mul(10);
+        while at.table < walk.len() {
+            let (generation, table) = walk[at.table];
+            if keys_left == 0 || shards_left == 0 {
+                return at.encode(generation, table.len()) + 1;
+            }
+            let shard = table[at.shard].lock().unwrap();
+            let (end, seen) = shard.scan(at.end, keys_left, now, &mut f);
+            keys_left -= seen;
+            shards_left -= 1;
+            at.end = end;
+            if end == 0 {
+                at.shard += 1;
+                if at.shard == table.len() {
+                    at.table += 1;
+                    at.shard = 0;
+                }
+            }
+        }
+        0
+    }
+
+    /// See `ShardedDb::random_key`.
+    fn random_key(&self) -> Option<String> {
+        let previous = self.previous.iter().flat_map(|table| table.iter());
+        let shards: Vec<&Mutex<Shard>> = previous.chain(self.current.iter()).collect();
+        let start = RandomState::new().hash_one(0u64) as usize;
+        let now = Instant::now();
+        (0..shards.len()).find_map(|i| {
+            let shard = &shards[(start + i) % shards.len()];
+            shard.lock().unwrap().random_key(now)
+        })
+    }
+}
+
+/// A database of `num_shards` shards, whose keys and values count towards
+/// `used`.
+pub fn new_sharded_db(
+    num_shards: usize,
+    hasher: Box<dyn ShardHasher>,
+    used: UsedMemory,
+) -> ShardedDb {
+    ShardedDb {
+        inner: Arc::new(Inner {
+            tables: RwLock::new(Tables {
+                current: Arc::new(new_table(num_shards, &used)),
+                previous: None,
+                hasher,
+                generation: 0,
+            }),
+            frozen: tokio::sync::Mutex::new(()),
+            resharding: AtomicBool::new(false),
+            migrated: AtomicUsize::new(0),
+            used,
+        }),
+    }
+}
+
+/// A database of `num_shards` shards, keyed with SipHash, for tests.
+#[cfg(test)]
+pub fn new_test_db(num_shards: usize, used: UsedMemory) -> ShardedDb {
+    use crate::hasher::new_shard_hasher;
+    use tokio_official_tutorial_code_minis::config::ShardHash;
+    new_sharded_db(num_shards, new_shard_hasher(ShardHash::SipHash), used)
+}
+
+fn new_table(num_shards: usize, used: &UsedMemory) -> Table {
+    let mut table = Vec::with_capacity(num_shards);
+    for _ in 0..num_shards {
+        table.push(Mutex::new(Shard {
+            used: used.clone(),
+            ..Shard::default()
+        }));
+    }
+    table
+}
+
+/// Approximate bytes held by the keys and values of every shard it was
+/// given to, which is every database of the server, to be checked against
+/// `maxmemory`.
+#[derive(Clone, Debug, Default)]
+pub struct UsedMemory(Arc<AtomicUsize>);
+
+impl UsedMemory {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Shard counts of the database, as reported by INFO.
+pub struct LayoutStats {
+    pub shards: Vec<ShardStats>,
+    /// The shards of the old table while resharding, empty otherwise.
+    pub previous: Vec<ShardStats>,
+    /// Old shards already emptied.
+    pub migrated: usize,
+}
+
+impl ShardedDb {
+    /// Pins the current tables, so the shards of keys can be locked with
+    /// `Layout::lock`. Resharding cannot switch tables until it is dropped.
+    ///
+    /// Do not call this again while holding the result: a switch waiting
+    /// in between would deadlock.
+    pub fn layout(&self) -> Layout<'_> {
+        Layout {
+            tables: self.inner.tables.read().unwrap(),
+        }
+    }
+
+    /// Runs `f` on the shard of `key`, locked.
+    pub fn with_shard<T>(&self, key: &str, f: impl FnOnce(&mut Shard) -> T) -> T {
+        let layout = self.layout();
+        let mut shards = layout.lock([key]);
+        f(shards.get(key).1)
+    }
+
+    /// Calls `f` with each shard in turn, those of the old table first
+    /// while resharding. `index` is the position of the shard in that
+    /// order.
+    ///
+    /// Keys can move from the old table to the new one in the meantime,
+    /// so hold `freeze` to see each of them exactly once.
+    pub fn for_each_shard(&self, mut f: impl FnMut(usize, &mut Shard)) {
+        let (previous, current) = {
+            let tables = self.inner.tables.read().unwrap();
+            (tables.previous.clone(), tables.current.clone())
+        };
+        let shards = previous.iter().flat_map(|table| table.iter());
+        for (index, shard) in shards.chain(current.iter()).enumerate() {
+            f(index, &mut shard.lock().unwrap());
+        }
+    }
+
+    /// Calls `f` with the shard at `index` in the order of
+    /// `for_each_shard`, wrapping around past the last one, and that
+    /// index.
+    pub fn with_shard_at<T>(&self, index: usize, f: impl FnOnce(usize, &mut Shard) -> T) -> T {
+        let (previous, current) = {
+            let tables = self.inner.tables.read().unwrap();
+            (tables.previous.clone(), tables.current.clone())
+        };
+        let old = previous.as_ref().map_or(0, |table| table.len());
+        let index = index % (old + current.len());
+        let shard = match &previous {
+            Some(previous) if index < old => &previous[index],
+            _ => &current[index - old],
+        };
+        let mut shard = shard.lock().unwrap();
+        f(index, &mut shard)
+    }
+
+    /// Holds off every command, and every step of a running resharding,
+    /// until the result is dropped, so the shards can be read as they were
+    /// at a single point in time.
+    ///
+    /// Like `layout`, this must not be called while holding a layout.
+    pub fn pause(&self) -> Paused<'_> {
+        Paused {
+            tables: self.inner.tables.write().unwrap(),
+        }
+    }
+
+    /// Removes every key, as a write to each of them.
+    pub fn clear(&self) {
+        self.pause().clear();
+    }
+
+    /// Counts the keys, including expired ones not yet purged, pausing
+    /// commands so none is counted twice while resharding.
+    pub fn len(&self) -> usize {
+        self.pause().len()
+    }
+
+    /// Calls `f` with the keys of the next few shards, locked one at a
+    /// time, starting where `cursor` left off, or from the first shard if
+    /// it is 0. Stops once `count` keys have been looked at, or ten times
+    /// as many shards, and returns the cursor to continue from, 0 once
+    /// every shard has been visited.
+    ///
+    /// Shards are walked in the order of `for_each_shard`, and each of them
+    /// from its last key down. Removing a key only ever moves another one
+    /// to a lower position, new keys go at the end, and resharding moves
+    /// keys to the new table, which comes later, so every key present for
+    /// the whole scan is seen at least once. Some may be seen twice. A
+    /// cursor into a table a finished resharding dropped starts over.
+    pub fn scan(&self, cursor: u64, count: usize, f: impl FnMut(&str)) -> u64 {
+        self.inner.tables.read().unwrap().scan(cursor, count, f)
+    }
+
+    /// A random key, or `None` if there is none.
+    ///
+    /// The shard is picked at random, and shards after it are tried in turn
+    /// while it holds no key, so the pick is not quite uniform.
+    pub fn random_key(&self) -> Option<String> {
+        self.inner.tables.read().unwrap().random_key()
+    }
+
+    /// Waits for a running resharding to finish, and keeps another from
+    /// starting until the guard is dropped.
+    pub async fn freeze(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.inner.frozen.lock().await
+    }
+
+    /// As `freeze`, from a thread outside the runtime, such as one of
+    /// `spawn_blocking`.
+    pub fn blocking_freeze(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.inner.frozen.blocking_lock()
+    }
+
+    pub fn stats(&self) -> LayoutStats {
+        let (previous, current) = {
+            let tables = self.inner.tables.read().unwrap();
+            (tables.previous.clone(), tables.current.clone())
+        };
+        let stats = |table: &Table| -> Vec<ShardStats> {
+            let shards = table.iter();
+            shards.map(|shard| shard.lock().unwrap().stats()).collect()
+        };
+        LayoutStats {
+            shards: stats(&current),
+            previous: previous.as_deref().map(stats).unwrap_or_default(),
+            migrated: self.inner.migrated.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Starts moving every key to a new table of `num_shards` shards, in
+    /// the background. Returns `false` if a resharding is already running.
+    ///
+    /// Writers to the AOF and snapshots `freeze` the layout, so either
+    /// waits for the other.
+    pub fn reshard(&self, num_shards: usize) -> bool {
+        if self.inner.resharding.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        let db = self.clone();
+        tokio::spawn(async move {
+            let frozen = db.freeze().await;
+            let started = Instant::now();
+            let from = db.switch_tables(num_shards);
+            info!("Resharding from {} to {} shards", from, num_shards);
+            for index in 0..from {
+                while !db.migrate_batch(index) {
+                    tokio::task::yield_now().await;
+                }
+                db.inner.migrated.store(index + 1, Ordering::Relaxed);
+                tokio::task::yield_now().await;
+            }
+            db.inner.tables.write().unwrap().previous = None;
+            db.inner.migrated.store(0, Ordering::Relaxed);
+            drop(frozen);
+            db.inner.resharding.store(false, Ordering::SeqCst);
+            info!("Resharding finished in {:?}", started.elapsed());
+        });
+        true
+    }
+
+    /// Makes a new, empty table of `num_shards` current, and returns the
+    /// size of the old one.
+    fn switch_tables(&self, num_shards: usize) -> usize {
+        let table = Arc::new(new_table(num_shards, &self.inner.used));
+        let mut tables = self.inner.tables.write().unwrap();
+        let previous = std::mem::replace(&mut tables.current, table);
+        let from = previous.len();
+        tables.previous = Some(previous);
+        tables.generation += 1;
+        from
+    }
+
+    /// Moves up to `MIGRATION_BATCH` keys out of shard `index` of the old
+    /// table. Returns `true` once the shard is empty.
+    fn migrate_batch(&self, index: usize) -> bool {
+        let tables = self.inner.tables.read().unwrap();
+        let previous = tables.previous.as_ref().expect("not resharding");
+        let mut old = previous[index].lock().unwrap();
+        let moved = old.take_some(MIGRATION_BATCH);
+        let done = old.is_drained();
+        let mut targets = BTreeMap::new();
+        for (key, moving) in moved {
+            targets
+                .entry(tables.hash(&key) as usize % tables.current.len())
+                .or_insert_with(Vec::new)
+                .push((key, moving));
+        }
+        // Old shards are always locked before new ones, as in `lock`.
+        for (target, keys) in targets {
+            let mut shard = tables.current[target].lock().unwrap();
+            for (key, moving) in keys {
+                shard.adopt(key, moving);
+            }
+        }
+        done
+    }
+}
+
+/// The tables of a `ShardedDb`, pinned by `ShardedDb::layout`.
+pub struct Layout<'a> {
+    tables: RwLockReadGuard<'a, Tables>,
+}
+
+impl Layout<'_> {
+    /// Locks the shards holding `keys`, each one once, in ascending index
+    /// order, and the old shards they are moving out of first.
+    ///
+    /// Every command and transaction goes through here, so two of them can
+    /// never each hold a shard the other one is waiting for.
+    pub fn lock<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> LockedShards<'_> {
+        self.tables.lock(keys)
+    }
+}
+
+fn lock_table<'a>(table: &'a Table, hashes: &[u64]) -> BTreeMap<usize, MutexGuard<'a, Shard>> {
+    let mut indexes: Vec<usize> = hashes
+        .iter()
+        .map(|&hash| hash as usize % table.len())
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes
+        .into_iter()
+        .map(|index| (index, table[index].lock().unwrap()))
+        .collect()
+}
+
+/// The tables of a `ShardedDb` while no command runs, from
+/// `ShardedDb::pause`.
+pub struct Paused<'a> {
+    tables: RwLockWriteGuard<'a, Tables>,
+}
+
+impl Paused<'_> {
+    /// Calls `f` with each shard in turn, in the same order as
+    /// `ShardedDb::for_each_shard`. Keys cannot move in the meantime.
+    pub fn for_each_shard(&self, mut f: impl FnMut(usize, &mut Shard)) {
+        let shards = self.tables.previous.iter().flat_map(|table| table.iter());
+        for (index, shard) in shards.chain(self.tables.current.iter()).enumerate() {
+            f(index, &mut shard.lock().unwrap());
+        }
+    }
+
+    /// Removes every key, as a write to each of them.
+    pub fn clear(&self) {
+        self.for_each_shard(|_, shard| shard.clear());
+    }
+
+    /// Locks the shards holding `keys`, as `Layout::lock` does, for
+    /// commands on single keys to run while the database is paused.
+    pub fn lock<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> LockedShards<'_> {
+        self.tables.lock(keys)
+    }
+
+    /// As `ShardedDb::len`.
+    pub fn len(&self) -> usize {
+        let mut keys = 0;
+        self.for_each_shard(|_, shard| keys += shard.len());
+        keys
+    }
+
+    /// As `ShardedDb::scan`.
+    pub fn scan(&self, cursor: u64, count: usize, f: impl FnMut(&str)) -> u64 {
+        self.tables.scan(cursor, count, f)
+    }
+
+    /// As `ShardedDb::random_key`.
+    pub fn random_key(&self) -> Option<String> {
+        self.tables.random_key()
+    }
+
+    /// Swaps every key with those of `other`, as a write to each watched
+    /// key of both. The watches themselves stay where they are.
+    ///
+    /// Neither database may be resharding, so both must be frozen too.
+    pub fn swap(&mut self, other: &mut Paused<'_>) {
+        let (ours, theirs) = (&mut *self.tables, &mut *other.tables);
+        assert!(
+            ours.previous.is_none() && theirs.previous.is_none(),
+            "swapping databases while resharding"
+        );
+        std::mem::swap(&mut ours.current, &mut theirs.current);
+        // SCAN cursors into either start over.
+        ours.generation += 1;
+        theirs.generation += 1;
+        // Each table still holds the watches of the database it came from.
+        let watched = (take_versions(theirs), take_versions(ours));
+        restore_versions(ours, watched.0);
+        restore_versions(theirs, watched.1);
+    }
+}
+
+/// Where `ShardedDb::scan` left off, packed into the cursor given to
+/// clients as the low 8 bits of the generation of the table, then the
+/// shard index and end position, in the table's number of shards as
+/// radix.
+#[derive(Default)]
+struct ScanCursor {
+    /// Index of the table in the walk.
+    table: usize,
+    shard: usize,
+    /// Keys below this position are left to visit, or all of them if 0.
+    end: usize,
+}
+
+impl ScanCursor {
+    /// Returns `None` if no table in `walk` is of the generation `cursor`
+    /// was made in.
+    fn decode(cursor: u64, walk: &[(u64, &Arc<Table>)]) -> Option<ScanCursor> {
+        let table = walk
+            .iter()
+            .position(|(generation, _)| *generation as u8 == cursor as u8)?;
+        let (rest, len) = (cursor >> 8, walk[table].1.len() as u64);
+        Some(ScanCursor {
+            table,
+            shard: (rest % len) as usize,
+            end: (rest / len) as usize,
+        })
+    }
+
+    fn encode(&self, generation: u64, len: usize) -> u64 {
+        let rest = self.end as u64 * len as u64 + self.shard as u64;
+        rest << 8 | generation as u8 as u64
+    }
+}
+
+fn take_versions(tables: &Tables) -> Vec<(String, Version)> {
+    let shards = tables.current.iter();
+    let versions = shards.flat_map(|shard| std::mem::take(&mut shard.lock().unwrap().versions));
+    versions.collect()
+}
+
+/// Puts back watches taken with `take_versions`, bumped.
+fn restore_versions(tables: &Tables, versions: Vec<(String, Version)>) {
+    for (key, mut version) in versions {
+        version.version += 1;
+        let index = tables.hash(&key) as usize % tables.current.len();
+        let mut shard = tables.current[index].lock().unwrap();
+        shard.versions.insert(key, version);
+    }
+}
+
+/// The shards locked by `Layout::lock`, released on drop.
+///
+/// `S` is how each shard is held: by its guard, or borrowed from the guard
+/// in a `ShardView`.
+pub struct LockedShards<'a, S = MutexGuard<'a, Shard>> {
+    tables: &'a Tables,
+    previous: BTreeMap<usize, S>,
+    guards: BTreeMap<usize, S>,
+}
+
+/// Shards borrowed from a `LockedShards`, without their guards, so code
+/// that must be `Send` can work on them.
+pub type ShardView<'a> = LockedShards<'a, &'a mut Shard>;
+
+impl<S: DerefMut<Target = Shard>> LockedShards<'_, S> {
+    /// The index and shard of `key`, which must have been passed to
+    /// `Layout::lock`.
+    pub fn get(&mut self, key: &str) -> (usize, &mut Shard) {
+        let hash = self.tables.hash(key);
+        let index = hash as usize % self.tables.current.len();
+        let shard = self
+            .guards
+            .get_mut(&index)
+            .expect("key was not locked by Layout::lock");
+        if let Some(previous) = &self.tables.previous {
+            let old = self
+                .previous
+                .get_mut(&(hash as usize % previous.len()))
+                .expect("key was not locked by Layout::lock");
+            if let Some(moving) = old.take(key) {
+                shard.adopt(key.to_string(), moving);
+            }
+        }
+        (index, shard)
+    }
+}
+
+impl LockedShards<'_> {
+    /// The locked shards, borrowed from their guards.
+    pub fn view(&mut self) -> ShardView<'_> {
+        LockedShards {
+            tables: self.tables,
+            previous: borrow_guards(&mut self.previous),
+            guards: borrow_guards(&mut self.guards),
+        }
+    }
+}
+
+fn borrow_guards<'a>(
+    guards: &'a mut BTreeMap<usize, MutexGuard<'_, Shard>>,
+) -> BTreeMap<usize, &'a mut Shard> {
+    guards
+        .iter_mut()
+        .map(|(&index, guard)| (index, &mut **guard))
+        .collect()
+}
+
+/// Converts a deadline to wall clock milliseconds since the Unix epoch, the
+/// form in which deadlines are written to disk.
+pub fn to_unix_ms(when: Instant, now: Instant) -> u64 {
+    let wall = SystemTime::now() + when.saturating_duration_since(now);
+    wall.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Time left until the wall clock reaches `ms`, zero if it already has.
+pub fn until_unix_ms(ms: u64) -> Duration {
+    let wall = UNIX_EPOCH + Duration::from_millis(ms);
+    wall.duration_since(SystemTime::now()).unwrap_or_default()
+}
+
+pub fn from_unix_ms(ms: u64, now: Instant) -> Instant {
+    now + until_unix_ms(ms)
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub value: Value,
+    pub expires_at: Option<Instant>,
+    /// Approximate bytes of the key and value, as last measured.
+    size: usize,
+    access: Access,
+}
+
+impl Entry {
+    pub fn new(value: Value, expires_at: Option<Instant>) -> Entry {
+        Entry {
+            value,
+            expires_at,
+            size: 0,
+            access: Access::new(Instant::now()),
+        }
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|when| when <= now)
+    }
+}
+
+/// Starting use count of a new entry, so that it is not the first to go
+/// under LFU eviction.
+const LFU_INIT: u8 = 5;
+
+/// How much harder each use count is to reach than the one before.
+const LFU_LOG_FACTOR: u64 = 10;
+
+/// Idle time that takes one off a use count.
+const LFU_DECAY: Duration = Duration::from_secs(60);
+
+/// How recently and how often an entry was used, for eviction.
+#[derive(Debug, Clone, Copy)]
+struct Access {
+    last: Instant,
+    /// Logarithmic count of uses, which decays while the entry is idle, as
+    /// in Redis' LFU.
+    count: u8,
+}
+
+impl Access {
+    fn new(now: Instant) -> Access {
+        Access {
+            last: now,
+            count: LFU_INIT,
+        }
+    }
+
+    /// The use count, after the decay for the time idle.
+    fn count(&self, now: Instant) -> u8 {
+        let periods = now.saturating_duration_since(self.last).as_secs() / LFU_DECAY.as_secs();
+        self.count.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    /// Records a use. The count goes up with a probability that shrinks as
+    /// it grows, `random` deciding.
+    fn hit(&mut self, now: Instant, random: u64) {
+        let count = self.count(now);
+        let base = count.saturating_sub(LFU_INIT) as u64;
+        self.count = if count < u8::MAX && random.is_multiple_of(base * LFU_LOG_FACTOR + 1) {
+            count + 1
+        } else {
+            count
+        };
+        self.last = now;
+    }
+}
+
+/// Remaining time to live of a key, as reported by TTL and PTTL.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ttl {
+    Missing,
+    Persistent,
+    Remaining(Duration),
+}
+
+/// Size of a shard, as reported by INFO.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ShardStats {
+    pub keys: usize,
+    /// Keys with a deadline.
+    pub expires: usize,
+    /// Approximate bytes held by keys and values.
+    pub bytes: usize,
+}
+
+/// One shard of the database.
+///
+/// Every read goes through `live`, which drops an entry whose deadline has
+/// passed before returning it. Since callers always hold the shard mutex, a
+/// key can never be observed after its deadline even while the sweeper is
+/// working on the same shard.
+#[derive(Debug, Default)]
+pub struct Shard {
+    /// Indexed as well as keyed, so eviction can sample random keys.
+    entries: IndexMap<String, Entry>,
+    /// Version counters of the keys some client is WATCHing.
+    versions: HashMap<String, Version>,
+    /// Sum of the sizes of the entries.
+    bytes: usize,
+    /// Where `bytes` is added up with the other shards.
+    used: UsedMemory,
+    /// State of the random number generator eviction and LFU counts use.
+    rng: u64,
+}
+
+/// A key on its way between shards while resharding.
+struct Moving {
+    entry: Option<Entry>,
+    version: Option<Version>,
+}
+
+#[derive(Debug)]
+struct Version {
+    /// Bumped by every write to the key, including its expiry.
+    version: u64,
+    watchers: usize,
+}
+
+impl Shard {
+    fn live(&mut self, key: &str, now: Instant) -> Option<&mut Entry> {
+        if self.entries.get(key)?.is_expired(now) {
+            self.remove_entry(key);
+            self.touch(key);
+            return None;
+        }
+        let random = self.random();
+        let entry = self.entries.get_mut(key)?;
+        entry.access.hit(now, random);
+        Some(entry)
+    }
+
+    /// Stores `entry` under `key`, replacing any previous one.
+    fn insert(&mut self, key: String, mut entry: Entry) {
+        entry.size = key.len() + entry.value.approx_size();
+        self.grow(entry.size);
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.shrink(old.size);
+        }
+    }
+
+    fn remove_entry(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.swap_remove(key)?;
+        self.shrink(entry.size);
+        Some(entry)
+    }
+
+    fn grow(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        self.used.0.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn shrink(&mut self, bytes: usize) {
+        self.bytes -= bytes;
+        self.used.0.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Measures the size of `key` again, after a command changed its value
+    /// in place.
+    pub fn measure(&mut self, key: &str) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            let (old, size) = (entry.size, key.len() + entry.value.approx_size());
+            entry.size = size;
+            self.shrink(old);
+            self.grow(size);
+        }
+    }
+
+    /// Number of keys, including expired ones not yet purged.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// A xorshift64* step; good enough to sample keys, and cheap enough to
+    /// run on every access.
+    fn random(&mut self) -> u64 {
+        if self.rng == 0 {
+            self.rng = RandomState::new().hash_one(0u64) | 1;
+        }
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// The key `policy` picks among `samples` random ones, and its score,
+    /// the keys with higher scores going first. Returns `None` if there is
+    /// no key the policy may evict.
+    ///
+    /// Like Redis, this does not keep keys ordered by use; the best of a
+    /// few random keys is close enough to the best one overall.
+    pub fn eviction_candidate(
+        &mut self,
+        policy: MaxMemoryPolicy,
+        samples: usize,
+    ) -> Option<(String, u64)> {
+        let volatile = matches!(
+            policy,
+            MaxMemoryPolicy::VolatileLru | MaxMemoryPolicy::VolatileTtl
+        );
+        let now = Instant::now();
+        // Higher scores go first.
+        let score = |entry: &Entry| -> u64 {
+            match policy {
+                MaxMemoryPolicy::AllKeysLru | MaxMemoryPolicy::VolatileLru => {
+                    now.saturating_duration_since(entry.access.last).as_millis() as u64
+                }
+                MaxMemoryPolicy::AllKeysLfu => (u8::MAX - entry.access.count(now)) as u64,
+                MaxMemoryPolicy::VolatileTtl => {
+                    let ttl = entry
+                        .expires_at
+                        .map(|when| when.saturating_duration_since(now));
+                    u64::MAX
+                        - ttl
+                            .unwrap_or(Duration::MAX)
+                            .as_millis()
+                            .min(u64::MAX as u128) as u64
+                }
+                MaxMemoryPolicy::NoEviction => 0,
+            }
+        };
+
+        let mut best: Option<(usize, u64)> = None;
+        let mut found = 0;
+        // Random indexes may mostly land on keys without a deadline, which
+        // the volatile policies skip, so a few more are drawn.
+        for _ in 0..samples * 4 {
+            if found == samples || self.entries.is_empty() {
+                break;
+            }
+            let index = (self.random() % self.entries.len() as u64) as usize;
+            let entry = &self.entries[index];
+            if volatile && entry.expires_at.is_none() {
+                continue;
+            }
+            found += 1;
+            let score = score(entry);
+            if best.is_none_or(|(_, best)| score > best) {
+                best = Some((index, score));
+            }
+        }
+        let (index, score) = match best {
+            Some(best) => best,
+            // Keys with a deadline are rare then, but may still be there.
+            None if volatile => {
+                let mut entries = self.entries.values();
+                let index = entries.position(|entry| entry.expires_at.is_some())?;
+                (index, score(&self.entries[index]))
+            }
+            None => return None,
+        };
+        let (key, _) = self.entries.get_index(index)?;
+        Some((key.clone(), score))
+    }
+
+    /// Removes `key`, picked by `eviction_candidate`, as a write to it.
+    /// Returns `false` if it is gone already.
+    pub fn evict(&mut self, key: &str) -> bool {
+        if self.remove_entry(key).is_none() {
+            return false;
+        }
+        self.touch(key);
+        true
+    }
+
+    /// Calls `f` with the live keys among the `count` positions below
+    /// `end`, or below the last key if `end` is 0, from the highest down.
+    /// Returns the position to continue from, 0 once the first key has
+    /// been visited, and how many positions were looked at.
+    pub fn scan(
+        &self,
+        end: usize,
+        count: usize,
+        now: Instant,
+        mut f: impl FnMut(&str),
+    ) -> (usize, usize) {
+        let end = match end {
+            0 => self.entries.len(),
+            end => end.min(self.entries.len()),
+        };
+        let start = end.saturating_sub(count);
+        for (key, entry) in self.entries[start..end].iter().rev() {
+            if !entry.is_expired(now) {
+                f(key);
+            }
+        }
+        (start, end - start)
+    }
+
+    /// A live key picked at random, or `None` if there is none.
+    pub fn random_key(&mut self, now: Instant) -> Option<String> {
+        let len = self.entries.len();
+        let start = self.random() as usize;
+        (0..len)
+            .filter_map(|i| self.entries.get_index((start + i) % len))
+            .find(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+    }
+
+    /// Starts tracking writes to `key` and returns its current version.
+    pub fn watch(&mut self, key: &str) -> u64 {
+        let version = self.versions.entry(key.to_string()).or_insert(Version {
+            version: 0,
+            watchers: 0,
+        });
+        version.watchers += 1;
+        version.version
+    }
+
+    /// Undoes one `watch` of `key`.
+    pub fn unwatch(&mut self, key: &str) {
+        if let Some(version) = self.versions.get_mut(key) {
+            version.watchers -= 1;
+            if version.watchers == 0 {
+                self.versions.remove(key);
+            }
+        }
+    }
+
+    /// Version of a watched key, after dropping it if it just expired.
+    pub fn version(&mut self, key: &str) -> u64 {
+        self.live(key, Instant::now());
+        self.versions.get(key).map_or(0, |version| version.version)
+    }
+
+    /// Records a write to `key` for the clients watching it.
+    pub fn touch(&mut self, key: &str) {
+        if let Some(version) = self.versions.get_mut(key) {
+            version.version += 1;
+        }
+    }
+
+    /// The string at `key`. Fails with WRONGTYPE on any other kind of value.
+    pub fn get(&mut self, key: &str) -> Result<Option<Bytes>, CommandError> {
+        match self.live(key, Instant::now()) {
+            Some(entry) => Ok(Some(entry.value.as_string()?.clone())),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores the string `value`, replacing any previous value and deadline.
+    pub fn set(&mut self, key: String, value: Bytes, expire: Option<Duration>) {
+        // A deadline too far off for an `Instant` is never reached.
+        let expires_at = expire.and_then(|ttl| Instant::now().checked_add(ttl));
+        self.insert(key, Entry::new(Value::String(value), expires_at));
+    }
+
+    /// Stores the string `value`, keeping the deadline of any live value it
+    /// replaces.
+    pub fn set_keep_ttl(&mut self, key: String, value: Bytes) {
+        let expires_at = self
+            .live(&key, Instant::now())
+            .and_then(|entry| entry.expires_at);
+        self.insert(key, Entry::new(Value::String(value), expires_at));
+    }
+
+    /// Stores `value` only if `key` does not exist. Returns whether it did.
+    pub fn set_nx(&mut self, key: String, value: Bytes) -> bool {
+        if self.live(&key, Instant::now()).is_some() {
+            return false;
+        }
+        self.set(key, value, None);
+        true
+    }
+
+    /// Stores `value` without a deadline and returns the previous string.
+    pub fn get_set(&mut self, key: String, value: Bytes) -> Result<Option<Bytes>, CommandError> {
+        let old = self.get(&key)?;
+        self.set(key, value, None);
+        Ok(old)
+    }
+
+    /// Length of the string at `key`, 0 if it is missing.
+    pub fn strlen(&mut self, key: &str) -> Result<usize, CommandError> {
+        Ok(self.get(key)?.map_or(0, |value| value.len()))
+    }
+
+    /// Appends `data` to the string at `key`, creating the key if missing,
+    /// and returns the new length. The deadline is kept.
+    pub fn append(&mut self, key: &str, data: &[u8]) -> Result<usize, CommandError> {
+        match self.live(key, Instant::now()) {
+            Some(entry) => {
+                let old = entry.value.as_string()?;
+                let mut value = BytesMut::with_capacity(old.len() + data.len());
+                value.extend_from_slice(old);
+                value.extend_from_slice(data);
+                let len = value.len();
+                entry.value = Value::String(value.freeze());
+                Ok(len)
+            }
+            None => {
+                self.set(key.to_string(), Bytes::copy_from_slice(data), None);
+                Ok(data.len())
+            }
+        }
+    }
+
+    /// Adds `delta` to the integer stored at `key`, a missing key counting
+    /// as 0, and returns the result. The deadline is kept.
+    pub fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64, CommandError> {
+        match self.live(key, Instant::now()) {
+            Some(entry) => {
+                let current: i64 = std::str::from_utf8(entry.value.as_string()?)
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or(CommandError::NotInteger)?;
+                let value = current.checked_add(delta).ok_or(CommandError::Overflow)?;
+                entry.value = Value::String(Bytes::from(value.to_string()));
+                Ok(value)
+            }
+            None => {
+                self.set(key.to_string(), Bytes::from(delta.to_string()), None);
+                Ok(delta)
+            }
+        }
+    }
+
+    /// The value at `key`, of any kind.
+    pub fn value(&mut self, key: &str) -> Option<&mut Value> {
+        self.live(key, Instant::now()).map(|entry| &mut entry.value)
+    }
+
+    /// The value at `key`, or a new one from `create` stored without a
+    /// deadline if the key is missing.
+    pub fn value_or_insert(&mut self, key: &str, create: impl FnOnce() -> Value) -> &mut Value {
+        if self.live(key, Instant::now()).is_none() {
+            self.insert(key.to_string(), Entry::new(create(), None));
+        }
+        &mut self.entries.get_mut(key).unwrap().value
+    }
+
+    /// Removes up to `count` elements from the head (`front`) or tail of the
+    /// list at `key`, deleting the key once the list is empty.
+    pub fn pop(
+        &mut self,
+        key: &str,
+        count: usize,
+        front: bool,
+    ) -> Result<Vec<Bytes>, CommandError> {
+        let list = match self.value(key) {
+            Some(value) => value.as_list()?,
+            None => return Ok(Vec::new()),
+        };
+        let count = count.min(list.len());
+        let popped = if front {
+            list.drain(..count).collect()
+        } else {
+            list.drain(list.len() - count..).rev().collect()
+        };
+        self.remove_if_empty(key);
+        Ok(popped)
+    }
+
+    /// Deletes `key` if it holds a collection that is now empty.
+    pub fn remove_if_empty(&mut self, key: &str) {
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.value.is_empty())
+        {
+            self.remove_entry(key);
+        }
+    }
+
+    /// Deletes `key`. Returns `false` if it did not exist.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.live(key, Instant::now()).is_some() && self.remove_entry(key).is_some()
+    }
+
+    /// Deletes `key` and returns its entry, deadline included.
+    pub fn remove_live(&mut self, key: &str) -> Option<Entry> {
+        self.live(key, Instant::now())?;
+        self.remove_entry(key)
+    }
+
+    pub fn exists(&mut self, key: &str) -> bool {
+        self.live(key, Instant::now()).is_some()
+    }
+
+    pub fn ttl(&mut self, key: &str) -> Ttl {
+        let now = Instant::now();
+        match self.live(key, now) {
+            None => Ttl::Missing,
+            Some(Entry {
+                expires_at: None, ..
+            }) => Ttl::Persistent,
+            Some(Entry {
+                expires_at: Some(when),
+                ..
+            }) => Ttl::Remaining(*when - now),
+        }
+    }
+
+    /// Sets a deadline on an existing key, or deletes it if `ttl` is zero.
+    /// Returns `false` if the key is missing.
+    pub fn expire(&mut self, key: &str, ttl: Duration) -> bool {
+        if ttl.is_zero() {
+            return self.remove(key);
+        }
+        let now = Instant::now();
+        match self.live(key, now) {
+            Some(entry) => {
+                entry.expires_at = now.checked_add(ttl);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Clears the deadline of a key. Returns `false` if there was none.
+    pub fn persist(&mut self, key: &str) -> bool {
+        match self.live(key, Instant::now()) {
+            Some(entry) => entry.expires_at.take().is_some(),
+            None => false,
+        }
+    }
+
+    /// Iterates over the entries that are still alive at `now`.
+    pub fn iter_live(&self, now: Instant) -> impl Iterator<Item = (&String, &Entry)> {
+        self.entries
+            .iter()
+            .filter(move |(_, entry)| !entry.is_expired(now))
+    }
+
+    /// Removes `key`, with its version if watched, to move it to another
+    /// shard. Returns `None` if there was neither.
+    fn take(&mut self, key: &str) -> Option<Moving> {
+        let moving = Moving {
+            entry: self.remove_entry(key),
+            version: self.versions.remove(key),
+        };
+        (moving.entry.is_some() || moving.version.is_some()).then_some(moving)
+    }
+
+    /// Removes up to `count` keys with `take`.
+    fn take_some(&mut self, count: usize) -> Vec<(String, Moving)> {
+        let keys = self.entries.keys().chain(self.versions.keys());
+        let keys: Vec<String> = keys.take(count).cloned().collect();
+        keys.into_iter()
+            .filter_map(|key| self.take(&key).map(|moving| (key, moving)))
+            .collect()
+    }
+
+    fn is_drained(&self) -> bool {
+        self.entries.is_empty() && self.versions.is_empty()
+    }
+
+    /// Stores a key taken from another shard.
+    fn adopt(&mut self, key: String, moving: Moving) {
+        if let Some(version) = moving.version {
+            self.versions.insert(key.clone(), version);
+        }
+        if let Some(entry) = moving.entry {
+            self.insert(key, entry);
+        }
+    }
+
+    /// Inserts an entry as is, keeping its deadline.
+    pub fn insert_entry(&mut self, key: String, entry: Entry) {
+        self.insert(key, entry);
+    }
+
+    /// Drops every entry, bumping the versions of watched keys that had one.
+    pub fn clear(&mut self) {
+        for (key, version) in self.versions.iter_mut() {
+            if self.entries.contains_key(key) {
+                version.version += 1;
+            }
+        }
+        self.entries.clear();
+        self.shrink(self.bytes);
+    }
+
+    /// Counts the entries, including expired ones not yet purged.
+    pub fn stats(&self) -> ShardStats {
+        ShardStats {
+            keys: self.entries.len(),
+            expires: self
+                .entries
+                .values()
+                .filter(|e| e.expires_at.is_some())
+                .count(),
+            bytes: self.bytes,
+        }
+    }
+
+    /// Removes every expired entry and returns how many were dropped.
+    pub fn purge_expired(&mut self, now: Instant) -> usize {
+        for (key, version) in self.versions.iter_mut() {
+            if self
+                .entries
+                .get(key)
+                .is_some_and(|entry| entry.is_expired(now))
+            {
+                version.version += 1;
+            }
+        }
+        let before = self.entries.len();
+        let mut freed = 0;
+        self.entries.retain(|_, entry| {
+            let expired = entry.is_expired(now);
+            if expired {
+                freed += entry.size;
+            }
+            !expired
+        });
+        self.shrink(freed);
+        before - self.entries.len()
+    }
+}
+
+/// Shards with a key to evict looked at for each key evicted, the best of
+/// their candidates going.
+const EVICTION_SHARDS: usize = 8;
+
+/// Random shards tried for each key evicted, since most may be empty.
+const EVICTION_TRIES: usize = 64;
+
+/// Evicts keys from `dbs`, picked by `policy` among random ones, until the
+/// keys and values held in `used` fit in `maxmemory`. Fails if the policy
+/// is not to evict, or nothing is left it may evict.
+///
+/// `evicted` is called with the database, shard and key of each key
+/// evicted, while its shard is still locked.
+pub fn make_room(
+    dbs: &[ShardedDb],
+    used: &UsedMemory,
+    maxmemory: usize,
+    (policy, samples): (MaxMemoryPolicy, usize),
+    mut evicted: impl FnMut(usize, usize, &str),
+) -> Result<(), CommandError> {
+    let mut rng = RandomState::new().hash_one(Instant::now()) | 1;
+    let mut random = move || {
+        rng ^= rng >> 12;
+        rng ^= rng << 25;
+        rng ^= rng >> 27;
+        rng.wrapping_mul(0x2545_f491_4f6c_dd1d) as usize
+    };
+    while used.get() > maxmemory {
+        if policy == MaxMemoryPolicy::NoEviction {
+            return Err(CommandError::OutOfMemory);
+        }
+        // The best candidate of a few random shards of random databases.
+        let mut best: Option<(u64, usize, usize, String)> = None;
+        let mut found = 0;
+        for _ in 0..EVICTION_TRIES {
+            if found == EVICTION_SHARDS {
+                break;
+            }
+            let db = random() % dbs.len();
+            let candidate = dbs[db].with_shard_at(random(), |index, shard| {
+                let (key, score) = shard.eviction_candidate(policy, samples)?;
+                Some((score, db, index, key))
+            });
+            if let Some(candidate) = candidate {
+                found += 1;
+                if best.as_ref().is_none_or(|best| candidate.0 > best.0) {
+                    best = Some(candidate);
+                }
+            }
+        }
+        // Keys are few and far between, so look at every shard in turn.
+        for (db, sharded) in dbs.iter().enumerate() {
+            if best.is_some() {
+                break;
+            }
+            sharded.for_each_shard(|index, shard| {
+                if best.is_none() {
+                    best = shard
+                        .eviction_candidate(policy, samples)
+                        .map(|(key, score)| (score, db, index, key));
+                }
+            });
+        }
+        let (_, db, index, key) = best.ok_or(CommandError::OutOfMemory)?;
+        dbs[db].with_shard_at(index, |index, shard| {
+            if shard.evict(&key) {
+                evicted(db, index, &key);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Background task that periodically drops expired keys.
+///
+/// Shards are locked one at a time so connections only ever wait on the
+/// shard currently being swept.
+pub async fn sweep_expired(db: ShardedDb, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        db.for_each_shard(|_, shard| {
+            shard.purge_expired(Instant::now());
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn new_db(shards: usize) -> ShardedDb {
+        new_test_db(shards, Default::default())
+    }
+
+    #[test]
+    fn deadlines_past_what_an_instant_holds_never_come() {
+        let db = new_db(4);
+        let layout = db.layout();
+        let mut shards = layout.lock(["a", "b"]);
+        let shard = shards.get("a").1;
+        shard.set("a".to_string(), "1".into(), Some(Duration::MAX));
+        assert_eq!(shard.ttl("a"), Ttl::Persistent);
+        let shard = shards.get("b").1;
+        shard.set("b".to_string(), "1".into(), None);
+        assert!(shard.expire("b", Duration::MAX));
+        assert_eq!(shard.ttl("b"), Ttl::Persistent);
+    }
+
+    #[test]
+    fn expiring_with_a_zero_ttl_deletes_the_key() {
+        let db = new_db(4);
+        let layout = db.layout();
+        let mut shards = layout.lock(["a"]);
+        let shard = shards.get("a").1;
+        shard.set("a".to_string(), "1".into(), None);
+        assert!(shard.expire("a", Duration::ZERO));
+        assert_eq!(shard.ttl("a"), Ttl::Missing);
+        assert!(!shard.expire("a", Duration::ZERO));
+    }
+
+    fn set(db: &ShardedDb, key: &str) {
+        let layout = db.layout();
+        let mut shards = layout.lock([key]);
+        shards.get(key).1.set(key.to_string(), "value".into(), None);
+    }
+
+    fn exists(db: &ShardedDb, key: &str) -> bool {
+        let layout = db.layout();
+        let mut shards = layout.lock([key]);
+        shards.get(key).1.get(key).unwrap().is_some()
+    }
+
+    #[test]
+    fn used_memory_is_counted_across_databases() {
+        let used = UsedMemory::default();
+        let dbs: Vec<_> = (0..2).map(|_| new_test_db(4, used.clone())).collect();
+        set(&dbs[0], "a");
+        let one = used.get();
+        assert!(one > 0);
+        set(&dbs[1], "a");
+        assert_eq!(used.get(), 2 * one);
+        dbs[0].for_each_shard(|_, shard| shard.clear());
+        let layout = dbs[1].layout();
+        let mut shards = layout.lock(["a"]);
+        assert!(shards.get("a").1.remove("a"));
+        assert_eq!(used.get(), 0);
+    }
+
+    #[test]
+    fn noeviction_refuses_writes_over_the_limit() {
+        let used = UsedMemory::default();
+        let dbs = [new_test_db(4, used.clone())];
+        set(&dbs[0], "a");
+        let policy = (MaxMemoryPolicy::NoEviction, 5);
+        let evicted = |_: usize, _: usize, key: &str| panic!("evicted {}", key);
+        assert!(make_room(&dbs, &used, used.get(), policy, evicted).is_ok());
+        assert!(matches!(
+            make_room(&dbs, &used, used.get() - 1, policy, evicted),
+            Err(CommandError::OutOfMemory)
+        ));
+        assert!(exists(&dbs[0], "a"));
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_key_at_the_limit() {
+        let used = UsedMemory::default();
+        let dbs = [new_test_db(1, used.clone())];
+        for key in ["a", "b", "c"] {
+            set(&dbs[0], key);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(exists(&dbs[0], "a"));
+        let mut evicted = vec![];
+        let policy = (MaxMemoryPolicy::AllKeysLru, 64);
+        make_room(&dbs, &used, used.get() - 1, policy, |db, shard, key| {
+            evicted.push((db, shard, key.to_string()))
+        })
+        .unwrap();
+        assert_eq!(evicted, [(0, 0, "b".to_string())]);
+        assert!(exists(&dbs[0], "a") && exists(&dbs[0], "c"));
+    }
+
+    fn remove(db: &ShardedDb, key: &str) {
+        let layout = db.layout();
+        let mut shards = layout.lock([key]);
+        assert!(shards.get(key).1.remove(key));
+    }
+
+    /// Scans `db` to the end, `count` at a time, calling `between` after
+    /// each step with the number of steps taken. Returns every key seen.
+    fn scan_all(db: &ShardedDb, count: usize, mut between: impl FnMut(usize)) -> HashSet<String> {
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        for step in 0.. {
+            cursor = db.scan(cursor, count, |key| {
+                seen.insert(key.to_string());
+            });
+            if cursor == 0 {
+                break;
+            }
+            between(step);
+        }
+        seen
+    }
+
+    #[test]
+    fn scan_sees_every_key_that_stays_despite_deletions() {
+        let db = new_db(4);
+        for i in 0..200 {
+            set(&db, &format!("key:{}", i));
+        }
+        let seen = scan_all(&db, 10, |step| {
+            // Each deletion moves the last key of its shard into the hole.
+            for i in (step * 10..step * 10 + 10).filter(|i| i % 2 == 1 && *i < 200) {
+                remove(&db, &format!("key:{}", i));
+            }
+            // Growing the shards never moves their keys.
+            for i in 0..20 {
+                set(&db, &format!("new:{}:{}", step, i));
+            }
+        });
+        for i in (0..200).step_by(2) {
+            assert!(seen.contains(&format!("key:{}", i)), "key:{}", i);
+        }
+    }
+
+    #[test]
+    fn scan_sees_every_key_across_a_resharding() {
+        // Whether the old table is dropped before the scan ends.
+        for (from, to, finish) in [(4, 7, false), (8, 2, false), (4, 7, true)] {
+            let db = new_db(from);
+            for i in 0..500 {
+                set(&db, &format!("key:{}", i));
+            }
+            let mut migrated = 0;
+            let seen = scan_all(&db, 10, |step| match step {
+                0..=2 => {}
+                3 => {
+                    db.switch_tables(to);
+                }
+                _ if migrated < from => migrated += db.migrate_batch(migrated) as usize,
+                // A cursor into the dropped table starts over.
+                _ if finish => db.inner.tables.write().unwrap().previous = None,
+                _ => {}
+            });
+            assert_eq!(seen.len(), 500, "{} to {} shards", from, to);
+        }
+    }
+}
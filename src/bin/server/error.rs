@@ -0,0 +1,216 @@
+use crate::frame::Frame;
+use crate::parse::ParseError;
+use std::fmt;
+
+/// Errors reported back to the client instead of closing the connection.
+///
+/// The `Display` output carries the Redis error prefix (`ERR`, `WRONGTYPE`),
+/// so it can be sent as a `Frame::Error` as is.
+#[derive(Debug)]
+pub enum CommandError {
+    /// The frame was not a well formed command.
+    Protocol(String),
+    UnknownCommand(String),
+    WrongArity(String),
+    Syntax,
+    NotInteger,
+    /// INCR and friends would leave the 64 bit signed range.
+    Overflow,
+    NotAllowedInSubscriberMode(String),
+    NotAllowedInTransaction,
+    ValueTooLarge,
+    NotFloat,
+    /// An argument out of range, with a message naming it.
+    Invalid(&'static str),
+    /// The key holds a different kind of value than the command works on.
+    WrongType,
+    /// A write sent to a replica, which only takes writes from its primary.
+    ReadOnly,
+    /// A command other than AUTH before the client logged in.
+    NoAuth,
+    WrongPass,
+    /// AUTH with only a password while the default user has none.
+    NoPasswordConfigured,
+    /// HELLO with a protocol version other than 2 or 3.
+    NoProto,
+    NoPermission {
+        user: String,
+        command: &'static str,
+    },
+    NoKeyPermission,
+    /// An ACL SETUSER rule that could not be applied, and why.
+    AclRule(String, &'static str),
+    /// A write that needs memory while the data set is at `maxmemory`.
+    OutOfMemory,
+    /// A write while the AOF cannot be written to, and why.
+    AofFailing(String),
+    ClusterDisabled,
+    /// The keys of a command are in different hash slots.
+    CrossSlot,
+    /// The slot of the keys is served by the node at `addr`.
+    Moved {
+        slot: u16,
+        addr: String,
+    },
+    /// No node serves the slot of the keys.
+    ClusterDown,
+    /// CLUSTER ADDSLOTS on a slot some node already serves.
+    SlotBusy(u16),
+}
+
+impl CommandError {
+    /// Maps a parse failure while reading the arguments of `command`.
+    pub fn from_parse(command: &str, err: ParseError) -> CommandError {
+        match err {
+            ParseError::EndOfStream | ParseError::ExtraArguments => {
+                CommandError::WrongArity(command.to_string())
+            }
+            ParseError::Syntax => CommandError::Syntax,
+            ParseError::NotInteger => CommandError::NotInteger,
+            ParseError::NotFloat => CommandError::NotFloat,
+            ParseError::Invalid(msg) => CommandError::Invalid(msg),
+            ParseError::Other(msg) => CommandError::Protocol(msg),
+        }
+    }
+
+    pub fn to_frame(&self) -> Frame {
+        Frame::Error(self.to_string())
+    }
+}
+
+impl From<ParseError> for CommandError {
+    fn from(err: ParseError) -> CommandError {
+        CommandError::Protocol(err.to_string())
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Protocol(msg) => write!(f, "ERR Protocol error: {}", msg),
+            CommandError::UnknownCommand(name) => write!(f, "ERR unknown command '{}'", name),
+            CommandError::WrongArity(name) => {
+                write!(f, "ERR wrong number of arguments for '{}' command", name)
+            }
+            CommandError::Syntax => "ERR syntax error".fmt(f),
+            CommandError::NotInteger => "ERR value is not an integer or out of range".fmt(f),
+            CommandError::NotFloat => "ERR value is not a valid float".fmt(f),
+            CommandError::Invalid(msg) => write!(f, "ERR {}", msg),
+            CommandError::Overflow => "ERR increment or decrement would overflow".fmt(f),
+            CommandError::ValueTooLarge => {
+                "ERR value is larger than the configured max-value-size".fmt(f)
+            }
+            CommandError::NotAllowedInSubscriberMode(name) => write!(
+                f,
+                "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context",
+                name
+            ),
+            CommandError::NotAllowedInTransaction => {
+                "ERR Command not allowed inside a transaction".fmt(f)
+            }
+            CommandError::WrongType => {
+                "WRONGTYPE Operation against a key holding the wrong kind of value".fmt(f)
+            }
+            CommandError::ReadOnly => {
+                "READONLY You can't write against a read only replica.".fmt(f)
+            }
+            CommandError::NoAuth => "NOAUTH Authentication required.".fmt(f),
+            CommandError::NoProto => "NOPROTO unsupported protocol version".fmt(f),
+            CommandError::WrongPass => {
+                "WRONGPASS invalid username-password pair or user is disabled.".fmt(f)
+            }
+            CommandError::NoPasswordConfigured => "ERR AUTH <password> called without any \
+                password configured for the default user. Are you sure your configuration is \
+                correct?"
+                .fmt(f),
+            CommandError::NoPermission { user, command } => write!(
+                f,
+                "NOPERM User {} has no permissions to run the '{}' command",
+                user, command
+            ),
+            CommandError::NoKeyPermission => "NOPERM No permissions to access a key".fmt(f),
+            CommandError::AclRule(rule, reason) => {
+                write!(f, "ERR Error in ACL SETUSER modifier '{}': {}", rule, reason)
+            }
+            CommandError::OutOfMemory => {
+                "OOM command not allowed when used memory > 'maxmemory'.".fmt(f)
+            }
+            CommandError::AofFailing(err) => {
+                write!(f, "MISCONF Errors writing to the AOF file: {}", err)
+            }
+            CommandError::ClusterDisabled => {
+                "ERR This instance has cluster support disabled".fmt(f)
+            }
+            CommandError::CrossSlot => {
+                "CROSSSLOT Keys in request don't hash to the same slot".fmt(f)
+            }
+            CommandError::Moved { slot, addr } => write!(f, "MOVED {} {}", slot, addr),
+            CommandError::ClusterDown => "CLUSTERDOWN Hash slot not served".fmt(f),
+            CommandError::SlotBusy(slot) => write!(f, "ERR Slot {} is already busy", slot),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_start_with_the_redis_prefix() {
+        use CommandError::*;
+        let errors = [
+            (Protocol("bad".to_string()), "ERR"),
+            (UnknownCommand("nosuch".to_string()), "ERR"),
+            (WrongArity("get".to_string()), "ERR"),
+            (Syntax, "ERR"),
+            (NotInteger, "ERR"),
+            (Overflow, "ERR"),
+            (NotAllowedInSubscriberMode("get".to_string()), "ERR"),
+            (NotAllowedInTransaction, "ERR"),
+            (ValueTooLarge, "ERR"),
+            (NotFloat, "ERR"),
+            (Invalid("timeout is negative"), "ERR"),
+            (WrongType, "WRONGTYPE"),
+            (ReadOnly, "READONLY"),
+            (NoAuth, "NOAUTH"),
+            (WrongPass, "WRONGPASS"),
+            (NoPasswordConfigured, "ERR"),
+            (NoProto, "NOPROTO"),
+            (
+                NoPermission {
+                    user: "alice".to_string(),
+                    command: "get",
+                },
+                "NOPERM",
+            ),
+            (NoKeyPermission, "NOPERM"),
+            (AclRule("+nosuch".to_string(), "Unknown command"), "ERR"),
+            (OutOfMemory, "OOM"),
+            (AofFailing("disk full".to_string()), "MISCONF"),
+            (ClusterDisabled, "ERR"),
+            (CrossSlot, "CROSSSLOT"),
+            (
+                Moved {
+                    slot: 3999,
+                    addr: "127.0.0.1:7001".to_string(),
+                },
+                "MOVED",
+            ),
+            (ClusterDown, "CLUSTERDOWN"),
+            (SlotBusy(7), "ERR"),
+        ];
+        for (err, prefix) in errors {
+            let Frame::Error(reply) = err.to_frame() else {
+                panic!("{:?} is not an error reply", err);
+            };
+            assert_eq!(reply.split(' ').next(), Some(prefix), "{:?}", err);
+        }
+        let moved = Moved {
+            slot: 3999,
+            addr: "127.0.0.1:7001".to_string(),
+        };
+        assert_eq!(moved.to_string(), "MOVED 3999 127.0.0.1:7001");
+    }
+}
@@ -0,0 +1,165 @@
+/// Matches `text` against a Redis style glob pattern.
+///
+/// Supports `*`, `?`, character classes such as `[abc]`, `[^a]` and `[a-z]`,
+/// and `\` to escape the next character.
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match.
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, text[t]) {
+                        if matched {
+                            p = next;
+                            t += 1;
+                            continue;
+                        }
+                    }
+                }
+                c => {
+                    let (literal, width) = if c == b'\\' && p + 1 < pattern.len() {
+                        (pattern[p + 1], 2)
+                    } else {
+                        (c, 1)
+                    };
+                    if literal == text[t] {
+                        p += width;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the class starting at `pattern[start] == b'['`.
+/// Returns whether it matched and the index just past the class, or `None`
+/// if the class is not terminated.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (lo, hi) = (
+                pattern[i].min(pattern[i + 2]),
+                pattern[i].max(pattern[i + 2]),
+            );
+            matched |= (lo..=hi).contains(&c);
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    if i >= pattern.len() {
+        return None;
+    }
+    Some((matched != negate, i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn star_matches_any_run_of_bytes() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("news.*", "news."));
+        assert!(matches("news.*", "news.sport"));
+        assert!(matches("*.sport", "news.sport"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(matches("**", "ab"));
+        assert!(!matches("news.*", "new"));
+        assert!(!matches("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_byte() {
+        assert!(matches("h?llo", "hello"));
+        assert!(matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(!matches("h?llo", "heello"));
+        assert!(!matches("?", ""));
+    }
+
+    #[test]
+    fn classes_match_listed_bytes_and_ranges() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("key[a-z]", "keyq"));
+        assert!(!matches("key[a-z]", "keyQ"));
+        // Ranges may be given backwards.
+        assert!(matches("key[z-a]", "keyq"));
+        // A `-` before the `]` is literal.
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[0-9]*", "7up"));
+    }
+
+    #[test]
+    fn negated_classes_match_other_bytes() {
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("[^a-c]", "d"));
+        assert!(!matches("[^a-c]", "b"));
+        // Still one byte, so never matches nothing.
+        assert!(!matches("x[^e]", "x"));
+    }
+
+    #[test]
+    fn backslash_escapes_the_next_byte() {
+        assert!(matches(r"a\*b", "a*b"));
+        assert!(!matches(r"a\*b", "aXb"));
+        assert!(matches(r"a\?", "a?"));
+        assert!(!matches(r"a\?", "ab"));
+        assert!(matches(r"\[a]", "[a]"));
+        assert!(matches(r"[\]]", "]"));
+        assert!(matches(r"[\-a]", "-"));
+        assert!(matches(r"a\\b", r"a\b"));
+    }
+
+    #[test]
+    fn unterminated_classes_match_nothing() {
+        assert!(!matches("[abc", "a"));
+        assert!(!matches("[abc", "[abc"));
+        assert!(!matches("x[", "x["));
+        assert!(!matches("[^", "a"));
+        assert!(!matches("*[abc", "x[abc"));
+        assert!(!matches("[", ""));
+    }
+}
@@ -0,0 +1,163 @@
+use tokio_official_tutorial_code_minis::config::ShardHash;
+use tokio_official_tutorial_code_minis::slot::key_slot;
+
+/// Maps keys to shards: the shard of a key is its hash modulo the number
+/// of shards.
+///
+/// Implementations must return the same hash for a key on every build and
+/// every run, so a shard layout can be written down and read back.
+pub trait ShardHasher: Send + Sync {
+    fn hash(&self, key: &[u8]) -> u64;
+}
+
+pub fn new_shard_hasher(kind: ShardHash) -> Box<dyn ShardHasher> {
+    match kind {
+        ShardHash::SipHash => Box::new(SipHash::default()),
+        ShardHash::Fnv => Box::new(Fnv),
+        ShardHash::Crc16 => Box::new(KeySlot),
+    }
+}
+
+/// SipHash-2-4, the same function as the standard library's default
+/// hasher was when it was written, but with a key fixed here rather than
+/// left to the standard library.
+pub struct SipHash {
+    k0: u64,
+    k1: u64,
+}
+
+impl SipHash {
+    pub fn with_key(key: [u8; 16]) -> SipHash {
+        SipHash {
+            k0: u64::from_le_bytes(key[..8].try_into().unwrap()),
+            k1: u64::from_le_bytes(key[8..].try_into().unwrap()),
+        }
+    }
+}
+
+impl Default for SipHash {
+    fn default() -> SipHash {
+        SipHash::with_key(*b"mini-redis shard")
+    }
+}
+
+impl ShardHasher for SipHash {
+    fn hash(&self, key: &[u8]) -> u64 {
+        let mut v = [
+            self.k0 ^ 0x736f6d6570736575,
+            self.k1 ^ 0x646f72616e646f6d,
+            self.k0 ^ 0x6c7967656e657261,
+            self.k1 ^ 0x7465646279746573,
+        ];
+        let mut words = key.chunks_exact(8);
+        for word in &mut words {
+            let m = u64::from_le_bytes(word.try_into().unwrap());
+            v[3] ^= m;
+            sip_rounds(&mut v, 2);
+            v[0] ^= m;
+        }
+        // The last word holds the remaining bytes and the length of the key.
+        let mut last = [0; 8];
+        let rest = words.remainder();
+        last[..rest.len()].copy_from_slice(rest);
+        let m = u64::from_le_bytes(last) | (key.len() as u64) << 56;
+        v[3] ^= m;
+        sip_rounds(&mut v, 2);
+        v[0] ^= m;
+        v[2] ^= 0xff;
+        sip_rounds(&mut v, 4);
+        v[0] ^ v[1] ^ v[2] ^ v[3]
+    }
+}
+
+fn sip_rounds(v: &mut [u64; 4], rounds: usize) {
+    for _ in 0..rounds {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+}
+
+/// 64-bit FNV-1a. Much cheaper than SipHash on short keys, but a client
+/// choosing its keys can pile them onto one shard.
+pub struct Fnv;
+
+impl ShardHasher for Fnv {
+    fn hash(&self, key: &[u8]) -> u64 {
+        key.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+}
+
+/// The key slot of Redis Cluster, from `slot::key_slot`.
+///
+/// Keys with the same `{hashtag}` get the same slot, so a client can keep
+/// the keys of one MSET or transaction on one shard. Shard counts that
+/// divide 16384 spread the slots evenly.
+pub struct KeySlot;
+
+impl ShardHasher for KeySlot {
+    fn hash(&self, key: &[u8]) -> u64 {
+        key_slot(key) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::new_sharded_db;
+
+    /// The shard `key` lands in, in a database of `shards` shards.
+    fn shard_of(kind: ShardHash, shards: usize, key: &str) -> usize {
+        let db = new_sharded_db(shards, new_shard_hasher(kind), Default::default());
+        let layout = db.layout();
+        let index = layout.lock([key]).get(key).0;
+        index
+    }
+
+    #[test]
+    fn siphash_matches_reference_vectors() {
+        let mut key = [0; 16];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let hasher = SipHash::with_key(key);
+        let message: Vec<u8> = (0..15).collect();
+        assert_eq!(hasher.hash(b""), 0x726fdb47dd0e0e31);
+        assert_eq!(hasher.hash(&message[..1]), 0x74f839c593dc67fd);
+        assert_eq!(hasher.hash(&message[..8]), 0x93f5f5799a932462);
+        assert_eq!(hasher.hash(&message), 0xa129ca6149be45e5);
+    }
+
+    #[test]
+    fn fnv_matches_reference_vectors() {
+        assert_eq!(Fnv.hash(b""), 0xcbf29ce484222325);
+        assert_eq!(Fnv.hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(Fnv.hash(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn shards_of_keys_are_pinned() {
+        let keys = ["foo", "bar", "user:1000", "{user1000}.following"];
+        let pinned = [
+            (ShardHash::SipHash, 1000, [863, 701, 240, 863]),
+            (ShardHash::SipHash, 16, [7, 5, 0, 7]),
+            (ShardHash::Fnv, 1000, [407, 746, 681, 630]),
+            (ShardHash::Fnv, 16, [7, 10, 9, 6]),
+            (ShardHash::Crc16, 1000, [182, 61, 649, 443]),
+            (ShardHash::Crc16, 16, [6, 5, 1, 3]),
+        ];
+        for (kind, shards, expected) in pinned {
+            let actual = keys.map(|key| shard_of(kind, shards, key));
+            assert_eq!(actual, expected, "{:?} with {} shards", kind, shards);
+        }
+    }
+}
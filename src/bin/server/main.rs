@@ -0,0 +1,2003 @@
+/*
+File reorganized here to make it easier to run
+cargo run --bin server
+*/
+
+mod acl;
+mod aof;
+mod blocking;
+mod cluster;
+mod cmd;
+mod connection;
+mod db;
+mod error;
+mod glob;
+mod hasher;
+mod metrics;
+mod module;
+mod multi;
+mod parse;
+mod pubsub;
+mod replication;
+mod shutdown;
+mod snapshot;
+mod value;
+
+use acl::Acl;
+use aof::Aof;
+use blocking::Blocking;
+use bytes::Bytes;
+use cluster::Cluster;
+use cmd::{AclCommand, Command, ReplConf};
+use connection::Connection;
+use db::{
+    new_sharded_db, Entry, LayoutStats, LockedShards, Paused, Shard, ShardedDb, Ttl, UsedMemory,
+};
+use error::CommandError;
+use glob::glob_match;
+use metrics::Metrics;
+use module::CommandTable;
+use multi::{Held, Lock, Transaction};
+use pubsub::{PubSub, SessionEnd, Subscriptions};
+use replication::{PrimaryLink, Replication};
+use shutdown::Shutdown;
+use snapshot::Snapshotter;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio_official_tutorial_code_minis::codec::{self, RespCodec};
+use tokio_official_tutorial_code_minis::config::{self, Config};
+use tokio_official_tutorial_code_minis::frame::{self, Frame, Protocol};
+use tracing::{debug, error, info, warn};
+use value::{index_range, Value, ZSet};
+
+/// Handles shared by every connection.
+#[derive(Clone)]
+struct Shared {
+    config: Arc<Config>,
+    /// The `databases` logical databases clients SELECT from.
+    dbs: Arc<[ShardedDb]>,
+    snapshotter: Snapshotter,
+    /// `None` unless `appendonly` is set.
+    aof: Option<Aof>,
+    pubsub: PubSub,
+    /// Clients blocked in BLPOP and BRPOP.
+    blocking: Blocking,
+    metrics: Metrics,
+    replication: Replication,
+    /// `None` unless `cluster-enabled` is set.
+    cluster: Option<Cluster>,
+    acl: Acl,
+    /// Bytes held by the keys and values of every database.
+    used_memory: UsedMemory,
+    /// The registered `CommandHandler`s, every command parsed looks up.
+    commands: Arc<CommandTable>,
+}
+
+impl Shared {
+    /// The state of a server run with `config`, with empty databases and
+    /// no AOF yet.
+    fn new(config: Config) -> Shared {
+        let commands = CommandTable::new();
+        let used_memory = UsedMemory::default();
+        Shared {
+            dbs: (0..config.databases)
+                .map(|_| {
+                    let hasher = hasher::new_shard_hasher(config.shard_hash);
+                    new_sharded_db(config.shards, hasher, used_memory.clone())
+                })
+                .collect(),
+            snapshotter: Snapshotter::new(config.snapshot_path()),
+            aof: None,
+            pubsub: PubSub::new(config.pubsub_channel_capacity, config.pubsub_lag_policy),
+            blocking: Blocking::default(),
+            metrics: Metrics::new(commands.names()),
+            replication: Replication::new(config.repl_backlog_size),
+            cluster: config.cluster_enabled.then(|| {
+                // Other nodes need an address they can reach; a wildcard bind
+                // is announced as localhost.
+                let host = match config.bind {
+                    bind if bind.is_unspecified() => "127.0.0.1".to_string(),
+                    bind => bind.to_string(),
+                };
+                Cluster::new(host, config.port, config.cluster_bus_port())
+            }),
+            acl: Acl::new(config.requirepass.as_deref(), commands.names()),
+            used_memory,
+            commands: Arc::new(commands),
+            config: Arc::new(config),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let config = Config::load_or_exit(Config::default());
+    tracing_subscriber::fmt()
+        .with_max_level(config.loglevel)
+        .init();
+
+    let listener = match TcpListener::bind(config.addr()).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to listen on {}: {}", config.addr(), err);
+            std::process::exit(1);
+        }
+    };
+    info!("Listening on {}", config.addr());
+    let mut shared = Shared::new(config);
+    shared.aof = match open_aof(&shared).await {
+        Ok(aof) => aof,
+        Err(err) => {
+            error!("Failed to load data: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some((host, port)) = shared.config.replicaof.clone() {
+        replicate(&shared, host, port);
+    }
+    for db in shared.dbs.iter() {
+        tokio::spawn(db::sweep_expired(db.clone(), Duration::from_millis(100)));
+    }
+    tokio::spawn(snapshot::save_periodically(
+        shared.snapshotter.clone(),
+        shared.dbs.to_vec(),
+        Duration::from_secs(60),
+        1,
+    ));
+    if let Some(aof) = &shared.aof {
+        tokio::spawn(aof::flush_every_second(aof.clone()));
+    }
+
+    tokio::spawn(metrics::sample_ops(shared.metrics.clone()));
+    if shared.config.metrics_port != 0 {
+        let addr = (shared.config.bind, shared.config.metrics_port);
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                tokio::spawn(metrics::serve_http(listener, shared.metrics.clone()));
+            }
+            Err(err) => {
+                error!("Failed to listen on {:?} for metrics: {}", addr, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(cluster) = &shared.cluster {
+        let addr = (shared.config.bind, shared.config.cluster_bus_port());
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                tokio::spawn(cluster::serve_bus(listener, cluster.clone()));
+            }
+            Err(err) => {
+                error!(
+                    "Failed to listen on {:?} for the cluster bus: {}",
+                    addr, err
+                );
+                std::process::exit(1);
+            }
+        }
+        if let Err(err) = cluster.add_slots(&shared.config.cluster_slots) {
+            error!("Failed to claim the configured slots: {}", err);
+            std::process::exit(1);
+        }
+        for (host, port) in &shared.config.cluster_meet {
+            let bus_port = config::default_bus_port(*port).expect("port checked by Config::load");
+            cluster.meet(host.clone(), bus_port);
+        }
+        tokio::spawn(cluster::gossip(cluster.clone()));
+    }
+
+    let (notify_shutdown, _) = watch::channel(false);
+    // Every connection task holds a clone of `shutdown_complete`; once all of
+    // them are dropped, `recv` on the other end returns `None`.
+    let (shutdown_complete, mut all_closed) = mpsc::channel::<()>(1);
+
+    // One permit per open connection, held until the connection closes.
+    let clients = Arc::new(Semaphore::new(shared.config.maxclients));
+
+    tokio::select! {
+        _ = async {
+            loop {
+                let socket = accept(&listener).await;
+                let permit = match clients.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        shared.metrics.client_rejected();
+                        tokio::spawn(reject(socket));
+                        continue;
+                    }
+                };
+                let shared = shared.clone();
+                let shutdown = Shutdown::new(notify_shutdown.subscribe());
+                let done = shutdown_complete.clone();
+                debug!("Accepted");
+                tokio::spawn(async move {
+                    process(socket, shared, shutdown).await;
+                    drop(permit);
+                    drop(done);
+                });
+            }
+        } => {}
+        _ = shutdown::signal() => {
+            info!("Shutting down");
+        }
+    }
+
+    // Stop accepting, tell every connection to finish the command it is
+    // running, then give them a bounded amount of time to do so.
+    drop(listener);
+    let _ = notify_shutdown.send(true);
+    drop(shutdown_complete);
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, all_closed.recv())
+        .await
+        .is_err()
+    {
+        warn!("Timed out waiting for connections to close");
+    }
+
+    if let Some(aof) = &shared.aof {
+        if let Err(err) = aof.sync().await {
+            error!("Failed to flush the AOF: {}", err);
+        }
+    }
+    match shared.snapshotter.save(&shared.dbs).await {
+        Ok(()) => info!("DB saved on disk"),
+        Err(err) => error!("Failed to save the snapshot: {}", err),
+    }
+}
+
+/// How long connections get to finish their current command on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest pause between two failed `accept` calls.
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Accepts the next connection, retrying with exponential backoff on errors.
+///
+/// Errors such as EMFILE (out of file descriptors) are usually transient:
+/// they go away once other connections close, so the server waits instead of
+/// exiting.
+async fn accept(listener: &TcpListener) -> TcpStream {
+    let mut backoff = Duration::from_millis(5);
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                // Replies are already batched per pipeline, so waiting for
+                // more data to coalesce, as Nagle's algorithm does, only adds
+                // a delayed ACK round trip whenever a batch needs two writes.
+                if let Err(err) = socket.set_nodelay(true) {
+                    debug!("Failed to set TCP_NODELAY: {}", err);
+                }
+                return socket;
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to accept a connection, retrying in {:?}: {}",
+                    backoff, err
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Tells a client over the `maxclients` limit why it is being dropped.
+async fn reject(socket: TcpStream) {
+    debug!("Rejected {:?}: too many clients", socket.peer_addr().ok());
+    let mut connection = Connection::new(socket, RespCodec::default());
+    let reply = Frame::Error("ERR max number of clients reached".to_string());
+    let _ = connection.write_frame(&reply).await;
+}
+
+/// Loads the data set, from the AOF when `appendonly` is set and from the
+/// snapshot otherwise, and opens the AOF for writing if enabled.
+///
+/// `shared.aof` must still be `None` so replayed commands are not appended
+/// again.
+async fn open_aof(shared: &Shared) -> std::io::Result<Option<Aof>> {
+    let enabled = shared.config.appendonly;
+    let path = shared.config.aof_path();
+    let existing = path.exists();
+    if !enabled || !existing {
+        let keys = shared.snapshotter.load(&shared.dbs).await?;
+        info!("Loaded {} keys from snapshot", keys);
+    }
+    if !enabled {
+        return Ok(None);
+    }
+
+    let commands = aof::load(&path, &shared.commands).await?;
+    info!("Replaying {} commands from AOF", commands.len());
+    let mut selected = 0;
+    for cmd in commands {
+        match cmd {
+            Command::Select { db } => {
+                selected = db_index(shared, db).map_err(|err| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+                })?;
+            }
+            cmd => {
+                execute(cmd, shared, selected).await;
+            }
+        }
+    }
+    let aof = Aof::open(path, shared.config.appendfsync).await?;
+    if !existing {
+        // Seed the new file with whatever the snapshot held.
+        aof.bgrewrite(&shared.dbs);
+    }
+    Ok(Some(aof))
+}
+
+/// Makes this server a replica of the primary at `host` and `port`.
+/// Returns `false` if it already is one.
+fn replicate(shared: &Shared, host: String, port: u16) -> bool {
+    let task = tokio::spawn(follow_primary(shared.clone(), host.clone(), port));
+    shared.replication.follow(host, port, task)
+}
+
+/// How long a replica waits before reconnecting to its primary.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Copies the data set of the primary, then applies every write it
+/// streams, reconnecting whenever the link drops, until REPLICAOF aborts
+/// the task.
+async fn follow_primary(shared: Shared, host: String, port: u16) {
+    // The database the stream last selected. A partial resync carries on
+    // from where the stream was cut, so it is kept across reconnects.
+    let mut selected = 0;
+    loop {
+        if let Err(err) = sync_with_primary(&shared, &host, port, &mut selected).await {
+            warn!("Replication link to {}:{} failed: {}", host, port, err);
+        }
+        shared.replication.link_down();
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn sync_with_primary(
+    shared: &Shared,
+    host: &str,
+    port: u16,
+    selected: &mut usize,
+) -> mini_redis::Result<()> {
+    let resume = shared.replication.resume_point();
+    let config = &shared.config;
+    let auth = (config.masterauth.as_deref()).map(|password| (&config.masteruser[..], password));
+    let (mut link, copy) = PrimaryLink::connect(host, port, config.port, auth, resume).await?;
+    if let Some(data) = copy {
+        for db in shared.dbs.iter() {
+            db.clear();
+        }
+        let keys = snapshot::decode(&data, &shared.dbs)?;
+        *selected = 0;
+        shared.snapshotter.mark_dirty();
+        info!("Loaded {} keys from the primary", keys);
+        if let Some(aof) = &shared.aof {
+            // The file still holds the replaced data set.
+            if !aof.bgrewrite(&shared.dbs) {
+                warn!("AOF rewrite already running, the AOF may miss the primary's data");
+            }
+        }
+    }
+    shared
+        .replication
+        .link_up(link.replid().to_string(), link.offset());
+    info!(
+        "Replicating {}:{} from offset {}",
+        host,
+        port,
+        link.offset()
+    );
+
+    while let Some(cmd) = link.next_command(&shared.commands).await? {
+        if cmd.is_write() {
+            shared.snapshotter.mark_dirty();
+        }
+        match cmd {
+            Command::Select { db } => *selected = db_index(shared, db)?,
+            cmd => {
+                execute(cmd, shared, *selected).await;
+            }
+        }
+        shared.replication.applied(link.offset());
+        if let (Some(aof), false) = (&shared.aof, link.has_buffered_command()) {
+            aof.flush().await?;
+        }
+    }
+    Err("connection closed by the primary".into())
+}
+
+async fn process(socket: TcpStream, shared: Shared, mut shutdown: Shutdown) {
+    let peer = socket.peer_addr().ok();
+    let codec = RespCodec::new(
+        shared.config.proto_max_bulk_len,
+        shared.config.proto_max_depth,
+    );
+    let mut connection = Connection::new(socket, codec);
+    let _client = shared.metrics.client_connected();
+    // A command sent while the client was blocked in BLPOP or BRPOP.
+    let mut stashed = None;
+    let mut transaction = Transaction::new(shared.dbs.clone());
+    let mut selected = 0;
+    let mut subscriptions = Subscriptions::new(&shared.pubsub);
+    // Announced by replicas with REPLCONF before they send PSYNC.
+    let mut replica_port = None;
+    // `None` until the client logs in, if it has to.
+    let mut user = shared.acl.initial_user();
+    // Whether replies to writes were queued since the AOF was last flushed.
+    let mut unflushed = false;
+    loop {
+        // Replies are queued, and only sent once every command the client
+        // pipelined has run, so a batch of commands costs one flush.
+        if stashed.is_none() && !connection.has_buffered_frame() {
+            if !flush_aof(&shared, &mut unflushed).await {
+                return;
+            }
+            if let Err(err) = connection.flush().await {
+                debug!("{:?}: write failed: {}", peer, err);
+                return;
+            }
+        }
+        // Shutdown is only checked between commands, so one that has been
+        // read always runs to completion and gets its reply.
+        let frame = match stashed.take() {
+            Some(frame) => Ok(Some(frame)),
+            None => tokio::select! {
+                frame = connection.read_frame() => frame,
+                // Only RESP3 connections get here while subscribed.
+                message = subscriptions.next_message(), if subscriptions.count() > 0 => {
+                    let result = match message {
+                        Ok(message) => connection.queue_frame(&message).await,
+                        Err(reply) => {
+                            let _ = connection.write_frame(&reply).await;
+                            return;
+                        }
+                    };
+                    if let Err(err) = result {
+                        debug!("{:?}: write failed: {}", peer, err);
+                        return;
+                    }
+                    continue;
+                }
+                _ = shutdown.recv() => {
+                    let _ = connection.flush().await;
+                    return;
+                }
+            },
+        };
+        let frame = match frame {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return,
+            Err(err) => {
+                // The read buffer now holds bytes that are not valid RESP, so
+                // nothing after them can be framed reliably.
+                warn!("{:?}: closing connection: {}", peer, err);
+                let reply = Frame::Error(format!("ERR Protocol error: {}", err));
+                let _ = connection.write_frame(&reply).await;
+                return;
+            }
+        };
+        shared.metrics.record_in(&frame, connection.protocol());
+        let cmd = Command::from_frame_named(frame, &shared.commands).and_then(|(name, cmd)| {
+            match &user {
+                _ if matches!(cmd, Command::Auth { .. } | Command::Hello { .. }) => {}
+                Some(user) => shared.acl.check(user, &name, &cmd)?,
+                None => return Err(CommandError::NoAuth),
+            }
+            // A replica only takes writes from its primary, which
+            // `follow_primary` applies without coming through here.
+            if cmd.is_write() && shared.replication.is_replica() {
+                return Err(CommandError::ReadOnly);
+            }
+            if let (true, Some(aof)) = (cmd.is_write(), &shared.aof) {
+                aof.check_writable()?;
+            }
+            // In a cluster, keys served by other nodes are redirected there,
+            // and only database 0 exists.
+            if let Some(cluster) = &shared.cluster {
+                cluster.check(&cmd.keys())?;
+                match cmd {
+                    Command::Select { db: 1.. } => {
+                        return Err(CommandError::Invalid(
+                            "SELECT is not allowed in cluster mode",
+                        ))
+                    }
+                    Command::Move { .. } => {
+                        return Err(CommandError::Invalid("MOVE is not allowed in cluster mode"))
+                    }
+                    Command::SwapDb { .. } => {
+                        return Err(CommandError::Invalid(
+                            "SWAPDB is not allowed in cluster mode",
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+            if cmd.uses_memory() {
+                make_room(&shared)?;
+            }
+            Ok(cmd)
+        });
+        let response = match cmd {
+            cmd if transaction.is_queuing()
+                && !matches!(cmd, Ok(Command::Exec | Command::Discard)) =>
+            {
+                transaction.queue(cmd)
+            }
+            Ok(Command::Psync { replid, offset }) => {
+                if !flush_aof(&shared, &mut unflushed).await {
+                    return;
+                }
+                // The connection carries the replication stream from now on.
+                if let Err(err) = replication::serve_replica(
+                    &mut connection,
+                    (&shared.replication, &shared.commands),
+                    &shared.dbs,
+                    &mut shutdown,
+                    peer,
+                    replica_port,
+                    (replid, offset),
+                )
+                .await
+                {
+                    debug!("{:?}: closing replica connection: {}", peer, err);
+                }
+                return;
+            }
+            Ok(Command::Auth { username, password }) => {
+                match shared.acl.authenticate(username.as_deref(), &password) {
+                    Ok(name) => {
+                        user = Some(name);
+                        Frame::Simple("OK".to_string())
+                    }
+                    Err(err) => err.to_frame(),
+                }
+            }
+            Ok(Command::Hello { protover, auth }) => {
+                match hello(&shared, &mut user, protover, auth) {
+                    Ok((protocol, reply)) => {
+                        connection.set_protocol(protocol);
+                        reply
+                    }
+                    Err(err) => err.to_frame(),
+                }
+            }
+            Ok(Command::Select { db }) => match db_index(&shared, db) {
+                Ok(db) => {
+                    selected = db;
+                    Frame::Simple("OK".to_string())
+                }
+                Err(err) => err.to_frame(),
+            },
+            Ok(Command::Acl(AclCommand::WhoAmI)) => {
+                Frame::Bulk(user.clone().unwrap_or_default().into())
+            }
+            Ok(Command::ReplConf(conf)) => {
+                if let ReplConf::ListeningPort(port) = conf {
+                    replica_port = Some(port);
+                }
+                Frame::Simple("OK".to_string())
+            }
+            Ok(
+                cmd @ (Command::Subscribe { .. }
+                | Command::PSubscribe { .. }
+                | Command::Unsubscribe { .. }
+                | Command::PUnsubscribe { .. }),
+            ) if connection.protocol() == Protocol::Resp3 => {
+                for ack in subscriptions.apply(cmd) {
+                    if let Err(err) = connection.queue_frame(&ack).await {
+                        debug!("{:?}: write failed: {}", peer, err);
+                        return;
+                    }
+                }
+                continue;
+            }
+            Ok(cmd @ (Command::Subscribe { .. } | Command::PSubscribe { .. })) => {
+                if !flush_aof(&shared, &mut unflushed).await {
+                    return;
+                }
+                match pubsub::subscriber_session(
+                    &mut connection,
+                    &mut subscriptions,
+                    (&shared.commands, &shared.acl),
+                    user.as_deref(),
+                    &mut shutdown,
+                    cmd,
+                )
+                .await
+                {
+                    Ok(SessionEnd::Unsubscribed) => continue,
+                    Ok(SessionEnd::Closed) => return,
+                    Err(err) => {
+                        debug!("{:?}: closing subscriber connection: {}", peer, err);
+                        return;
+                    }
+                }
+            }
+            Ok(cmd) => {
+                let mut write = cmd.is_write();
+                if write {
+                    shared.snapshotter.mark_dirty();
+                }
+                let name = cmd.name();
+                let started = Instant::now();
+                let response = match cmd {
+                    Command::BPop {
+                        keys,
+                        front,
+                        timeout,
+                    } => {
+                        // Earlier pipelined commands get their replies
+                        // before this one blocks.
+                        if !flush_aof(&shared, &mut unflushed).await {
+                            return;
+                        }
+                        if let Err(err) = connection.flush().await {
+                            debug!("{:?}: write failed: {}", peer, err);
+                            return;
+                        }
+                        let pop = blocking_pop(keys, front, timeout, &shared, selected);
+                        tokio::pin!(pop);
+                        // Keep reading while blocked, only to notice the
+                        // client going away; dropping `pop` deregisters it.
+                        loop {
+                            tokio::select! {
+                                response = &mut pop => break response,
+                                frame = connection.read_frame(), if stashed.is_none() => {
+                                    match frame {
+                                        Ok(Some(frame)) => stashed = Some(frame),
+                                        Ok(None) | Err(_) => return,
+                                    }
+                                }
+                                _ = shutdown.recv() => return,
+                            }
+                        }
+                    }
+                    Command::Multi => transaction.multi(selected),
+                    Command::Watch { keys } => transaction.watch(selected, keys),
+                    Command::Unwatch => transaction.unwatch(),
+                    Command::Discard => transaction.discard(),
+                    Command::Exec => {
+                        let exec = transaction.exec(|db, cmd, held| {
+                            if cmd.is_write() {
+                                write = true;
+                                shared.snapshotter.mark_dirty();
+                            }
+                            let name = cmd.name();
+                            let started = Instant::now();
+                            let response = exec_queued(cmd, &shared, db, held, &mut selected);
+                            shared
+                                .metrics
+                                .record_command(name, started.elapsed(), &response);
+                            response
+                        });
+                        exec.await
+                    }
+                    cmd => execute(cmd, &shared, selected).await,
+                };
+                shared
+                    .metrics
+                    .record_command(name, started.elapsed(), &response);
+                unflushed |= write;
+                response
+            }
+            Err(err) => err.to_frame(),
+        };
+        shared.metrics.record_out(&response, connection.protocol());
+        if connection.queue_is_full() && !flush_aof(&shared, &mut unflushed).await {
+            return;
+        }
+        if let Err(err) = connection.queue_frame(&response).await {
+            debug!("{:?}: write failed: {}", peer, err);
+            return;
+        }
+    }
+}
+
+/// Writes the AOF buffer to the file before the replies to `unflushed`
+/// writes are sent, so no client hears of a write a crash would lose.
+///
+/// Returns false if that failed: the connection is then closed without
+/// the replies, and the failure refuses writes until a flush succeeds.
+async fn flush_aof(shared: &Shared, unflushed: &mut bool) -> bool {
+    let Some(aof) = &shared.aof else {
+        return true;
+    };
+    if !std::mem::take(unflushed) {
+        return true;
+    }
+    match aof.flush().await {
+        Ok(()) => true,
+        Err(err) => {
+            error!("AOF write error: {}", err);
+            false
+        }
+    }
+}
+
+/// Serves HELLO: logs the client in if `auth` is given, then picks the
+/// protocol, RESP2 or RESP3, to reply in from now on.
+fn hello(
+    shared: &Shared,
+    user: &mut Option<String>,
+    protover: Option<u64>,
+    auth: Option<(String, String)>,
+) -> Result<(Protocol, Frame), CommandError> {
+    let protocol = match protover {
+        None | Some(2) => Protocol::Resp2,
+        Some(3) => Protocol::Resp3,
+        Some(_) => return Err(CommandError::NoProto),
+    };
+    if let Some((username, password)) = auth {
+        *user = Some(shared.acl.authenticate(Some(&username), &password)?);
+    }
+    if user.is_none() {
+        return Err(CommandError::NoAuth);
+    }
+    let bulk = |s: &str| Frame::Bulk(s.to_string().into());
+    let mode = if shared.cluster.is_some() {
+        "cluster"
+    } else {
+        "standalone"
+    };
+    let role = if shared.replication.is_replica() {
+        "replica"
+    } else {
+        "master"
+    };
+    let reply = Frame::Map(vec![
+        (bulk("server"), bulk("mini-redis")),
+        (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+        (bulk("proto"), Frame::Integer(protocol.version() as i64)),
+        (bulk("mode"), bulk(mode)),
+        (bulk("role"), bulk(role)),
+        (bulk("modules"), Frame::Array(Vec::new())),
+    ]);
+    Ok((protocol, reply))
+}
+
+/// Serves BLPOP and BRPOP in database `db`: pops right away if one of the
+/// lists has an element, and otherwise waits for a push to one of them to
+/// hand it an element, until the timeout elapses.
+async fn blocking_pop(
+    keys: Vec<String>,
+    front: bool,
+    timeout: Option<Duration>,
+    shared: &Shared,
+    db: usize,
+) -> Frame {
+    // A deadline too far off for an `Instant` is never reached.
+    let deadline = timeout.and_then(|timeout| tokio::time::Instant::now().checked_add(timeout));
+    let watch = {
+        let layout = shared.dbs[db].layout();
+        let mut shards = layout.lock(&keys);
+        let cmd = Command::BPop {
+            keys: keys.clone(),
+            front,
+            timeout,
+        };
+        match apply(cmd, shared, db, &mut shards) {
+            // Queued before the shards are unlocked, so the next push to
+            // any of the lists is handed over.
+            Frame::Null => shared.blocking.watch(db, &keys, front),
+            response => return response,
+        }
+    };
+    let (key, value) = match deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, watch.popped()).await {
+            Ok(popped) => popped,
+            // Unless an element was handed over as the timeout elapsed.
+            Err(_) => match watch.cancel() {
+                Some(popped) => popped,
+                None => return Frame::Null,
+            },
+        },
+        None => watch.popped().await,
+    };
+    Frame::Array(vec![Frame::Bulk(key.into()), Frame::Bulk(value)])
+}
+
+/// Hands elements of the list at `key`, in shard `shard` of database `db`,
+/// to the clients blocked on it, feeding each pop as if they had run it.
+fn serve_blocked(
+    shared: &Shared,
+    log: Option<&WriteLog>,
+    (db, shard): (usize, usize),
+    db_shard: &mut Shard,
+    key: &str,
+) {
+    shared.blocking.serve(db, key, |front| {
+        let value = db_shard.pop(key, 1, front).ok()?.pop()?;
+        feed_pop(log, shard, key, 1, front);
+        Some(value)
+    });
+}
+
+/// Runs `cmd` against database `db`.
+async fn execute(cmd: Command, shared: &Shared, db: usize) -> Frame {
+    // These lock shards themselves, so they cannot run under a layout.
+    match cmd {
+        Command::Keys { pattern } => {
+            let db = &shared.dbs[db];
+            keys_reply(&pattern, |cursor, count, f| db.scan(cursor, count, f))
+        }
+        Command::Scan {
+            cursor,
+            pattern,
+            count,
+        } => scan_reply(pattern.as_deref(), |f| {
+            shared.dbs[db].scan(cursor, count, f)
+        }),
+        Command::DbSize => Frame::Integer(shared.dbs[db].len() as i64),
+        Command::RandomKey => bulk_or_null(shared.dbs[db].random_key().map(Bytes::from)),
+        Command::FlushDb => flush_db(shared, db, &shared.dbs[db].pause()),
+        Command::FlushAll => {
+            let paused: Vec<_> = shared.dbs.iter().map(ShardedDb::pause).collect();
+            for paused in &paused {
+                paused.clear();
+            }
+            flushed_all(shared)
+        }
+        Command::Move { key, db: to } => {
+            move_key(shared, key, db, to).unwrap_or_else(|err| err.to_frame())
+        }
+        Command::SwapDb { first, second } => match swap_dbs(shared, first, second).await {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => err.to_frame(),
+        },
+        Command::Save => match shared.snapshotter.save(&shared.dbs).await {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(format!("ERR {}", err)),
+        },
+        Command::Info { section } => Frame::text(info(section.as_deref(), shared)),
+        Command::Module { handler, args, .. } => {
+            let result = handler
+                .execute(module::Db::new(shared, db, None), args)
+                .await;
+            result.unwrap_or_else(|err| err.to_frame())
+        }
+        cmd => {
+            let layout = shared.dbs[db].layout();
+            let mut shards = layout.lock(cmd.keys());
+            apply(cmd, shared, db, &mut shards)
+        }
+    }
+}
+
+/// Checks a database index given by a client.
+fn db_index(shared: &Shared, db: usize) -> Result<usize, CommandError> {
+    if db < shared.dbs.len() {
+        Ok(db)
+    } else {
+        Err(CommandError::Invalid("DB index is out of range"))
+    }
+}
+
+/// Moves `key` from database `from` to database `to`, unless `to` already
+/// has such a key. Replies with whether it was moved.
+fn move_key(shared: &Shared, key: String, from: usize, to: usize) -> Result<Frame, CommandError> {
+    let to = move_target(shared, from, to)?;
+    // Both layouts are taken before either shard is locked, in database
+    // order, as EXEC does.
+    let first = shared.dbs[from.min(to)].layout();
+    let second = shared.dbs[from.max(to)].layout();
+    let (mut first, mut second) = (first.lock([&key]), second.lock([&key]));
+    let (source, target) = if from < to {
+        (&mut first, &mut second)
+    } else {
+        (&mut second, &mut first)
+    };
+    Ok(move_locked(shared, &key, (from, source), (to, target)))
+}
+
+/// Checks the database MOVE is given, when run against database `from`.
+fn move_target(shared: &Shared, from: usize, to: usize) -> Result<usize, CommandError> {
+    let to = db_index(shared, to)?;
+    if from == to {
+        return Err(CommandError::Invalid(
+            "source and destination objects are the same",
+        ));
+    }
+    Ok(to)
+}
+
+/// MOVE, with the shards of `key` in both databases locked.
+fn move_locked(
+    shared: &Shared,
+    key: &str,
+    (from, source): (usize, &mut LockedShards<'_>),
+    (to, target): (usize, &mut LockedShards<'_>),
+) -> Frame {
+    let (to_shard, target_shard) = target.get(key);
+    if target_shard.exists(key) {
+        return Frame::Integer(0);
+    }
+    let (from_shard, source_shard) = source.get(key);
+    let entry = match source_shard.remove_live(key) {
+        Some(entry) => entry,
+        None => return Frame::Integer(0),
+    };
+    source_shard.touch(key);
+    if let Some(log) = WriteLog::new(shared, from) {
+        log.feed_move(from_shard, key, to, to_shard, &entry);
+    }
+    target_shard.insert_entry(key.to_string(), entry);
+    let log = WriteLog::new(shared, to);
+    serve_blocked(shared, log.as_ref(), (to, to_shard), target_shard, key);
+    target_shard.measure(key);
+    target_shard.touch(key);
+    Frame::Integer(1)
+}
+
+/// Swaps the keys of databases `first` and `second`, so clients that
+/// selected one see the other's.
+async fn swap_dbs(shared: &Shared, first: usize, second: usize) -> Result<(), CommandError> {
+    let Some((first, second)) = swap_indexes(shared, first, second)? else {
+        return Ok(());
+    };
+    // In database order, as everything locking several of them does.
+    let (low, high) = (
+        &shared.dbs[first.min(second)],
+        &shared.dbs[first.max(second)],
+    );
+    // Frozen so neither is resharding, which leaves a single table to swap.
+    let _frozen = (low.freeze().await, high.freeze().await);
+    let (mut low, mut high) = (low.pause(), high.pause());
+    let (first_paused, second_paused) = if first < second {
+        (&mut low, &mut high)
+    } else {
+        (&mut high, &mut low)
+    };
+    swap_paused(shared, (first, first_paused), (second, second_paused));
+    Ok(())
+}
+
+/// Checks the databases SWAPDB is given. Returns `None` if they are the
+/// same one, which leaves nothing to do.
+fn swap_indexes(
+    shared: &Shared,
+    first: usize,
+    second: usize,
+) -> Result<Option<(usize, usize)>, CommandError> {
+    let first =
+        db_index(shared, first).map_err(|_| CommandError::Invalid("invalid first DB index"))?;
+    let second =
+        db_index(shared, second).map_err(|_| CommandError::Invalid("invalid second DB index"))?;
+    Ok(Some((first, second)).filter(|_| first != second))
+}
+
+/// SWAPDB, with both databases paused and frozen.
+fn swap_paused(
+    shared: &Shared,
+    (first, first_paused): (usize, &mut Paused<'_>),
+    (second, second_paused): (usize, &mut Paused<'_>),
+) {
+    first_paused.swap(second_paused);
+    if let Some(log) = WriteLog::new(shared, first) {
+        let (first, second) = (first.to_string(), second.to_string());
+        log.feed(0, &[b"SWAPDB", first.as_bytes(), second.as_bytes()]);
+    }
+    // Lists may have appeared under the keys clients are blocked on.
+    for (db, paused) in [(first, &*first_paused), (second, &*second_paused)] {
+        let keys = shared.blocking.keys(db);
+        let mut shards = paused.lock(&keys);
+        let log = WriteLog::new(shared, db);
+        for key in &keys {
+            let (shard, db_shard) = shards.get(key);
+            serve_blocked(shared, log.as_ref(), (db, shard), db_shard, key);
+            db_shard.measure(key);
+            db_shard.touch(key);
+        }
+    }
+}
+
+/// Runs `cmd`, queued in a transaction for database `db`, under the locks
+/// EXEC holds. A SELECT changes the database the connection has selected.
+fn exec_queued(
+    cmd: Command,
+    shared: &Shared,
+    db: usize,
+    held: &mut Held<'_, '_>,
+    selected: &mut usize,
+) -> Frame {
+    let ok = || Frame::Simple("OK".to_string());
+    match cmd {
+        Command::Select { db } => match db_index(shared, db) {
+            Ok(db) => {
+                *selected = db;
+                ok()
+            }
+            Err(err) => err.to_frame(),
+        },
+        Command::Move { key, db: to } => match move_target(shared, db, to) {
+            Ok(to) => {
+                let (source, target) = held.pair(db, to);
+                source.with_keys([&key], |source| {
+                    target.with_keys([&key], |target| {
+                        move_locked(shared, &key, (db, source), (to, target))
+                    })
+                })
+            }
+            Err(err) => err.to_frame(),
+        },
+        Command::Keys { pattern } => {
+            let paused = held.paused(db);
+            keys_reply(&pattern, |cursor, count, f| paused.scan(cursor, count, f))
+        }
+        Command::Scan {
+            cursor,
+            pattern,
+            count,
+        } => scan_reply(pattern.as_deref(), |f| {
+            held.paused(db).scan(cursor, count, f)
+        }),
+        Command::DbSize => Frame::Integer(held.paused(db).len() as i64),
+        Command::RandomKey => bulk_or_null(held.paused(db).random_key().map(Bytes::from)),
+        Command::FlushDb => flush_db(shared, db, held.paused(db)),
+        Command::FlushAll => {
+            for db in 0..shared.dbs.len() {
+                held.paused(db).clear();
+            }
+            flushed_all(shared)
+        }
+        Command::SwapDb { first, second } => match swap_indexes(shared, first, second) {
+            Ok(Some((first, second))) => {
+                let (first_lock, second_lock) = held.pair(first, second);
+                let (first_paused, second_paused) = (first_lock.paused(), second_lock.paused());
+                swap_paused(shared, (first, first_paused), (second, second_paused));
+                ok()
+            }
+            Ok(None) => ok(),
+            Err(err) => err.to_frame(),
+        },
+        cmd => match held.get(db) {
+            Lock::Shards(shards) => apply(cmd, shared, db, shards),
+            Lock::Paused(paused) => {
+                let mut shards = paused.lock(cmd.keys());
+                apply(cmd, shared, db, &mut shards)
+            }
+        },
+    }
+}
+
+/// Keys scanned per lock of the layout by KEYS, so a resharding waiting
+/// to switch tables is held up for one batch at most.
+const KEYS_BATCH: usize = 1024;
+
+/// Replies to KEYS with every key matching `pattern`, sorted, walking the
+/// database with `scan`, which works as `ShardedDb::scan`.
+fn keys_reply(
+    pattern: &str,
+    mut scan: impl FnMut(u64, usize, &mut dyn FnMut(&str)) -> u64,
+) -> Frame {
+    // A set, as keys moving while resharding can be scanned twice.
+    let mut keys = BTreeSet::new();
+    let mut cursor = 0;
+    loop {
+        cursor = scan(cursor, KEYS_BATCH, &mut |key| {
+            if glob_match(pattern.as_bytes(), key.as_bytes()) {
+                keys.insert(key.to_string());
+            }
+        });
+        if cursor == 0 {
+            let keys = keys.into_iter().map(|key| Frame::Bulk(key.into()));
+            return Frame::Array(keys.collect());
+        }
+    }
+}
+
+/// Replies to SCAN with the cursor `scan` returns and the keys it visits
+/// that match `pattern`.
+fn scan_reply(pattern: Option<&str>, scan: impl FnOnce(&mut dyn FnMut(&str)) -> u64) -> Frame {
+    let pattern = pattern.unwrap_or("*");
+    let mut keys = Vec::new();
+    let cursor = scan(&mut |key| {
+        if glob_match(pattern.as_bytes(), key.as_bytes()) {
+            keys.push(Frame::Bulk(key.to_string().into()));
+        }
+    });
+    Frame::Array(vec![
+        Frame::Bulk(cursor.to_string().into()),
+        Frame::Array(keys),
+    ])
+}
+
+/// FLUSHDB, with database `db` paused.
+fn flush_db(shared: &Shared, db: usize, paused: &Paused<'_>) -> Frame {
+    paused.clear();
+    // Recorded before commands resume, so no write can land in the AOF or
+    // the replication stream ahead of the flush. Shard 0 is the first of
+    // the database an AOF rewrite dumps, so it keeps the flush unless it
+    // has dumped none of the database yet.
+    if let Some(log) = WriteLog::new(shared, db) {
+        log.feed(0, &[b"FLUSHDB"]);
+    }
+    Frame::Simple("OK".to_string())
+}
+
+/// Records FLUSHALL, once every database is cleared and still paused.
+fn flushed_all(shared: &Shared) -> Frame {
+    // As FLUSHDB, recorded in database 0, the first one dumped.
+    if let Some(log) = WriteLog::new(shared, 0) {
+        log.feed(0, &[b"FLUSHALL"]);
+    }
+    Frame::Simple("OK".to_string())
+}
+
+/// Evicts keys until the data set fits in `maxmemory`, or fails if the
+/// policy is not to evict.
+///
+/// Only client commands go through here, so that neither replaying the AOF
+/// nor the writes of a primary are ever refused. Evicted keys are written
+/// to the AOF and replicas as deleted.
+fn make_room(shared: &Shared) -> Result<(), CommandError> {
+    let config = &shared.config;
+    if config.maxmemory == 0 {
+        return Ok(());
+    }
+    let policy = (config.maxmemory_policy, config.maxmemory_samples);
+    db::make_room(
+        &shared.dbs,
+        &shared.used_memory,
+        config.maxmemory,
+        policy,
+        |db, shard, key| {
+            if let Some(log) = WriteLog::new(shared, db) {
+                log.feed(shard, &[b"DEL", key.as_bytes()]);
+            }
+            shared.metrics.key_evicted();
+        },
+    )
+}
+
+/// Runs `cmd` on `shards` of database `db`, which must hold the shards of
+/// all its keys, and bumps the version of every key a successful write
+/// touched.
+///
+/// A write that turns out to change nothing, such as DEL of a missing key,
+/// still counts, so EXEC may fail more often than strictly needed, but
+/// never misses a change.
+fn apply(cmd: Command, shared: &Shared, db: usize, shards: &mut LockedShards<'_>) -> Frame {
+    // Handlers measure and touch the keys they write themselves.
+    if let Command::Module { handler, args, .. } = cmd {
+        let result = module::execute_held(&*handler, args, shared, db, shards.view());
+        return result.unwrap_or_else(|err| err.to_frame());
+    }
+    let written: Vec<String> = if cmd.is_write() {
+        cmd.keys().into_iter().map(String::from).collect()
+    } else {
+        Vec::new()
+    };
+    let result = run(cmd, shared, db, shards);
+    for key in &written {
+        let shard = shards.get(key).1;
+        // Values are changed in place, so only now is their size known.
+        shard.measure(key);
+        if result.is_ok() {
+            shard.touch(key);
+        }
+    }
+    result.unwrap_or_else(|err| err.to_frame())
+}
+
+/// Applies `cmd` to database `db` and returns the reply.
+///
+/// Writes are fed to the AOF only once they are known to succeed, while the
+/// shard lock is still held.
+fn run(
+    cmd: Command,
+    shared: &Shared,
+    db: usize,
+    shards: &mut LockedShards<'_>,
+) -> Result<Frame, CommandError> {
+    let Shared {
+        dbs,
+        snapshotter,
+        aof,
+        pubsub,
+        replication,
+        ..
+    } = shared;
+    let log = WriteLog::new(shared, db);
+    let frame = match cmd {
+        Command::SetNx { key, value } => {
+            check_sizes(shared, [&value])?;
+            let (shard, db_shard) = shards.get(&key);
+            let updated = db_shard.set_nx(key.clone(), value.clone());
+            if let (Some(log), true) = (&log, updated) {
+                log.feed(shard, &[b"SET", key.as_bytes(), &value]);
+            }
+            Frame::Integer(updated as i64)
+        }
+        Command::GetSet { key, value } => {
+            check_sizes(shared, [&value])?;
+            let (shard, db_shard) = shards.get(&key);
+            let old = db_shard.get_set(key.clone(), value.clone())?;
+            if let Some(log) = &log {
+                log.feed(shard, &[b"SET", key.as_bytes(), &value]);
+            }
+            bulk_or_null(old)
+        }
+        Command::MGet { keys } => {
+            let values = keys.iter().map(|key| {
+                // MGET never fails; keys of another type read as missing.
+                bulk_or_null(shards.get(key).1.get(key).ok().flatten())
+            });
+            Frame::Array(values.collect())
+        }
+        Command::MSet { pairs } => {
+            check_sizes(shared, pairs.iter().map(|(_, value)| value))?;
+            // All shards stay locked until every key is set, so no reader
+            // sees only part of the batch.
+            for (key, value) in pairs {
+                let (shard, db_shard) = shards.get(&key);
+                if let Some(log) = &log {
+                    log.feed(shard, &[b"SET", key.as_bytes(), &value]);
+                }
+                db_shard.set(key, value, None);
+            }
+            Frame::Simple("OK".to_string())
+        }
+        Command::IncrBy { key, delta } => {
+            let (shard, db_shard) = shards.get(&key);
+            let value = db_shard.incr_by(&key, delta)?;
+            if let Some(log) = &log {
+                let delta = delta.to_string();
+                log.feed(shard, &[b"INCRBY", key.as_bytes(), delta.as_bytes()]);
+            }
+            Frame::Integer(value)
+        }
+        Command::Append { key, value } => {
+            let (shard, db_shard) = shards.get(&key);
+            if db_shard.strlen(&key)? + value.len() > shared.config.max_value_size {
+                return Err(CommandError::ValueTooLarge);
+            }
+            let len = db_shard.append(&key, &value)?;
+            if let Some(log) = &log {
+                log.feed(shard, &[b"APPEND", key.as_bytes(), &value]);
+            }
+            Frame::Integer(len as i64)
+        }
+        Command::Strlen { key } => Frame::Integer(shards.get(&key).1.strlen(&key)? as i64),
+        Command::GetRange { key, start, end } => {
+            let value = shards.get(&key).1.get(&key)?;
+            let value = value.unwrap_or_default();
+            Frame::Bulk(value.slice(index_range(value.len(), start, end)))
+        }
+        Command::HSet { key, pairs } => {
+            check_sizes(shared, pairs.iter().map(|(_, value)| value))?;
+            let (shard, db_shard) = shards.get(&key);
+            let hash = db_shard
+                .value_or_insert(&key, || Value::Hash(HashMap::new()))
+                .as_hash()?;
+            if let Some(log) = &log {
+                let mut args: Vec<&[u8]> = vec![b"HSET", key.as_bytes()];
+                for (field, value) in &pairs {
+                    args.extend([&field[..], &value[..]]);
+                }
+                log.feed(shard, &args);
+            }
+            let mut added = 0;
+            for (field, value) in pairs {
+                added += hash.insert(field, value).is_none() as i64;
+            }
+            Frame::Integer(added)
+        }
+        Command::HGet { key, field } => {
+            let (_, db_shard) = shards.get(&key);
+            let hash = db_shard.value(&key).map(Value::as_hash).transpose()?;
+            bulk_or_null(hash.and_then(|hash| hash.get(&field).cloned()))
+        }
+        Command::HGetAll { key } => {
+            let (_, db_shard) = shards.get(&key);
+            let hash = db_shard.value(&key).map(Value::as_hash).transpose()?;
+            let pairs = hash
+                .into_iter()
+                .flatten()
+                .map(|(field, value)| (Frame::Bulk(field.clone()), Frame::Bulk(value.clone())));
+            Frame::Map(pairs.collect())
+        }
+        Command::Push { key, values, front } => {
+            check_sizes(shared, &values)?;
+            let (shard, db_shard) = shards.get(&key);
+            let list = db_shard
+                .value_or_insert(&key, || Value::List(VecDeque::new()))
+                .as_list()?;
+            if let Some(log) = &log {
+                let name: &[u8] = if front { b"LPUSH" } else { b"RPUSH" };
+                let mut args = vec![name, key.as_bytes()];
+                args.extend(values.iter().map(|value| &value[..]));
+                log.feed(shard, &args);
+            }
+            for value in values {
+                if front {
+                    list.push_front(value);
+                } else {
+                    list.push_back(value);
+                }
+            }
+            let len = list.len();
+            // The reply counts the elements handed to blocked clients too.
+            serve_blocked(shared, log.as_ref(), (db, shard), db_shard, &key);
+            Frame::Integer(len as i64)
+        }
+        Command::Pop { key, count, front } => {
+            let (shard, db_shard) = shards.get(&key);
+            let popped = db_shard.pop(&key, count.unwrap_or(1) as usize, front)?;
+            feed_pop(log.as_ref(), shard, &key, popped.len(), front);
+            match count {
+                Some(_) if db_shard.exists(&key) || !popped.is_empty() => {
+                    Frame::Array(popped.into_iter().map(Frame::Bulk).collect())
+                }
+                Some(_) => Frame::Null,
+                None => bulk_or_null(popped.into_iter().next()),
+            }
+        }
+        Command::BPop { keys, front, .. } => {
+            // Never waits here: blocking is done by `blocking_pop`, which
+            // only calls this for the first try.
+            for key in keys {
+                let (shard, db_shard) = shards.get(&key);
+                if let Some(value) = db_shard.pop(&key, 1, front)?.pop() {
+                    feed_pop(log.as_ref(), shard, &key, 1, front);
+                    return Ok(Frame::Array(vec![
+                        Frame::Bulk(key.into()),
+                        Frame::Bulk(value),
+                    ]));
+                }
+            }
+            Frame::Null
+        }
+        Command::LRange { key, start, stop } => {
+            let (_, db_shard) = shards.get(&key);
+            let list = db_shard.value(&key).map(Value::as_list).transpose()?;
+            let values = list.into_iter().flat_map(|list| {
+                let range = index_range(list.len(), start, stop);
+                list.range(range).cloned().map(Frame::Bulk)
+            });
+            Frame::Array(values.collect())
+        }
+        Command::SAdd { key, members } => {
+            check_sizes(shared, &members)?;
+            let (shard, db_shard) = shards.get(&key);
+            let set = db_shard
+                .value_or_insert(&key, || Value::Set(HashSet::new()))
+                .as_set()?;
+            if let Some(log) = &log {
+                let mut args: Vec<&[u8]> = vec![b"SADD", key.as_bytes()];
+                args.extend(members.iter().map(|member| &member[..]));
+                log.feed(shard, &args);
+            }
+            let mut added = 0;
+            for member in members {
+                added += set.insert(member) as i64;
+            }
+            Frame::Integer(added)
+        }
+        Command::SMembers { key } => {
+            let (_, db_shard) = shards.get(&key);
+            let set = db_shard.value(&key).map(Value::as_set).transpose()?;
+            let members = set
+                .into_iter()
+                .flat_map(|set| set.iter().cloned().map(Frame::Bulk));
+            Frame::Set(members.collect())
+        }
+        Command::SIsMember { key, member } => {
+            let (_, db_shard) = shards.get(&key);
+            let set = db_shard.value(&key).map(Value::as_set).transpose()?;
+            Frame::Integer(set.is_some_and(|set| set.contains(&member)) as i64)
+        }
+        Command::ZAdd { key, pairs } => {
+            check_sizes(shared, pairs.iter().map(|(_, member)| member))?;
+            let (shard, db_shard) = shards.get(&key);
+            let zset = db_shard
+                .value_or_insert(&key, || Value::ZSet(ZSet::default()))
+                .as_zset()?;
+            if let Some(log) = &log {
+                let scores: Vec<String> =
+                    pairs.iter().map(|(score, _)| score.to_string()).collect();
+                let mut args: Vec<&[u8]> = vec![b"ZADD", key.as_bytes()];
+                for ((_, member), score) in pairs.iter().zip(&scores) {
+                    args.extend([score.as_bytes(), &member[..]]);
+                }
+                log.feed(shard, &args);
+            }
+            let mut added = 0;
+            for (score, member) in pairs {
+                added += zset.insert(member, score) as i64;
+            }
+            Frame::Integer(added)
+        }
+        Command::ZRange {
+            key,
+            start,
+            stop,
+            with_scores,
+        } => {
+            let (_, db_shard) = shards.get(&key);
+            let zset = db_shard.value(&key).map(Value::as_zset).transpose()?;
+            let mut reply = Vec::new();
+            if let Some(zset) = zset {
+                let range = index_range(zset.len(), start, stop);
+                for (member, score) in zset.iter().skip(range.start).take(range.len()) {
+                    reply.push(Frame::Bulk(member.clone()));
+                    if with_scores {
+                        reply.push(Frame::Bulk(score.to_string().into()));
+                    }
+                }
+            }
+            Frame::Array(reply)
+        }
+        Command::ZScore { key, member } => {
+            let (_, db_shard) = shards.get(&key);
+            let zset = db_shard.value(&key).map(Value::as_zset).transpose()?;
+            let score = zset.and_then(|zset| zset.score(&member));
+            score.map_or(Frame::Null, Frame::Double)
+        }
+        Command::Del { keys } => {
+            let mut removed = 0;
+            for key in &keys {
+                let (shard, db_shard) = shards.get(key);
+                if db_shard.remove(key) {
+                    if let Some(log) = &log {
+                        log.feed(shard, &[b"DEL", key.as_bytes()]);
+                    }
+                    removed += 1;
+                }
+            }
+            Frame::Integer(removed)
+        }
+        Command::Exists { keys } => {
+            let found = keys.iter().filter(|key| shards.get(key).1.exists(key));
+            Frame::Integer(found.count() as i64)
+        }
+        Command::Type { key } => {
+            let (_, db_shard) = shards.get(&key);
+            let name = db_shard
+                .value(&key)
+                .map_or("none", |value| value.type_name());
+            Frame::Simple(name.to_string())
+        }
+        Command::Ttl { key } => {
+            let ttl = shards.get(&key).1.ttl(&key);
+            ttl_reply(ttl, |left| ((left.as_millis() + 500) / 1000) as i64)
+        }
+        Command::Pttl { key } => {
+            let ttl = shards.get(&key).1.ttl(&key);
+            ttl_reply(ttl, |left| left.as_millis() as i64)
+        }
+        Command::Expire { key, ttl } => {
+            let (shard, db_shard) = shards.get(&key);
+            let updated = db_shard.expire(&key, ttl);
+            if let (Some(log), true) = (&log, updated) {
+                if ttl.is_zero() {
+                    log.feed(shard, &[b"DEL", key.as_bytes()]);
+                } else {
+                    let at = unix_ms_after(ttl);
+                    log.feed(shard, &[b"PEXPIREAT", key.as_bytes(), at.as_bytes()]);
+                }
+            }
+            Frame::Integer(updated as i64)
+        }
+        Command::Persist { key } => {
+            let (shard, db_shard) = shards.get(&key);
+            let updated = db_shard.persist(&key);
+            if let (Some(log), true) = (&log, updated) {
+                log.feed(shard, &[b"PERSIST", key.as_bytes()]);
+            }
+            Frame::Integer(updated as i64)
+        }
+        Command::BgSave => {
+            if snapshotter.bgsave(dbs) {
+                Frame::Simple("Background saving started".to_string())
+            } else {
+                Frame::Error("ERR Background save already in progress".to_string())
+            }
+        }
+        Command::Reshard { shards } => {
+            if dbs[db].reshard(shards) {
+                Frame::Simple("Resharding started".to_string())
+            } else {
+                Frame::Error("ERR Resharding already in progress".to_string())
+            }
+        }
+        Command::BgRewriteAof => match aof {
+            Some(aof) if aof.bgrewrite(dbs) => {
+                Frame::Simple("Background append only file rewriting started".to_string())
+            }
+            Some(_) => Frame::Error(
+                "ERR Background append only file rewriting already in progress".to_string(),
+            ),
+            None => Frame::Error("ERR append only file is disabled".to_string()),
+        },
+        Command::Publish { channel, message } => {
+            Frame::Integer(pubsub.publish(&channel, message) as i64)
+        }
+        Command::Ping { message: None } => Frame::Simple("PONG".to_string()),
+        Command::Ping {
+            message: Some(message),
+        } => Frame::Bulk(message),
+        cmd @ (Command::Subscribe { .. }
+        | Command::PSubscribe { .. }
+        | Command::Unsubscribe { .. }
+        | Command::PUnsubscribe { .. }) => {
+            // SUBSCRIBE and PSUBSCRIBE never get here outside of AOF replay,
+            // and unsubscribing without subscriptions has nothing to undo.
+            Frame::Array(vec![
+                Frame::Bulk(cmd.name().into()),
+                Frame::Null,
+                Frame::Integer(0),
+            ])
+        }
+        Command::ReplicaOf {
+            primary: Some((host, port)),
+        } => {
+            if replicate(shared, host, port) {
+                Frame::Simple("OK".to_string())
+            } else {
+                Frame::Simple("OK Already connected to specified master".to_string())
+            }
+        }
+        Command::ReplicaOf { primary: None } => {
+            replication.promote();
+            Frame::Simple("OK".to_string())
+        }
+        Command::Acl(cmd) => shared.acl.command(cmd)?,
+        Command::CommandInfo(cmd) => shared.commands.command(cmd),
+        Command::Cluster(cmd) => match &shared.cluster {
+            Some(cluster) => cluster.command(cmd)?,
+            None => return Err(CommandError::ClusterDisabled),
+        },
+        // EXEC unwatches every key once the queued commands have run.
+        Command::Unwatch => Frame::Simple("OK".to_string()),
+        // SAVE, INFO, MOVE and the commands over whole databases are run by
+        // `execute`, and the others are handled by `process`; none of them
+        // can be queued in a transaction.
+        Command::Keys { .. }
+        | Command::Scan { .. }
+        | Command::DbSize
+        | Command::RandomKey
+        | Command::FlushDb
+        | Command::FlushAll
+        | Command::Select { .. }
+        | Command::Move { .. }
+        | Command::SwapDb { .. }
+        | Command::Save
+        | Command::Info { .. }
+        | Command::Multi
+        | Command::Exec
+        | Command::Discard
+        | Command::Watch { .. }
+        | Command::Psync { .. }
+        | Command::ReplConf(_)
+        | Command::Auth { .. }
+        | Command::Hello { .. } => return Err(CommandError::NotAllowedInTransaction),
+        Command::Module { .. } => unreachable!("handlers are run by `apply`"),
+    };
+    Ok(frame)
+}
+
+/// Sections of the INFO reply, in order.
+const INFO_SECTIONS: &[&str] = &[
+    "server",
+    "clients",
+    "memory",
+    "stats",
+    "replication",
+    "commandstats",
+    "cluster",
+    "keyspace",
+];
+
+/// Builds the INFO reply for one section, or for all of them when `section`
+/// is `None` or "all". Like Redis, an unknown section gives an empty reply.
+fn info(section: Option<&str>, shared: &Shared) -> String {
+    let sections: Vec<&str> = match section {
+        None | Some("all" | "everything" | "default") => INFO_SECTIONS.to_vec(),
+        Some(name) => INFO_SECTIONS
+            .iter()
+            .copied()
+            .filter(|&s| s == name)
+            .collect(),
+    };
+
+    // Shards are locked one at a time, so the totals are not a consistent
+    // snapshot under concurrent writes, but no shard is blocked for long.
+    let needs_db = sections.iter().any(|s| matches!(*s, "memory" | "keyspace"));
+    let layouts: Vec<LayoutStats> = if needs_db {
+        shared.dbs.iter().map(ShardedDb::stats).collect()
+    } else {
+        Vec::new()
+    };
+
+    let metrics = &shared.metrics;
+    let mut out = String::new();
+    for (i, name) in sections.iter().enumerate() {
+        if i > 0 {
+            out.push_str("\r\n");
+        }
+        let _ = write!(out, "# {}{}\r\n", name[..1].to_uppercase(), &name[1..]);
+        match *name {
+            "server" => {
+                let uptime = metrics.uptime().as_secs();
+                let _ = write!(
+                    out,
+                    "mini_redis_version:{}\r\nprocess_id:{}\r\ntcp_port:{}\r\n\
+                     uptime_in_seconds:{}\r\nuptime_in_days:{}\r\n",
+                    env!("CARGO_PKG_VERSION"),
+                    std::process::id(),
+                    shared.config.port,
+                    uptime,
+                    uptime / 86_400,
+                );
+            }
+            "clients" => out.push_str(&metrics.info_clients(shared.config.maxclients)),
+            "memory" => {
+                // While resharding, keys are in either table.
+                let all = layouts
+                    .iter()
+                    .flat_map(|l| l.shards.iter().chain(&l.previous));
+                let used: usize = all.map(|s| s.bytes).sum();
+                let _ = write!(
+                    out,
+                    "used_memory_dataset:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\n",
+                    used, shared.config.maxmemory, shared.config.maxmemory_policy,
+                );
+            }
+            "stats" => out.push_str(&metrics.info_stats()),
+            "replication" => out.push_str(&shared.replication.info()),
+            "commandstats" => out.push_str(&metrics.info_commandstats()),
+            "cluster" => {
+                let enabled = shared.cluster.is_some() as u8;
+                let _ = write!(out, "cluster_enabled:{}\r\n", enabled);
+            }
+            "keyspace" => {
+                for (db, layout) in layouts.iter().enumerate() {
+                    info_keyspace(&mut out, db, layout);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    out
+}
+
+/// Writes the INFO keyspace line of database `db`, which is left out while
+/// it is empty, as in Redis, unless it is being resharded.
+fn info_keyspace(out: &mut String, db: usize, layout: &LayoutStats) {
+    let (shards, previous) = (&layout.shards, &layout.previous);
+    // While resharding, keys are in either table.
+    let all = || shards.iter().chain(previous);
+    let keys: usize = all().map(|s| s.keys).sum();
+    let expires: usize = all().map(|s| s.expires).sum();
+    if keys == 0 && previous.is_empty() {
+        return;
+    }
+    // The spread is that of the new table while resharding.
+    let placed: usize = shards.iter().map(|s| s.keys).sum();
+    let min = shards.iter().map(|s| s.keys).min().unwrap_or(0);
+    let max = shards.iter().map(|s| s.keys).max().unwrap_or(0);
+    let avg = placed as f64 / shards.len().max(1) as f64;
+    // How much fuller the fullest shard is than the average one; 1.00 means
+    // keys are spread perfectly evenly.
+    let skew = if placed == 0 { 1.0 } else { max as f64 / avg };
+    let _ = write!(
+        out,
+        "db{}:keys={},expires={},shards={},keys_per_shard_min={},keys_per_shard_max={},\
+         keys_per_shard_avg={:.2},shard_skew={:.2},resharding={}",
+        db,
+        keys,
+        expires,
+        shards.len(),
+        min,
+        max,
+        avg,
+        skew,
+        !previous.is_empty() as u8,
+    );
+    if !previous.is_empty() {
+        let _ = write!(
+            out,
+            ",resharding_from={},resharding_shards_migrated={},resharding_keys_left={}",
+            previous.len(),
+            layout.migrated,
+            previous.iter().map(|s| s.keys).sum::<usize>(),
+        );
+    }
+    out.push_str("\r\n");
+}
+
+/// Wall clock deadline `ttl` from now, in Unix milliseconds, as written to
+/// the AOF so replaying it later does not extend the key's life.
+fn unix_ms_after(ttl: Duration) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.saturating_add(ttl).as_millis().to_string()
+}
+
+fn ttl_reply(ttl: Ttl, unit: impl Fn(Duration) -> i64) -> Frame {
+    match ttl {
+        Ttl::Missing => Frame::Integer(-2),
+        Ttl::Persistent => Frame::Integer(-1),
+        Ttl::Remaining(left) => Frame::Integer(unit(left)),
+    }
+}
+
+/// Where `run` records the writes it applies to one database: the AOF and
+/// the replication backlog.
+struct WriteLog<'a> {
+    db: usize,
+    aof: Option<&'a Aof>,
+    replication: &'a Replication,
+}
+
+impl WriteLog<'_> {
+    /// `None` if nothing records writes, so `run` can skip building the
+    /// commands to record.
+    fn new(shared: &Shared, db: usize) -> Option<WriteLog<'_>> {
+        let aof = shared.aof.as_ref();
+        let replication = &shared.replication;
+        (aof.is_some() || replication.is_active()).then_some(WriteLog {
+            db,
+            aof,
+            replication,
+        })
+    }
+
+    /// Records a write to shard `shard`, given as the arguments of the
+    /// command that reproduces it.
+    fn feed(&self, shard: usize, args: &[&[u8]]) {
+        if let Some(aof) = self.aof {
+            aof.feed(self.db, shard, args);
+        }
+        self.replication.feed(self.db, args);
+    }
+
+    /// Records that `key`, holding `entry`, moved from shard `shard` to
+    /// shard `to_shard` of database `to`.
+    ///
+    /// The AOF gets a write to each shard rather than a MOVE, so a rewrite
+    /// keeps each half exactly when it has dumped that shard.
+    fn feed_move(&self, shard: usize, key: &str, to: usize, to_shard: usize, entry: &Entry) {
+        if let Some(aof) = self.aof {
+            aof.feed(self.db, shard, &[b"DEL", key.as_bytes()]);
+            aof.feed_entry(to, to_shard, key, entry);
+        }
+        let to = to.to_string();
+        self.replication
+            .feed(self.db, &[b"MOVE", key.as_bytes(), to.as_bytes()]);
+    }
+}
+
+/// Records a pop of `count` elements as the LPOP or RPOP reproducing it.
+fn feed_pop(log: Option<&WriteLog>, shard: usize, key: &str, count: usize, front: bool) {
+    if let (Some(log), true) = (log, count > 0) {
+        let name: &[u8] = if front { b"LPOP" } else { b"RPOP" };
+        let count = count.to_string();
+        log.feed(shard, &[name, key.as_bytes(), count.as_bytes()]);
+    }
+}
+
+fn bulk_or_null(value: Option<Bytes>) -> Frame {
+    match value {
+        Some(value) => Frame::Bulk(value),
+        None => Frame::Null,
+    }
+}
+
+/// Fails with `ValueTooLarge` if any of `values` exceeds `max-value-size`.
+fn check_sizes<'a>(
+    shared: &Shared,
+    values: impl IntoIterator<Item = &'a Bytes>,
+) -> Result<(), CommandError> {
+    let max = shared.config.max_value_size;
+    if values.into_iter().any(|value| value.len() > max) {
+        return Err(CommandError::ValueTooLarge);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::Poll;
+
+    fn test_shared() -> Shared {
+        Shared::new(Config {
+            databases: 2,
+            ..Config::default()
+        })
+    }
+
+    fn command(shared: &Shared, args: &[&str]) -> Command {
+        let args = args.iter().map(|arg| Frame::Bulk(arg.to_string().into()));
+        Command::from_frame(Frame::Array(args.collect()), &shared.commands).unwrap()
+    }
+
+    async fn run(shared: &Shared, db: usize, args: &[&str]) -> Frame {
+        execute(command(shared, args), shared, db).await
+    }
+
+    /// Sends MULTI, then `commands`, then EXEC, as a client that selected
+    /// database `selected`.
+    async fn exec(shared: &Shared, selected: &mut usize, commands: &[&[&str]]) -> Frame {
+        let mut transaction = Transaction::new(shared.dbs.clone());
+        transaction.multi(*selected);
+        for args in commands {
+            let queued = transaction.queue(Ok(command(shared, args)));
+            assert_eq!(queued, Frame::Simple("QUEUED".to_string()), "{:?}", args);
+        }
+        let exec = transaction.exec(|db, cmd, held| exec_queued(cmd, shared, db, held, selected));
+        exec.await
+    }
+
+    fn bulk(s: &str) -> Frame {
+        Frame::Bulk(s.to_string().into())
+    }
+
+    fn error(message: &str) -> Frame {
+        Frame::Error(message.to_string())
+    }
+
+    async fn request(connection: &mut Connection, args: &[&str]) -> Frame {
+        let args = args.iter().map(|arg| bulk(arg)).collect();
+        connection.write_frame(&Frame::Array(args)).await.unwrap();
+        connection.read_frame().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn commands_need_auth_when_a_password_is_set() {
+        let shared = Shared::new(Config {
+            requirepass: Some("secret".to_string()),
+            ..Config::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_notify, shutdown) = watch::channel(false);
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            process(socket, shared, Shutdown::new(shutdown)).await;
+        });
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut connection = Connection::new(socket, RespCodec::default());
+        let noauth = error("NOAUTH Authentication required.");
+        assert_eq!(request(&mut connection, &["GET", "a"]).await, noauth);
+        assert_eq!(request(&mut connection, &["MULTI"]).await, noauth);
+        let reply = request(&mut connection, &["AUTH", "wrong"]).await;
+        assert!(matches!(reply, Frame::Error(e) if e.starts_with("WRONGPASS")));
+        assert_eq!(request(&mut connection, &["GET", "a"]).await, noauth);
+        assert_eq!(
+            request(&mut connection, &["AUTH", "secret"]).await,
+            Frame::Simple("OK".to_string())
+        );
+        assert_eq!(request(&mut connection, &["GET", "a"]).await, Frame::Null);
+    }
+
+    #[tokio::test]
+    async fn select_and_move_inside_exec() {
+        let shared = test_shared();
+        run(&shared, 0, &["SET", "a", "1"]).await;
+        run(&shared, 1, &["SET", "b", "2"]).await;
+        let mut selected = 0;
+        let reply = exec(
+            &shared,
+            &mut selected,
+            &[
+                &["MOVE", "a", "1"],
+                &["SELECT", "1"],
+                &["GET", "a"],
+                &["MOVE", "b", "0"],
+                &["MOVE", "a", "1"],
+                // Not a database, so the next commands stay in database 1.
+                &["SELECT", "2"],
+                &["GET", "b"],
+            ],
+        )
+        .await;
+        let expected = [
+            Frame::Integer(1),
+            Frame::Simple("OK".to_string()),
+            bulk("1"),
+            Frame::Integer(1),
+            error("ERR source and destination objects are the same"),
+            error("ERR DB index is out of range"),
+            Frame::Null,
+        ];
+        assert_eq!(reply, Frame::Array(expected.to_vec()));
+        assert_eq!(selected, 1);
+        assert_eq!(run(&shared, 0, &["GET", "b"]).await, bulk("2"));
+
+        // A transaction that does not run selects nothing.
+        let mut transaction = Transaction::new(shared.dbs.clone());
+        transaction.watch(1, vec!["a".to_string()]);
+        transaction.multi(selected);
+        transaction.queue(Ok(command(&shared, &["SELECT", "0"])));
+        run(&shared, 1, &["DEL", "a"]).await;
+        let reply = transaction
+            .exec(|db, cmd, held| exec_queued(cmd, &shared, db, held, &mut selected))
+            .await;
+        assert_eq!((reply, selected), (Frame::Null, 1));
+    }
+
+    #[tokio::test]
+    async fn whole_database_commands_inside_exec() {
+        let shared = test_shared();
+        run(&shared, 1, &["SET", "other", "1"]).await;
+        let mut selected = 0;
+        let reply = exec(
+            &shared,
+            &mut selected,
+            &[
+                &["SET", "a", "1"],
+                &["SET", "b", "2"],
+                &["KEYS", "*"],
+                &["SCAN", "0", "MATCH", "a", "COUNT", "1000"],
+                &["DBSIZE"],
+                &["FLUSHDB"],
+                &["RANDOMKEY"],
+                &["SELECT", "1"],
+                &["DBSIZE"],
+                &["FLUSHALL"],
+                &["DBSIZE"],
+            ],
+        )
+        .await;
+        let ok = Frame::Simple("OK".to_string());
+        let expected = [
+            ok.clone(),
+            ok.clone(),
+            Frame::Array(vec![bulk("a"), bulk("b")]),
+            Frame::Array(vec![bulk("0"), Frame::Array(vec![bulk("a")])]),
+            Frame::Integer(2),
+            ok.clone(),
+            Frame::Null,
+            ok.clone(),
+            Frame::Integer(1),
+            ok,
+            Frame::Integer(0),
+        ];
+        assert_eq!(reply, Frame::Array(expected.to_vec()));
+        assert_eq!(
+            run(&shared, 1, &["EXISTS", "other"]).await,
+            Frame::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn swapdb_hands_lists_to_blocked_clients() {
+        let shared = test_shared();
+        run(&shared, 0, &["RPUSH", "list", "x", "y"]).await;
+        let pop = blocking_pop(vec!["list".to_string()], true, None, &shared, 1);
+        tokio::pin!(pop);
+        assert!(futures::poll!(&mut pop).is_pending());
+
+        let mut selected = 0;
+        let commands: &[&[&str]] = &[&["SWAPDB", "0", "1"], &["EXISTS", "list"]];
+        let reply = exec(&shared, &mut selected, commands).await;
+        let expected = [Frame::Simple("OK".to_string()), Frame::Integer(0)];
+        assert_eq!(reply, Frame::Array(expected.to_vec()));
+        let popped = Frame::Array(vec![bulk("list"), bulk("x")]);
+        assert_eq!(futures::poll!(&mut pop), Poll::Ready(popped));
+
+        // The same outside a transaction.
+        let pop = blocking_pop(vec!["list".to_string()], false, None, &shared, 0);
+        tokio::pin!(pop);
+        assert!(futures::poll!(&mut pop).is_pending());
+        let reply = run(&shared, 0, &["SWAPDB", "1", "0"]).await;
+        assert_eq!(reply, Frame::Simple("OK".to_string()));
+        let popped = Frame::Array(vec![bulk("list"), bulk("y")]);
+        assert_eq!(futures::poll!(&mut pop), Poll::Ready(popped));
+        assert_eq!(
+            run(&shared, 1, &["EXISTS", "list"]).await,
+            Frame::Integer(0)
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use crate::frame::{Frame, Protocol};
+use indexmap::IndexMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// Upper bounds of the latency histogram buckets, in microseconds.
+const LATENCY_BUCKETS_US: [u64; 12] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 100_000, 500_000, 1_000_000,
+];
+
+/// Server wide counters.
+///
+/// Everything is an atomic, and the per command table is built once at
+/// startup and never modified, so recording a command takes no lock.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    started: Instant,
+    connected_clients: AtomicU64,
+    total_connections: AtomicU64,
+    rejected_connections: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    evicted_keys: AtomicU64,
+    /// In the order of `CommandTable::names`.
+    commands: IndexMap<&'static str, CommandStats>,
+}
+
+#[derive(Default)]
+struct CommandStats {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    usec: AtomicU64,
+    /// Calls per bucket of `LATENCY_BUCKETS_US`, plus one for
+    /// everything slower.
+    buckets: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+    /// Calls seen by the last run of the sampler.
+    sampled_calls: AtomicU64,
+    ops_per_sec: AtomicU64,
+}
+
+/// Decrements the connected clients gauge when dropped.
+pub struct ClientGuard {
+    metrics: Metrics,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.metrics.inner.connected_clients.fetch_sub(1, Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Counts calls of the commands in `names`.
+    pub fn new(names: &[&'static str]) -> Metrics {
+        Metrics {
+            inner: Arc::new(Inner {
+                started: Instant::now(),
+                connected_clients: AtomicU64::new(0),
+                total_connections: AtomicU64::new(0),
+                rejected_connections: AtomicU64::new(0),
+                bytes_in: AtomicU64::new(0),
+                bytes_out: AtomicU64::new(0),
+                evicted_keys: AtomicU64::new(0),
+                commands: names
+                    .iter()
+                    .map(|&name| (name, CommandStats::default()))
+                    .collect(),
+            }),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.inner.started.elapsed()
+    }
+
+    /// Counts a new connection until the returned guard is dropped.
+    pub fn client_connected(&self) -> ClientGuard {
+        self.inner.connected_clients.fetch_add(1, Relaxed);
+        self.inner.total_connections.fetch_add(1, Relaxed);
+        ClientGuard {
+            metrics: self.clone(),
+        }
+    }
+
+    pub fn client_rejected(&self) {
+        self.inner.rejected_connections.fetch_add(1, Relaxed);
+    }
+
+    pub fn key_evicted(&self) {
+        self.inner.evicted_keys.fetch_add(1, Relaxed);
+    }
+
+    pub fn record_in(&self, frame: &Frame, protocol: Protocol) {
+        let len = frame.encoded_len(protocol);
+        self.inner.bytes_in.fetch_add(len, Relaxed);
+    }
+
+    pub fn record_out(&self, frame: &Frame, protocol: Protocol) {
+        let len = frame.encoded_len(protocol);
+        self.inner.bytes_out.fetch_add(len, Relaxed);
+    }
+
+    pub fn record_command(&self, name: &str, elapsed: Duration, reply: &Frame) {
+        let stats = match self.inner.commands.get(name) {
+            Some(stats) => stats,
+            None => return,
+        };
+        let usec = elapsed.as_micros() as u64;
+        stats.calls.fetch_add(1, Relaxed);
+        stats.usec.fetch_add(usec, Relaxed);
+        if matches!(reply, Frame::Error(_)) {
+            stats.errors.fetch_add(1, Relaxed);
+        }
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&bound| usec <= bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        stats.buckets[bucket].fetch_add(1, Relaxed);
+    }
+
+    fn total_calls(&self) -> u64 {
+        self.inner
+            .commands
+            .values()
+            .map(|s| s.calls.load(Relaxed))
+            .sum()
+    }
+
+    fn total_ops_per_sec(&self) -> u64 {
+        self.inner
+            .commands
+            .values()
+            .map(|s| s.ops_per_sec.load(Relaxed))
+            .sum()
+    }
+
+    /// Body of the INFO clients section.
+    pub fn info_clients(&self, maxclients: usize) -> String {
+        let i = &self.inner;
+        format!(
+            "connected_clients:{}\r\nmaxclients:{}\r\nrejected_connections:{}\r\n",
+            i.connected_clients.load(Relaxed),
+            maxclients,
+            i.rejected_connections.load(Relaxed),
+        )
+    }
+
+    pub fn info_stats(&self) -> String {
+        let i = &self.inner;
+        format!(
+            "total_connections_received:{}\r\ntotal_commands_processed:{}\r\n\
+             instantaneous_ops_per_sec:{}\r\ntotal_net_input_bytes:{}\r\n\
+             total_net_output_bytes:{}\r\nevicted_keys:{}\r\n",
+            i.total_connections.load(Relaxed),
+            self.total_calls(),
+            self.total_ops_per_sec(),
+            i.bytes_in.load(Relaxed),
+            i.bytes_out.load(Relaxed),
+            i.evicted_keys.load(Relaxed),
+        )
+    }
+
+    pub fn info_commandstats(&self) -> String {
+        let mut out = String::new();
+        for (&name, stats) in &self.inner.commands {
+            let calls = stats.calls.load(Relaxed);
+            if calls == 0 {
+                continue;
+            }
+            let usec = stats.usec.load(Relaxed);
+            let _ = write!(
+                out,
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2},failed_calls={}\r\n",
+                name,
+                calls,
+                usec,
+                usec as f64 / calls as f64,
+                stats.errors.load(Relaxed),
+            );
+        }
+        out
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let i = &self.inner;
+        let mut out = String::new();
+        let mut metric = |name: &str, help: &str, kind: &str, value: u64| {
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        };
+        metric(
+            "mini_redis_uptime_seconds",
+            "Seconds since the server started.",
+            "gauge",
+            self.uptime().as_secs(),
+        );
+        metric(
+            "mini_redis_connected_clients",
+            "Currently open client connections.",
+            "gauge",
+            i.connected_clients.load(Relaxed),
+        );
+        metric(
+            "mini_redis_connections_received_total",
+            "Connections accepted since startup.",
+            "counter",
+            i.total_connections.load(Relaxed),
+        );
+        metric(
+            "mini_redis_rejected_connections_total",
+            "Connections refused because of the maxclients limit.",
+            "counter",
+            i.rejected_connections.load(Relaxed),
+        );
+        metric(
+            "mini_redis_net_input_bytes_total",
+            "Bytes of commands read from clients.",
+            "counter",
+            i.bytes_in.load(Relaxed),
+        );
+        metric(
+            "mini_redis_net_output_bytes_total",
+            "Bytes of replies written to clients.",
+            "counter",
+            i.bytes_out.load(Relaxed),
+        );
+        metric(
+            "mini_redis_evicted_keys_total",
+            "Keys evicted because of the maxmemory limit.",
+            "counter",
+            i.evicted_keys.load(Relaxed),
+        );
+
+        out.push_str("# HELP mini_redis_commands_total Commands processed.\n");
+        out.push_str("# TYPE mini_redis_commands_total counter\n");
+        for &name in i.commands.keys() {
+            let calls = i.commands[name].calls.load(Relaxed);
+            let _ = writeln!(
+                out,
+                "mini_redis_commands_total{{command=\"{name}\"}} {calls}"
+            );
+        }
+
+        out.push_str(
+            "# HELP mini_redis_command_errors_total Commands that replied with an error.\n",
+        );
+        out.push_str("# TYPE mini_redis_command_errors_total counter\n");
+        for &name in i.commands.keys() {
+            let errors = i.commands[name].errors.load(Relaxed);
+            let _ = writeln!(
+                out,
+                "mini_redis_command_errors_total{{command=\"{name}\"}} {errors}"
+            );
+        }
+
+        out.push_str("# HELP mini_redis_command_ops_per_second Calls during the last second.\n");
+        out.push_str("# TYPE mini_redis_command_ops_per_second gauge\n");
+        for &name in i.commands.keys() {
+            let ops = i.commands[name].ops_per_sec.load(Relaxed);
+            let _ = writeln!(
+                out,
+                "mini_redis_command_ops_per_second{{command=\"{name}\"}} {ops}"
+            );
+        }
+
+        out.push_str("# HELP mini_redis_command_duration_seconds Time spent executing commands.\n");
+        out.push_str("# TYPE mini_redis_command_duration_seconds histogram\n");
+        for &name in i.commands.keys() {
+            let stats = &i.commands[name];
+            let mut cumulative = 0;
+            for (bucket, &bound) in LATENCY_BUCKETS_US.iter().enumerate() {
+                cumulative += stats.buckets[bucket].load(Relaxed);
+                let _ = writeln!(
+                    out,
+                    "mini_redis_command_duration_seconds_bucket{{command=\"{name}\",le=\"{}\"}} {cumulative}",
+                    bound as f64 / 1e6
+                );
+            }
+            let count = stats.calls.load(Relaxed);
+            let _ = writeln!(
+                out,
+                "mini_redis_command_duration_seconds_bucket{{command=\"{name}\",le=\"+Inf\"}} {count}"
+            );
+            let _ = writeln!(
+                out,
+                "mini_redis_command_duration_seconds_sum{{command=\"{name}\"}} {}",
+                stats.usec.load(Relaxed) as f64 / 1e6
+            );
+            let _ = writeln!(
+                out,
+                "mini_redis_command_duration_seconds_count{{command=\"{name}\"}} {count}"
+            );
+        }
+        out
+    }
+}
+
+/// Background task updating the ops/sec figures once a second.
+pub async fn sample_ops(metrics: Metrics) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        for stats in metrics.inner.commands.values() {
+            let calls = stats.calls.load(Relaxed);
+            let previous = stats.sampled_calls.swap(calls, Relaxed);
+            stats.ops_per_sec.store(calls - previous, Relaxed);
+        }
+    }
+}
+
+/// Serves `GET /metrics` over plain HTTP/1.1 for Prometheus to scrape.
+pub async fn serve_http(listener: TcpListener, metrics: Metrics) {
+    info!("Serving metrics on {:?}", listener.local_addr().ok());
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = respond(socket, &metrics).await {
+                        debug!("metrics request failed: {}", err);
+                    }
+                });
+            }
+            Err(err) => {
+                warn!("Failed to accept a metrics connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+async fn respond(mut socket: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    // Only the request line matters, so a single read of the head is enough.
+    let mut buf = vec![0; 4096];
+    let n = socket.read(&mut buf).await?;
+    let head = String::from_utf8_lossy(&buf[..n]);
+    let mut request_line = head.lines().next().unwrap_or("").split_whitespace();
+
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.prometheus()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value of each line of `out` starting with `prefix`, in order.
+    fn values(out: &str, prefix: &str) -> Vec<u64> {
+        let lines = out.lines().filter(|line| line.starts_with(prefix));
+        lines
+            .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::new(&["get", "set"]);
+        let ok = Frame::Simple("OK".to_string());
+        for usec in [10, 300, 2_000_000] {
+            metrics.record_command("get", Duration::from_micros(usec), &ok);
+        }
+        let err = Frame::Error("ERR".to_string());
+        metrics.record_command("set", Duration::from_micros(60), &err);
+        metrics.record_command("nosuch", Duration::from_micros(60), &ok);
+        assert_eq!(metrics.total_calls(), 4);
+
+        let out = metrics.prometheus();
+        assert!(!out.contains("nosuch"));
+        let bucket = "mini_redis_command_duration_seconds_bucket{command=\"get\"";
+        let buckets = values(&out, bucket);
+        assert_eq!(buckets.len(), LATENCY_BUCKETS_US.len() + 1);
+        assert!(buckets.windows(2).all(|pair| pair[0] <= pair[1]));
+        // 10us, then 300us, and 2s only in the last one.
+        assert_eq!(buckets[..5], [1, 1, 1, 2, 2]);
+        assert_eq!(buckets[LATENCY_BUCKETS_US.len() - 1], 2);
+        let inf = values(&out, &format!("{},le=\"+Inf\"}}", bucket));
+        let count = "mini_redis_command_duration_seconds_count{command=\"get\"}";
+        assert_eq!(inf, [3]);
+        assert_eq!(values(&out, count), [3]);
+
+        let errors = "mini_redis_command_errors_total{command=\"set\"}";
+        assert_eq!(values(&out, errors), [1]);
+    }
+}
@@ -0,0 +1,694 @@
+use crate::cmd::{expire_ms, Command, CommandInfo};
+use crate::db::{until_unix_ms, Shard, ShardView};
+use crate::error::CommandError;
+use crate::frame::Frame;
+use crate::parse::{Parse, ParseError};
+use crate::{bulk_or_null, check_sizes, unix_ms_after, Shared, WriteLog};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use indexmap::IndexMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A command implemented outside of `Command`, and registered in the
+/// `CommandTable` at startup.
+///
+/// Parsing checks the arity of the command and picks its keys out of the
+/// arguments, so ACLs, cluster redirects, `maxmemory` and transactions
+/// treat it like any other command, and COMMAND lists it. `execute` parses
+/// the rest of the arguments and runs it.
+pub trait CommandHandler: Send + Sync {
+    /// The lowercase name clients call the command by.
+    fn name(&self) -> &'static str;
+
+    /// The number of arguments, the name included, or minus the least
+    /// number for a command taking any number of them.
+    fn arity(&self) -> i64;
+
+    fn flags(&self) -> &'static [Flag];
+
+    fn key_positions(&self) -> KeyPositions {
+        KeyPositions::NONE
+    }
+
+    /// Runs the command with `args`, the arguments after its name, against
+    /// `db`.
+    ///
+    /// A command queued in a transaction runs while EXEC holds its locks,
+    /// so it fails instead if the future does not finish on the first poll.
+    fn execute<'a>(&'a self, db: Db<'a>, args: Parse)
+        -> BoxFuture<'a, Result<Frame, CommandError>>;
+}
+
+impl fmt::Debug for dyn CommandHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+/// The properties of a command COMMAND reports, and the server acts on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flag {
+    /// The command modifies the keyspace, so replicas refuse it from
+    /// clients.
+    Write,
+    ReadOnly,
+    /// The command can grow the data set, so it is refused once
+    /// `maxmemory` is reached and nothing can be evicted.
+    DenyOom,
+    Fast,
+    /// The command is about running the server rather than the data.
+    Admin,
+    PubSub,
+}
+
+impl Flag {
+    fn as_str(self) -> &'static str {
+        match self {
+            Flag::Write => "write",
+            Flag::ReadOnly => "readonly",
+            Flag::DenyOom => "denyoom",
+            Flag::Fast => "fast",
+            Flag::Admin => "admin",
+            Flag::PubSub => "pubsub",
+        }
+    }
+}
+
+/// Which arguments of a command are keys, as COMMAND INFO gives them: the
+/// first and last, counting the name as 0 and negative positions from the
+/// end, and the step between two keys.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyPositions {
+    pub first: i64,
+    pub last: i64,
+    pub step: i64,
+}
+
+impl KeyPositions {
+    pub const NONE: KeyPositions = KeyPositions {
+        first: 0,
+        last: 0,
+        step: 0,
+    };
+
+    /// Every argument from `first` on.
+    pub const fn all_from(first: i64) -> KeyPositions {
+        KeyPositions {
+            first,
+            last: -1,
+            step: 1,
+        }
+    }
+
+    /// Just the argument at `position`.
+    pub const fn single(position: i64) -> KeyPositions {
+        KeyPositions {
+            first: position,
+            last: position,
+            step: 1,
+        }
+    }
+
+    /// Whether the argument at `position` of a command with `argc` of them
+    /// is a key.
+    fn contains(self, position: usize, argc: usize) -> bool {
+        let last = if self.last < 0 {
+            argc as i64 + self.last
+        } else {
+            self.last
+        };
+        let position = position as i64;
+        self.first > 0
+            && (self.first..=last).contains(&position)
+            && (position - self.first) % self.step.max(1) == 0
+    }
+}
+
+/// What COMMAND reports about a command: its name, arity, flags and which
+/// arguments are keys, as a `CommandHandler` gives them.
+#[derive(Clone, Copy, Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i64,
+    pub flags: &'static [Flag],
+    pub keys: KeyPositions,
+}
+
+impl CommandSpec {
+    pub const fn new(
+        name: &'static str,
+        arity: i64,
+        flags: &'static [Flag],
+        keys: KeyPositions,
+    ) -> CommandSpec {
+        CommandSpec {
+            name,
+            arity,
+            flags,
+            keys,
+        }
+    }
+
+    fn of(handler: &dyn CommandHandler) -> CommandSpec {
+        CommandSpec::new(
+            handler.name(),
+            handler.arity(),
+            handler.flags(),
+            handler.key_positions(),
+        )
+    }
+
+    /// Fails as a parser running out of arguments, or left with some, would
+    /// if `argc` arguments, the name included, do not match the arity.
+    pub fn check_arity(&self, argc: usize) -> Result<(), ParseError> {
+        if argc < self.arity.unsigned_abs() as usize {
+            return Err(ParseError::EndOfStream);
+        }
+        if self.arity > 0 && argc > self.arity as usize {
+            return Err(ParseError::ExtraArguments);
+        }
+        Ok(())
+    }
+
+    /// The COMMAND INFO entry of the command.
+    fn info(&self) -> Frame {
+        let flags = self.flags.iter();
+        Frame::Array(vec![
+            Frame::Bulk(self.name.into()),
+            Frame::Integer(self.arity),
+            Frame::Set(
+                flags
+                    .map(|flag| Frame::Simple(flag.as_str().to_string()))
+                    .collect(),
+            ),
+            Frame::Integer(self.keys.first),
+            Frame::Integer(self.keys.last),
+            Frame::Integer(self.keys.step),
+        ])
+    }
+}
+
+/// Every command of the server, looked up by name when a command is
+/// parsed: the `CommandHandler`s, and the specs of those `Command` parses.
+pub struct CommandTable {
+    handlers: HashMap<&'static str, Arc<dyn CommandHandler>>,
+    /// The registered commands first, in the order COMMAND lists them.
+    specs: IndexMap<&'static str, CommandSpec>,
+    /// Every command, registered or parsed by `Command`, in the order INFO
+    /// lists them.
+    names: Vec<&'static str>,
+}
+
+impl CommandTable {
+    /// A table with the built-in handlers. Modules register theirs on top
+    /// before the server starts.
+    pub fn new() -> CommandTable {
+        let mut table = CommandTable {
+            handlers: HashMap::new(),
+            specs: Command::SPECS
+                .iter()
+                .map(|spec| (spec.name, *spec))
+                .collect(),
+            names: Command::NAMES.to_vec(),
+        };
+        table.register(Arc::new(Get));
+        table.register(Arc::new(Set));
+        table
+    }
+
+    /// Adds `handler`, after the other registered commands.
+    ///
+    /// Panics if a command already has its name.
+    pub fn register(&mut self, handler: Arc<dyn CommandHandler>) {
+        let name = handler.name();
+        assert!(
+            !self.specs.contains_key(name),
+            "command '{}' is already defined",
+            name
+        );
+        let registered = self.handlers.len();
+        self.names.insert(registered, name);
+        self.specs
+            .shift_insert(registered, name, CommandSpec::of(&*handler));
+        self.handlers.insert(name, handler);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn CommandHandler>> {
+        self.handlers.get(name)
+    }
+
+    pub fn spec(&self, name: &str) -> Option<&CommandSpec> {
+        self.specs.get(name)
+    }
+
+    pub fn names(&self) -> &[&'static str] {
+        &self.names
+    }
+
+    /// Answers COMMAND.
+    pub fn command(&self, cmd: CommandInfo) -> Frame {
+        match cmd {
+            CommandInfo::Count => Frame::Integer(self.specs.len() as i64),
+            CommandInfo::List => Frame::Array(self.specs.values().map(CommandSpec::info).collect()),
+            CommandInfo::Info(names) => {
+                let specs = names.iter().map(|name| {
+                    let spec = self.specs.get(&name.to_lowercase()[..]);
+                    spec.map_or(Frame::Null, CommandSpec::info)
+                });
+                Frame::Array(specs.collect())
+            }
+        }
+    }
+}
+
+/// Checks the arity of a command of `handler`, whose arguments after the
+/// name are left in `parse`, and picks out its keys.
+pub fn parse_command(
+    handler: &Arc<dyn CommandHandler>,
+    parse: Parse,
+) -> Result<Command, ParseError> {
+    let argc = 1 + parse.remaining();
+    CommandSpec::of(&**handler).check_arity(argc)?;
+    let positions = handler.key_positions();
+    let mut args = parse.clone();
+    let mut keys = Vec::new();
+    for position in 1..argc {
+        if positions.contains(position, argc) {
+            keys.push(args.next_string()?);
+        } else {
+            args.next_bytes()?;
+        }
+    }
+    Ok(Command::Module {
+        handler: handler.clone(),
+        args: parse,
+        keys,
+    })
+}
+
+/// The database a `CommandHandler` runs against, which finds the shards
+/// of the keys it is given and locks them.
+pub struct Db<'a> {
+    shared: &'a Shared,
+    index: usize,
+    /// The shards EXEC holds while running a transaction, which hold the
+    /// keys of every queued command.
+    held: Option<ShardView<'a>>,
+}
+
+impl<'a> Db<'a> {
+    pub fn new(shared: &'a Shared, index: usize, held: Option<ShardView<'a>>) -> Db<'a> {
+        Db {
+            shared,
+            index,
+            held,
+        }
+    }
+
+    /// Runs `f` with the shards of `keys` locked. The keys should be among
+    /// those of the command, which are the only ones locked in a
+    /// transaction, and the only ones ACLs and cluster redirects look at.
+    pub fn read<T>(&mut self, keys: &[&str], f: impl FnOnce(&mut Shards) -> T) -> T {
+        let log = WriteLog::new(self.shared, self.index);
+        match &mut self.held {
+            Some(view) => f(&mut Shards {
+                view,
+                log: log.as_ref(),
+            }),
+            None => {
+                let layout = self.shared.dbs[self.index].layout();
+                let mut locked = layout.lock(keys);
+                f(&mut Shards {
+                    view: &mut locked.view(),
+                    log: log.as_ref(),
+                })
+            }
+        }
+    }
+
+    /// Like `read`, for a change to `keys`. Their sizes are measured again
+    /// afterwards, and their versions bumped if `f` succeeds, so WATCH
+    /// notices the write.
+    pub fn write<T>(
+        &mut self,
+        keys: &[&str],
+        f: impl FnOnce(&mut Shards) -> Result<T, CommandError>,
+    ) -> Result<T, CommandError> {
+        self.read(keys, |shards| {
+            let result = f(shards);
+            for key in keys {
+                let shard = shards.get(key);
+                shard.measure(key);
+                if result.is_ok() {
+                    shard.touch(key);
+                }
+            }
+            result
+        })
+    }
+}
+
+/// The shards locked by `Db::read` or `Db::write`.
+pub struct Shards<'s, 'v> {
+    view: &'s mut ShardView<'v>,
+    log: Option<&'s WriteLog<'s>>,
+}
+
+impl Shards<'_, '_> {
+    /// The shard of `key`, which must be one of the keys locked.
+    pub fn get(&mut self, key: &str) -> &mut Shard {
+        self.view.get(key).1
+    }
+
+    /// Records a write to `key` in the AOF and the replication stream,
+    /// given as the arguments of the command that reproduces it.
+    pub fn feed(&mut self, key: &str, args: &[&[u8]]) {
+        if let Some(log) = self.log {
+            log.feed(self.view.get(key).0, args);
+        }
+    }
+}
+
+/// Runs a command of `handler` queued in a transaction, on the shards EXEC
+/// holds.
+pub fn execute_held(
+    handler: &dyn CommandHandler,
+    args: Parse,
+    shared: &Shared,
+    index: usize,
+    held: ShardView<'_>,
+) -> Result<Frame, CommandError> {
+    let db = Db::new(shared, index, Some(held));
+    // EXEC holds its locks until every queued command has run, so nothing
+    // the command could wait for can happen in the meantime.
+    match futures::FutureExt::now_or_never(handler.execute(db, args)) {
+        Some(result) => result,
+        None => Err(CommandError::NotAllowedInTransaction),
+    }
+}
+
+struct Get;
+
+impl CommandHandler for Get {
+    fn name(&self) -> &'static str {
+        "get"
+    }
+
+    fn arity(&self) -> i64 {
+        2
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::ReadOnly, Flag::Fast]
+    }
+
+    fn key_positions(&self) -> KeyPositions {
+        KeyPositions::single(1)
+    }
+
+    fn execute<'a>(
+        &'a self,
+        mut db: Db<'a>,
+        mut args: Parse,
+    ) -> BoxFuture<'a, Result<Frame, CommandError>> {
+        Box::pin(async move {
+            let key = args
+                .next_string()
+                .map_err(|err| CommandError::from_parse("get", err))?;
+            let value = db.read(&[&key], |shards| shards.get(&key).get(&key))?;
+            Ok(bulk_or_null(value))
+        })
+    }
+}
+
+struct Set;
+
+/// The arguments of SET.
+struct SetArgs {
+    key: String,
+    value: Bytes,
+    /// Set by EX, PX and PXAT.
+    expire: Option<Duration>,
+    /// KEEPTTL: the key keeps the deadline it had.
+    keep_ttl: bool,
+    /// NX when `Some(false)`, XX when `Some(true)`: the key is only set if
+    /// its existence matches.
+    if_exists: Option<bool>,
+}
+
+impl Set {
+    fn parse(args: &mut Parse) -> Result<SetArgs, ParseError> {
+        let mut set = SetArgs {
+            key: args.next_string()?,
+            value: args.next_bytes()?,
+            expire: None,
+            keep_ttl: false,
+            if_exists: None,
+        };
+        while args.remaining() > 0 {
+            let option = args.next_string()?.to_uppercase();
+            match &option[..] {
+                "NX" | "XX" if set.if_exists.is_none() => set.if_exists = Some(option == "XX"),
+                "KEEPTTL" if set.expire.is_none() => set.keep_ttl = true,
+                "EX" | "PX" | "PXAT" if set.expire.is_none() && !set.keep_ttl => {
+                    let ms = match &option[..] {
+                        "EX" => expire_ms(args, 1000)?,
+                        "PX" => expire_ms(args, 1)?,
+                        _ => args.next_signed()?,
+                    };
+                    if ms <= 0 {
+                        return Err(ParseError::Invalid("invalid expire time"));
+                    }
+                    set.expire = Some(match &option[..] {
+                        "PXAT" => until_unix_ms(ms as u64),
+                        _ => Duration::from_millis(ms as u64),
+                    });
+                }
+                _ => return Err(ParseError::Syntax),
+            }
+        }
+        Ok(set)
+    }
+}
+
+impl CommandHandler for Set {
+    fn name(&self) -> &'static str {
+        "set"
+    }
+
+    fn arity(&self) -> i64 {
+        -3
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Write, Flag::DenyOom]
+    }
+
+    fn key_positions(&self) -> KeyPositions {
+        KeyPositions::single(1)
+    }
+
+    fn execute<'a>(
+        &'a self,
+        mut db: Db<'a>,
+        mut args: Parse,
+    ) -> BoxFuture<'a, Result<Frame, CommandError>> {
+        Box::pin(async move {
+            let set = Set::parse(&mut args).map_err(|err| CommandError::from_parse("set", err))?;
+            let SetArgs { key, value, .. } = &set;
+            check_sizes(db.shared, [value])?;
+            let stored = db.write(&[key], |shards| {
+                let shard = shards.get(key);
+                if set
+                    .if_exists
+                    .is_some_and(|exists| exists != shard.exists(key))
+                {
+                    return Ok(false);
+                }
+                if set.keep_ttl {
+                    shard.set_keep_ttl(key.clone(), value.clone());
+                    shards.feed(key, &[b"SET", key.as_bytes(), value, b"KEEPTTL"]);
+                    return Ok(true);
+                }
+                shard.set(key.clone(), value.clone(), set.expire);
+                match set.expire {
+                    None => shards.feed(key, &[b"SET", key.as_bytes(), value]),
+                    Some(ttl) => {
+                        let at = unix_ms_after(ttl);
+                        shards.feed(
+                            key,
+                            &[b"SET", key.as_bytes(), value, b"PXAT", at.as_bytes()],
+                        );
+                    }
+                }
+                Ok(true)
+            })?;
+            if stored {
+                Ok(Frame::Simple("OK".to_string()))
+            } else {
+                Ok(Frame::Null)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Takes key-value pairs, like MSET.
+    struct Pairs;
+
+    impl CommandHandler for Pairs {
+        fn name(&self) -> &'static str {
+            "pairs"
+        }
+
+        fn arity(&self) -> i64 {
+            -3
+        }
+
+        fn flags(&self) -> &'static [Flag] {
+            &[Flag::Write]
+        }
+
+        fn key_positions(&self) -> KeyPositions {
+            KeyPositions {
+                first: 1,
+                last: -1,
+                step: 2,
+            }
+        }
+
+        fn execute<'a>(
+            &'a self,
+            _db: Db<'a>,
+            _args: Parse,
+        ) -> BoxFuture<'a, Result<Frame, CommandError>> {
+            Box::pin(async { Ok(Frame::Null) })
+        }
+    }
+
+    fn parse(table: &CommandTable, args: &[&'static str]) -> Result<Command, CommandError> {
+        let frame = Frame::Array(args.iter().map(|&arg| Frame::Bulk(arg.into())).collect());
+        Command::from_frame(frame, table)
+    }
+
+    #[test]
+    fn registered_commands_are_parsed_with_their_keys() {
+        let mut table = CommandTable::new();
+        table.register(Arc::new(Pairs));
+        assert_eq!(&table.names()[..3], ["get", "set", "pairs"]);
+
+        let cmd = parse(&table, &["PAIRS", "a", "1", "b", "2"]).unwrap();
+        assert_eq!(cmd.name(), "pairs");
+        assert_eq!(cmd.keys(), ["a", "b"]);
+        assert!(cmd.is_write() && !cmd.uses_memory());
+        let cmd = parse(&table, &["set", "a", "1", "EX", "10"]).unwrap();
+        assert_eq!(cmd.keys(), ["a"]);
+
+        for args in [&["pairs", "a"][..], &["get"], &["get", "a", "b"]] {
+            let err = parse(&table, args).unwrap_err();
+            assert!(matches!(err, CommandError::WrongArity(_)), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn every_command_has_a_spec() {
+        let table = CommandTable::new();
+        for name in table.names() {
+            assert!(table.spec(name).is_some(), "{}", name);
+        }
+        // ACL rules name aliases by the command they are parsed as.
+        for (alias, name) in Command::ALIASES {
+            assert!(table.spec(alias).is_some() && table.names().contains(name));
+        }
+        for spec in Command::SPECS {
+            let aliased = Command::ALIASES
+                .iter()
+                .any(|(alias, _)| *alias == spec.name);
+            assert!(
+                aliased || table.names().contains(&spec.name),
+                "{}",
+                spec.name
+            );
+            let err = parse(&table, &[spec.name]).err();
+            assert!(
+                !matches!(err, Some(CommandError::UnknownCommand(_))),
+                "{}",
+                spec.name
+            );
+        }
+        let info = table.command(CommandInfo::Info(vec!["MGET".to_string()]));
+        let Frame::Array(entries) = info else {
+            panic!("{:?}", info)
+        };
+        let Frame::Array(mget) = &entries[0] else {
+            panic!("{:?}", entries)
+        };
+        assert_eq!(mget[1], Frame::Integer(-2));
+        assert_eq!(mget[3..], [1, -1, 1].map(Frame::Integer));
+    }
+
+    #[test]
+    fn set_rejects_bad_expire_times_and_conflicting_options() {
+        let set = |args: &[&'static str]| {
+            let frame = Frame::Array(args.iter().map(|&arg| Frame::Bulk(arg.into())).collect());
+            Set::parse(&mut Parse::new(frame).unwrap())
+        };
+        let set_args = set(&["k", "v", "xx", "EX", "10"]).unwrap();
+        assert_eq!(set_args.expire, Some(Duration::from_secs(10)));
+        assert_eq!(set_args.if_exists, Some(true));
+        assert!(set(&["k", "v", "KEEPTTL", "NX"]).unwrap().keep_ttl);
+
+        for args in [
+            &["k", "v", "EX", "0"][..],
+            &["k", "v", "PX", "-1"],
+            &["k", "v", "EX", "9223372036854775807"],
+            &["k", "v", "PXAT", "0"],
+        ] {
+            let err = set(args).err().unwrap();
+            assert!(matches!(err, ParseError::Invalid(_)), "{:?}", args);
+        }
+        for args in [
+            &["k", "v", "NX", "XX"][..],
+            &["k", "v", "EX", "1", "PX", "1"],
+            &["k", "v", "EX", "1", "KEEPTTL"],
+            &["k", "v", "GET"],
+        ] {
+            let err = set(args).err().unwrap();
+            assert!(matches!(err, ParseError::Syntax), "{:?}", args);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "already defined")]
+    fn names_cannot_be_registered_twice() {
+        struct Ping;
+        impl CommandHandler for Ping {
+            fn name(&self) -> &'static str {
+                "ping"
+            }
+
+            fn arity(&self) -> i64 {
+                -1
+            }
+
+            fn flags(&self) -> &'static [Flag] {
+                &[Flag::Fast]
+            }
+
+            fn execute<'a>(
+                &'a self,
+                _db: Db<'a>,
+                _args: Parse,
+            ) -> BoxFuture<'a, Result<Frame, CommandError>> {
+                Box::pin(async { Ok(Frame::Simple("PONG".to_string())) })
+            }
+        }
+        CommandTable::new().register(Arc::new(Ping));
+    }
+}
@@ -0,0 +1,476 @@
+use crate::cmd::Command;
+use crate::db::{Layout, LockedShards, Paused, ShardedDb};
+use crate::error::CommandError;
+use crate::frame::Frame;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// MULTI / EXEC state of one connection.
+///
+/// Commands sent after MULTI are queued rather than run. EXEC locks the
+/// shards of every queued and watched key at once, through `Layout::lock`,
+/// so the whole batch runs without any other client seeing it half done,
+/// whichever shards the keys live on. Databases that queued commands work
+/// on as a whole, such as with KEYS, FLUSHDB or SWAPDB, are paused instead.
+///
+/// WATCH is optimistic: it records the version of each key, and EXEC runs
+/// nothing if a write bumped one of them in the meantime. Keys may be
+/// watched in several databases, since SELECT is allowed before MULTI.
+pub struct Transaction {
+    dbs: Arc<[ShardedDb]>,
+    /// `Some` between MULTI and EXEC or DISCARD, with the database each
+    /// command runs against.
+    queued: Option<Vec<(usize, Command)>>,
+    /// The database commands are queued for, switched by a queued SELECT.
+    db: usize,
+    /// Set when a command could not be queued; EXEC then fails.
+    aborted: bool,
+    /// Watched databases and keys, and their versions when WATCH was called.
+    watched: Vec<(usize, String, u64)>,
+}
+
+impl Transaction {
+    pub fn new(dbs: Arc<[ShardedDb]>) -> Transaction {
+        Transaction {
+            dbs,
+            queued: None,
+            db: 0,
+            aborted: false,
+            watched: Vec::new(),
+        }
+    }
+
+    /// Whether commands are being queued instead of run.
+    pub fn is_queuing(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    /// Starts queuing commands for database `selected`.
+    pub fn multi(&mut self, selected: usize) -> Frame {
+        self.queued = Some(Vec::new());
+        self.db = selected;
+        self.aborted = false;
+        Frame::Simple("OK".to_string())
+    }
+
+    /// Handles a command received between MULTI and EXEC. A command that
+    /// fails to parse or is not allowed makes EXEC fail, as in Redis.
+    ///
+    /// The commands after a SELECT are for the database it names, if there
+    /// is one; EXEC still runs the SELECT itself, to change the database of
+    /// the connection or to reply with its error.
+    pub fn queue(&mut self, cmd: Result<Command, CommandError>) -> Frame {
+        let queued = self.queued.as_mut().expect("no transaction to queue in");
+        match cmd {
+            Ok(Command::Multi) => Frame::Error("ERR MULTI calls can not be nested".to_string()),
+            Ok(Command::Watch { .. }) => {
+                Frame::Error("ERR WATCH inside MULTI is not allowed".to_string())
+            }
+            Ok(cmd) if !cmd.is_allowed_in_transaction() => {
+                self.aborted = true;
+                CommandError::NotAllowedInTransaction.to_frame()
+            }
+            Ok(cmd) => {
+                if let Command::Select { db } = cmd {
+                    if db < self.dbs.len() {
+                        self.db = db;
+                    }
+                }
+                queued.push((self.db, cmd));
+                Frame::Simple("QUEUED".to_string())
+            }
+            Err(err) => {
+                self.aborted = true;
+                err.to_frame()
+            }
+        }
+    }
+
+    pub fn watch(&mut self, db: usize, keys: Vec<String>) -> Frame {
+        for key in keys {
+            if self
+                .watched
+                .iter()
+                .any(|(d, watched, _)| *d == db && *watched == key)
+            {
+                continue;
+            }
+            let version = self.dbs[db].with_shard(&key, |shard| shard.watch(&key));
+            self.watched.push((db, key, version));
+        }
+        Frame::Simple("OK".to_string())
+    }
+
+    pub fn unwatch(&mut self) -> Frame {
+        for (db, key, _) in self.watched.drain(..) {
+            self.dbs[db].with_shard(&key, |shard| shard.unwatch(&key));
+        }
+        Frame::Simple("OK".to_string())
+    }
+
+    pub fn discard(&mut self) -> Frame {
+        if self.queued.take().is_none() {
+            return Frame::Error("ERR DISCARD without MULTI".to_string());
+        }
+        self.unwatch()
+    }
+
+    /// Runs the queued commands with `apply`, given the database each one
+    /// is for, all under the same locks.
+    ///
+    /// Replies with an array of their replies, or with a null if a watched
+    /// key was written to since WATCH.
+    pub async fn exec(
+        &mut self,
+        mut apply: impl FnMut(usize, Command, &mut Held<'_, '_>) -> Frame,
+    ) -> Frame {
+        let queued = match self.queued.take() {
+            Some(queued) => queued,
+            None => return Frame::Error("ERR EXEC without MULTI".to_string()),
+        };
+        if std::mem::take(&mut self.aborted) {
+            self.unwatch();
+            return Frame::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            );
+        }
+
+        let needs = needs(&queued, &self.watched, self.dbs.len());
+        // Swapping databases needs them not to be resharding. Freezing
+        // comes before any other lock, in database order.
+        let mut frozen = Vec::new();
+        for (&db, need) in &needs {
+            if need.frozen {
+                frozen.push(self.dbs[db].freeze().await);
+            }
+        }
+        let reply = {
+            // Every layout is taken, or database paused, before any shard
+            // is locked, in database order, as MOVE does.
+            let mut taken: Vec<Taken<'_>> = needs
+                .iter()
+                .map(|(&db, need)| match need.paused {
+                    true => Taken::Paused(self.dbs[db].pause()),
+                    false => Taken::Layout(self.dbs[db].layout()),
+                })
+                .collect();
+            let locks = taken
+                .iter_mut()
+                .zip(needs.values())
+                .map(|(taken, need)| match taken {
+                    Taken::Layout(layout) => Lock::Shards(layout.lock(&need.keys)),
+                    Taken::Paused(paused) => Lock::Paused(paused),
+                })
+                .collect();
+            let mut held = Held {
+                dbs: needs.keys().copied().collect(),
+                locks,
+            };
+            let changed = self.watched.iter().any(|(db, key, version)| {
+                held.get(*db)
+                    .with_keys([key], |shards| shards.get(key).1.version(key))
+                    != *version
+            });
+            if changed {
+                Frame::Null
+            } else {
+                let replies = queued
+                    .into_iter()
+                    .map(|(db, cmd)| apply(db, cmd, &mut held));
+                Frame::Array(replies.collect())
+            }
+        };
+        drop(frozen);
+        // The shards are unlocked by now, so unwatching can lock them again.
+        self.unwatch();
+        reply
+    }
+}
+
+/// What EXEC must hold of a database.
+#[derive(Default)]
+struct Need<'a> {
+    /// Keys whose shards are locked, unless `paused`.
+    keys: Vec<&'a str>,
+    paused: bool,
+    frozen: bool,
+}
+
+/// The databases the queued and watched keys live in, and how each must
+/// be held for the commands to run.
+fn needs<'a>(
+    queued: &'a [(usize, Command)],
+    watched: &'a [(usize, String, u64)],
+    num_dbs: usize,
+) -> BTreeMap<usize, Need<'a>> {
+    let mut needs: BTreeMap<usize, Need<'_>> = BTreeMap::new();
+    for (db, key, _) in watched {
+        needs.entry(*db).or_default().keys.push(key);
+    }
+    for (db, cmd) in queued {
+        match cmd {
+            Command::Keys { .. }
+            | Command::Scan { .. }
+            | Command::DbSize
+            | Command::RandomKey
+            | Command::FlushDb => needs.entry(*db).or_default().paused = true,
+            Command::FlushAll => {
+                for db in 0..num_dbs {
+                    needs.entry(db).or_default().paused = true;
+                }
+            }
+            Command::SwapDb { first, second } => {
+                for &db in [first, second].into_iter().filter(|&&db| db < num_dbs) {
+                    let need = needs.entry(db).or_default();
+                    need.paused = true;
+                    need.frozen = true;
+                }
+            }
+            // Selecting changes nothing, and `db` is already the new one.
+            Command::Select { .. } => {}
+            Command::Move { key, db: to } => {
+                needs.entry(*db).or_default().keys.push(key);
+                if *to < num_dbs {
+                    needs.entry(*to).or_default().keys.push(key);
+                }
+            }
+            cmd => needs.entry(*db).or_default().keys.extend(cmd.keys()),
+        }
+    }
+    needs
+}
+
+enum Taken<'a> {
+    Layout(Layout<'a>),
+    Paused(Paused<'a>),
+}
+
+/// The locks EXEC holds on each database a transaction uses, for the
+/// queued commands to run under.
+pub struct Held<'a, 'db> {
+    /// The databases held, in ascending order.
+    dbs: Vec<usize>,
+    locks: Vec<Lock<'a, 'db>>,
+}
+
+/// How EXEC holds one database.
+pub enum Lock<'a, 'db> {
+    /// The shards of the keys its queued commands use.
+    Shards(LockedShards<'a>),
+    /// The whole database, paused.
+    Paused(&'a mut Paused<'db>),
+}
+
+impl<'a, 'db> Held<'a, 'db> {
+    /// The lock on database `db`, which a queued or watched key lives in.
+    pub fn get(&mut self, db: usize) -> &mut Lock<'a, 'db> {
+        let index = self.dbs.binary_search(&db).expect("database not held");
+        &mut self.locks[index]
+    }
+
+    /// Database `db`, which a queued command works on as a whole.
+    pub fn paused(&mut self, db: usize) -> &mut Paused<'db> {
+        self.get(db).paused()
+    }
+
+    /// The locks on two different databases.
+    pub fn pair(
+        &mut self,
+        first: usize,
+        second: usize,
+    ) -> (&mut Lock<'a, 'db>, &mut Lock<'a, 'db>) {
+        let first = self.dbs.binary_search(&first).expect("database not held");
+        let second = self.dbs.binary_search(&second).expect("database not held");
+        assert_ne!(first, second, "the same database twice");
+        if first < second {
+            let (low, high) = self.locks.split_at_mut(second);
+            (&mut low[first], &mut high[0])
+        } else {
+            let (low, high) = self.locks.split_at_mut(first);
+            (&mut high[0], &mut low[second])
+        }
+    }
+}
+
+impl<'db> Lock<'_, 'db> {
+    /// Runs `f` on the shards of `keys`, which must be among the keys the
+    /// queued commands of this database use.
+    pub fn with_keys<K: AsRef<str>, T>(
+        &mut self,
+        keys: impl IntoIterator<Item = K>,
+        f: impl FnOnce(&mut LockedShards<'_>) -> T,
+    ) -> T {
+        match self {
+            Lock::Shards(shards) => f(shards),
+            Lock::Paused(paused) => f(&mut paused.lock(keys)),
+        }
+    }
+
+    pub fn paused(&mut self) -> &mut Paused<'db> {
+        match self {
+            Lock::Paused(paused) => paused,
+            Lock::Shards(_) => panic!("database not paused"),
+        }
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.unwatch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::new_test_db;
+
+    fn dbs() -> Arc<[ShardedDb]> {
+        (0..2).map(|_| new_test_db(4, Default::default())).collect()
+    }
+
+    fn incr(key: &str) -> Command {
+        Command::IncrBy {
+            key: key.to_string(),
+            delta: 1,
+        }
+    }
+
+    /// Runs the commands as writes, replying with their names.
+    fn run(db: usize, cmd: Command, held: &mut Held<'_, '_>) -> Frame {
+        held.get(db).with_keys(cmd.keys(), |shards| {
+            for key in cmd.keys() {
+                shards.get(key).1.touch(key);
+            }
+        });
+        Frame::Simple(cmd.name().to_string())
+    }
+
+    /// A write to `key` of `db` by another client.
+    fn write(dbs: &[ShardedDb], db: usize, key: &str) {
+        dbs[db].with_shard(key, |shard| shard.touch(key));
+    }
+
+    fn version(dbs: &[ShardedDb], db: usize, key: &str) -> u64 {
+        dbs[db].with_shard(key, |shard| shard.version(key))
+    }
+
+    fn error(message: &str) -> Frame {
+        Frame::Error(message.to_string())
+    }
+
+    #[tokio::test]
+    async fn nested_multi_and_watch_are_refused_without_aborting() {
+        let mut transaction = Transaction::new(dbs());
+        transaction.multi(0);
+        assert_eq!(
+            transaction.queue(Ok(Command::Multi)),
+            error("ERR MULTI calls can not be nested")
+        );
+        let watch = Command::Watch {
+            keys: vec!["a".to_string()],
+        };
+        assert_eq!(
+            transaction.queue(Ok(watch)),
+            error("ERR WATCH inside MULTI is not allowed")
+        );
+        assert_eq!(
+            transaction.queue(Ok(incr("a"))),
+            Frame::Simple("QUEUED".to_string())
+        );
+        assert_eq!(
+            transaction.exec(run).await,
+            Frame::Array(vec![Frame::Simple("incrby".to_string())])
+        );
+        assert!(!transaction.is_queuing());
+    }
+
+    #[tokio::test]
+    async fn errors_while_queuing_abort_exec() {
+        let dbs = dbs();
+        let mut transaction = Transaction::new(dbs.clone());
+        transaction.watch(0, vec!["a".to_string()]);
+        transaction.multi(0);
+        transaction.queue(Ok(incr("a")));
+        let unknown = || CommandError::UnknownCommand("nope".to_string());
+        assert_eq!(transaction.queue(Err(unknown())), unknown().to_frame());
+        transaction.queue(Ok(incr("b")));
+        assert_eq!(
+            transaction.exec(|_, _, _| panic!("nothing runs")).await,
+            error("EXECABORT Transaction discarded because of previous errors.")
+        );
+        // The keys are no longer watched.
+        write(&dbs, 0, "a");
+        assert_eq!(version(&dbs, 0, "a"), 0);
+
+        // The next transaction starts afresh.
+        transaction.multi(0);
+        transaction.queue(Ok(incr("a")));
+        assert_eq!(
+            transaction.queue(Ok(Command::Save)),
+            CommandError::NotAllowedInTransaction.to_frame()
+        );
+        assert_eq!(
+            transaction.exec(run).await,
+            error("EXECABORT Transaction discarded because of previous errors.")
+        );
+        transaction.multi(0);
+        transaction.queue(Ok(incr("a")));
+        assert!(matches!(transaction.exec(run).await, Frame::Array(_)));
+    }
+
+    #[tokio::test]
+    async fn exec_fails_after_a_write_to_a_watched_key() {
+        let dbs = dbs();
+        let mut transaction = Transaction::new(dbs.clone());
+        transaction.watch(0, vec!["a".to_string()]);
+        transaction.multi(0);
+        transaction.queue(Ok(incr("a")));
+        write(&dbs, 0, "a");
+        assert_eq!(
+            transaction.exec(|_, _, _| panic!("nothing runs")).await,
+            Frame::Null
+        );
+
+        // Keys watched in another database than the one EXEC runs in count
+        // too, and writes to the same key in other databases do not.
+        transaction.watch(1, vec!["a".to_string()]);
+        transaction.multi(0);
+        transaction.queue(Ok(incr("b")));
+        write(&dbs, 0, "a");
+        assert!(matches!(transaction.exec(run).await, Frame::Array(_)));
+        transaction.watch(1, vec!["a".to_string()]);
+        transaction.multi(0);
+        transaction.queue(Ok(incr("b")));
+        write(&dbs, 1, "a");
+        assert_eq!(
+            transaction.exec(|_, _, _| panic!("nothing runs")).await,
+            Frame::Null
+        );
+    }
+
+    #[tokio::test]
+    async fn discard_and_drop_unwatch() {
+        let dbs = dbs();
+        let mut transaction = Transaction::new(dbs.clone());
+        transaction.watch(0, vec!["a".to_string(), "a".to_string()]);
+        transaction.watch(1, vec!["b".to_string()]);
+        write(&dbs, 0, "a");
+        assert_eq!(version(&dbs, 0, "a"), 1);
+        transaction.multi(0);
+        transaction.discard();
+        assert_eq!(version(&dbs, 0, "a"), 0);
+        assert_eq!(version(&dbs, 1, "b"), 0);
+        assert_eq!(transaction.discard(), error("ERR DISCARD without MULTI"));
+
+        transaction.watch(1, vec!["b".to_string()]);
+        let mut other = Transaction::new(dbs.clone());
+        other.watch(1, vec!["b".to_string()]);
+        drop(transaction);
+        // Still watched by the other client.
+        write(&dbs, 1, "b");
+        assert_eq!(version(&dbs, 1, "b"), 1);
+        drop(other);
+        assert_eq!(version(&dbs, 1, "b"), 0);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::frame::Frame;
+use bytes::Bytes;
+use std::{fmt, str, vec};
+
+/// Cursor over the entries of a command frame.
+///
+/// Mirrors the `Parse` helper inside mini-redis, which is not exported, so
+/// that the server can understand commands mini-redis has no type for.
+#[derive(Clone, Debug)]
+pub struct Parse {
+    parts: vec::IntoIter<Frame>,
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The frame ran out of entries.
+    EndOfStream,
+
+    /// The frame had entries left after the command was fully parsed.
+    ExtraArguments,
+
+    /// An option was not recognized by the command.
+    Syntax,
+
+    /// An entry could not be read as an integer.
+    NotInteger,
+
+    /// An entry could not be read as a number, or was NaN.
+    NotFloat,
+
+    /// An argument was well formed but out of range for the command.
+    Invalid(&'static str),
+
+    /// Any other malformed input.
+    Other(String),
+}
+
+impl Parse {
+    /// Returns `Err` if `frame` is not an array frame.
+    pub fn new(frame: Frame) -> Result<Parse, ParseError> {
+        match frame {
+            Frame::Array(array) => Ok(Parse {
+                parts: array.into_iter(),
+            }),
+            frame => Err(format!("protocol error; expected array, got {:?}", frame).into()),
+        }
+    }
+
+    fn next(&mut self) -> Result<Frame, ParseError> {
+        self.parts.next().ok_or(ParseError::EndOfStream)
+    }
+
+    pub fn next_string(&mut self) -> Result<String, ParseError> {
+        match self.next()? {
+            Frame::Simple(s) => Ok(s),
+            Frame::Bulk(data) => str::from_utf8(&data[..])
+                .map(|s| s.to_string())
+                .map_err(|_| "protocol error; invalid string".into()),
+            frame => Err(format!(
+                "protocol error; expected simple frame or bulk frame, got {:?}",
+                frame
+            )
+            .into()),
+        }
+    }
+
+    pub fn next_bytes(&mut self) -> Result<Bytes, ParseError> {
+        match self.next()? {
+            Frame::Simple(s) => Ok(Bytes::from(s.into_bytes())),
+            Frame::Bulk(data) => Ok(data),
+            frame => Err(format!(
+                "protocol error; expected simple frame or bulk frame, got {:?}",
+                frame
+            )
+            .into()),
+        }
+    }
+
+    pub fn next_int(&mut self) -> Result<u64, ParseError> {
+        match self.next()? {
+            Frame::Integer(v) => u64::try_from(v).map_err(|_| ParseError::NotInteger),
+            Frame::Simple(data) => data.parse().map_err(|_| ParseError::NotInteger),
+            Frame::Bulk(data) => str::from_utf8(&data)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or(ParseError::NotInteger),
+            _ => Err(ParseError::NotInteger),
+        }
+    }
+
+    /// Like `next_int`, but accepts negative numbers.
+    pub fn next_signed(&mut self) -> Result<i64, ParseError> {
+        match self.next()? {
+            Frame::Integer(v) => Ok(v),
+            Frame::Simple(data) => data.parse().map_err(|_| ParseError::NotInteger),
+            Frame::Bulk(data) => str::from_utf8(&data)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or(ParseError::NotInteger),
+            _ => Err(ParseError::NotInteger),
+        }
+    }
+
+    /// Reads a floating point number. `inf` and `-inf` are accepted, NaN is not.
+    pub fn next_float(&mut self) -> Result<f64, ParseError> {
+        let value: f64 = match self.next()? {
+            Frame::Integer(v) => v as f64,
+            Frame::Simple(data) => data.parse().map_err(|_| ParseError::NotFloat)?,
+            Frame::Bulk(data) => str::from_utf8(&data)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or(ParseError::NotFloat)?,
+            _ => return Err(ParseError::NotFloat),
+        };
+        if value.is_nan() {
+            return Err(ParseError::NotFloat);
+        }
+        Ok(value)
+    }
+
+    /// Number of entries not read yet.
+    pub fn remaining(&self) -> usize {
+        self.parts.len()
+    }
+
+    /// Ensure there are no more entries in the array.
+    pub fn finish(&mut self) -> Result<(), ParseError> {
+        if self.parts.next().is_none() {
+            Ok(())
+        } else {
+            Err(ParseError::ExtraArguments)
+        }
+    }
+}
+
+impl From<String> for ParseError {
+    fn from(src: String) -> ParseError {
+        ParseError::Other(src)
+    }
+}
+
+impl From<&str> for ParseError {
+    fn from(src: &str) -> ParseError {
+        src.to_string().into()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::EndOfStream => "protocol error; unexpected end of stream".fmt(f),
+            ParseError::ExtraArguments => "protocol error; expected end of frame".fmt(f),
+            ParseError::Syntax => "syntax error".fmt(f),
+            ParseError::NotInteger => "protocol error; invalid number".fmt(f),
+            ParseError::NotFloat => "protocol error; invalid float".fmt(f),
+            ParseError::Invalid(msg) => msg.fmt(f),
+            ParseError::Other(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
@@ -0,0 +1,370 @@
+use crate::acl::Acl;
+use crate::cmd::Command;
+use crate::connection::Connection;
+use crate::error::CommandError;
+use crate::frame::Frame;
+use crate::glob::glob_match;
+use crate::module::CommandTable;
+use crate::shutdown::Shutdown;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_official_tutorial_code_minis::config::LagPolicy;
+use tokio_stream::{Stream, StreamExt, StreamMap};
+use tracing::warn;
+
+/// Registry of the broadcast senders for every channel and pattern that has
+/// at least one subscriber.
+#[derive(Clone)]
+pub struct PubSub {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    capacity: usize,
+    lag_policy: LagPolicy,
+    channels: Mutex<HashMap<String, broadcast::Sender<Bytes>>>,
+    /// Pattern subscribers also need the name of the channel a message was
+    /// published to.
+    patterns: Mutex<HashMap<String, broadcast::Sender<(String, Bytes)>>>,
+}
+
+impl PubSub {
+    pub fn new(capacity: usize, lag_policy: LagPolicy) -> PubSub {
+        PubSub {
+            inner: Arc::new(Inner {
+                capacity,
+                lag_policy,
+                channels: Mutex::new(HashMap::new()),
+                patterns: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Returns the number of subscribers the message was delivered to.
+    pub fn publish(&self, channel: &str, message: Bytes) -> usize {
+        let mut receivers = self
+            .inner
+            .channels
+            .lock()
+            .unwrap()
+            .get(channel)
+            .and_then(|tx| tx.send(message.clone()).ok())
+            .unwrap_or(0);
+
+        for (pattern, tx) in self.inner.patterns.lock().unwrap().iter() {
+            if glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                receivers += tx.send((channel.to_string(), message.clone())).unwrap_or(0);
+            }
+        }
+        receivers
+    }
+
+    fn subscribe(&self, channel: &str) -> broadcast::Receiver<Bytes> {
+        let mut channels = self.inner.channels.lock().unwrap();
+        match channels.get(channel) {
+            Some(tx) => tx.subscribe(),
+            None => {
+                let (tx, rx) = broadcast::channel(self.inner.capacity);
+                channels.insert(channel.to_string(), tx);
+                rx
+            }
+        }
+    }
+
+    fn psubscribe(&self, pattern: &str) -> broadcast::Receiver<(String, Bytes)> {
+        let mut patterns = self.inner.patterns.lock().unwrap();
+        match patterns.get(pattern) {
+            Some(tx) => tx.subscribe(),
+            None => {
+                let (tx, rx) = broadcast::channel(self.inner.capacity);
+                patterns.insert(pattern.to_string(), tx);
+                rx
+            }
+        }
+    }
+
+    /// Drops the senders of a channel and a pattern nobody listens to
+    /// anymore. Must be called after the receiver has been dropped.
+    fn release(&self, channel: Option<&str>, pattern: Option<&str>) {
+        if let Some(channel) = channel {
+            let mut channels = self.inner.channels.lock().unwrap();
+            if channels
+                .get(channel)
+                .is_some_and(|tx| tx.receiver_count() == 0)
+            {
+                channels.remove(channel);
+            }
+        }
+        if let Some(pattern) = pattern {
+            let mut patterns = self.inner.patterns.lock().unwrap();
+            if patterns
+                .get(pattern)
+                .is_some_and(|tx| tx.receiver_count() == 0)
+            {
+                patterns.remove(pattern);
+            }
+        }
+    }
+}
+
+/// A message published to `channel`, or the number of messages missed.
+type Delivery = Result<(String, Bytes), u64>;
+
+type Messages = Pin<Box<dyn Stream<Item = Delivery> + Send>>;
+
+/// The channels and patterns a connection is subscribed to.
+///
+/// Acknowledgements and messages are push frames, which RESP2 connections
+/// get as plain arrays. Those can only send pub/sub commands while
+/// subscribed, through `subscriber_session`; RESP3 connections tell pushes
+/// from replies, so they keep running other commands in between.
+pub struct Subscriptions {
+    pubsub: PubSub,
+    channels: StreamMap<String, Messages>,
+    patterns: StreamMap<String, Messages>,
+}
+
+impl Subscriptions {
+    pub fn new(pubsub: &PubSub) -> Subscriptions {
+        Subscriptions {
+            pubsub: pubsub.clone(),
+            channels: StreamMap::new(),
+            patterns: StreamMap::new(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Applies a (P)SUBSCRIBE or (P)UNSUBSCRIBE command, and returns the
+    /// acknowledgements to send, one per channel or pattern.
+    pub fn apply(&mut self, cmd: Command) -> Vec<Frame> {
+        let mut acks = Vec::new();
+        match cmd {
+            Command::Subscribe { channels } => {
+                for channel in channels {
+                    if !self.channels.contains_key(&channel) {
+                        let rx = self.pubsub.subscribe(&channel);
+                        self.channels
+                            .insert(channel.clone(), channel_messages(channel.clone(), rx));
+                    }
+                    acks.push(self.ack("subscribe", Some(&channel)));
+                }
+            }
+            Command::PSubscribe { patterns } => {
+                for pattern in patterns {
+                    if !self.patterns.contains_key(&pattern) {
+                        let rx = self.pubsub.psubscribe(&pattern);
+                        self.patterns.insert(pattern.clone(), pattern_messages(rx));
+                    }
+                    acks.push(self.ack("psubscribe", Some(&pattern)));
+                }
+            }
+            Command::Unsubscribe { channels } => {
+                let channels = if channels.is_empty() {
+                    self.channels.keys().cloned().collect()
+                } else {
+                    channels
+                };
+                for channel in channels {
+                    if self.channels.remove(&channel).is_some() {
+                        self.pubsub.release(Some(&channel), None);
+                    }
+                    acks.push(self.ack("unsubscribe", Some(&channel)));
+                }
+                if acks.is_empty() {
+                    acks.push(self.ack("unsubscribe", None));
+                }
+            }
+            Command::PUnsubscribe { patterns } => {
+                let patterns = if patterns.is_empty() {
+                    self.patterns.keys().cloned().collect()
+                } else {
+                    patterns
+                };
+                for pattern in patterns {
+                    if self.patterns.remove(&pattern).is_some() {
+                        self.pubsub.release(None, Some(&pattern));
+                    }
+                    acks.push(self.ack("punsubscribe", Some(&pattern)));
+                }
+                if acks.is_empty() {
+                    acks.push(self.ack("punsubscribe", None));
+                }
+            }
+            cmd => panic!("not a pub/sub command: {}", cmd.name()),
+        }
+        acks
+    }
+
+    /// Waits for the next message to push to the client.
+    ///
+    /// Fails, with the error to send before closing the connection, if the
+    /// client fell behind and the lag policy is to disconnect it.
+    pub async fn next_message(&mut self) -> Result<Frame, Frame> {
+        loop {
+            let delivery = tokio::select! {
+                Some((_, delivery)) = self.channels.next() => delivery.map(|(channel, message)| {
+                    Frame::Push(vec![bulk("message"), bulk(&channel), Frame::Bulk(message)])
+                }),
+                Some((pattern, delivery)) = self.patterns.next() => delivery.map(|(channel, message)| {
+                    Frame::Push(vec![
+                        bulk("pmessage"),
+                        bulk(&pattern),
+                        bulk(&channel),
+                        Frame::Bulk(message),
+                    ])
+                }),
+                else => std::future::pending().await,
+            };
+            match delivery {
+                Ok(frame) => return Ok(frame),
+                Err(missed) => match self.pubsub.inner.lag_policy {
+                    LagPolicy::Skip => warn!("subscriber lagged, skipped {} messages", missed),
+                    LagPolicy::Disconnect => {
+                        return Err(Frame::Error(format!(
+                            "ERR subscriber fell {} messages behind, closing connection",
+                            missed
+                        )));
+                    }
+                },
+            }
+        }
+    }
+
+    fn ack(&self, kind: &str, name: Option<&str>) -> Frame {
+        Frame::Push(vec![
+            bulk(kind),
+            name.map_or(Frame::Null, bulk),
+            Frame::Integer(self.count() as i64),
+        ])
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        let channels: Vec<String> = self.channels.keys().cloned().collect();
+        for channel in channels {
+            self.channels.remove(&channel);
+            self.pubsub.release(Some(&channel), None);
+        }
+        let patterns: Vec<String> = self.patterns.keys().cloned().collect();
+        for pattern in patterns {
+            self.patterns.remove(&pattern);
+            self.pubsub.release(None, Some(&pattern));
+        }
+    }
+}
+
+/// How a subscriber session ended.
+pub enum SessionEnd {
+    /// The client unsubscribed from everything and may send commands again.
+    Unsubscribed,
+    /// The connection must be closed.
+    Closed,
+}
+
+/// Runs a RESP2 connection in subscriber mode, starting with the SUBSCRIBE
+/// or PSUBSCRIBE command `first`.
+///
+/// Messages and further (P)SUBSCRIBE and (P)UNSUBSCRIBE commands are served
+/// concurrently through `select!` until the client has no subscription
+/// left, or until the server shuts down. Those commands are checked against
+/// the ACL of `user`, like any other.
+pub async fn subscriber_session(
+    connection: &mut Connection,
+    subscriptions: &mut Subscriptions,
+    (commands, acl): (&CommandTable, &Acl),
+    user: Option<&str>,
+    shutdown: &mut Shutdown,
+    first: Command,
+) -> mini_redis::Result<SessionEnd> {
+    reply(connection, subscriptions, first).await?;
+    while subscriptions.count() > 0 {
+        tokio::select! {
+            message = subscriptions.next_message() => match message {
+                Ok(frame) => connection.write_frame(&frame).await?,
+                Err(reply) => {
+                    connection.write_frame(&reply).await?;
+                    return Ok(SessionEnd::Closed);
+                }
+            },
+            frame = connection.read_frame() => {
+                let frame = match frame? {
+                    Some(frame) => frame,
+                    None => return Ok(SessionEnd::Closed),
+                };
+                let cmd = Command::from_frame_named(frame, commands).and_then(|(name, cmd)| {
+                    acl.check(user.ok_or(CommandError::NoAuth)?, &name, &cmd)?;
+                    Ok(cmd)
+                });
+                match cmd {
+                    Ok(cmd) => reply(connection, subscriptions, cmd).await?,
+                    Err(err) => connection.write_frame(&err.to_frame()).await?,
+                }
+            }
+            _ = shutdown.recv() => return Ok(SessionEnd::Closed),
+        }
+    }
+    Ok(SessionEnd::Unsubscribed)
+}
+
+/// Answers a command sent in subscriber mode.
+async fn reply(
+    connection: &mut Connection,
+    subscriptions: &mut Subscriptions,
+    cmd: Command,
+) -> mini_redis::Result<()> {
+    match cmd {
+        cmd @ (Command::Subscribe { .. }
+        | Command::PSubscribe { .. }
+        | Command::Unsubscribe { .. }
+        | Command::PUnsubscribe { .. }) => {
+            for ack in subscriptions.apply(cmd) {
+                connection.write_frame(&ack).await?;
+            }
+        }
+        Command::Ping { .. } => {
+            connection
+                .write_frame(&Frame::Array(vec![bulk("pong"), bulk("")]))
+                .await?;
+        }
+        cmd => {
+            let err = CommandError::NotAllowedInSubscriberMode(cmd.name().to_string());
+            connection.write_frame(&err.to_frame()).await?;
+        }
+    }
+    Ok(())
+}
+
+fn channel_messages(channel: String, mut rx: broadcast::Receiver<Bytes>) -> Messages {
+    Box::pin(async_stream::stream! {
+        loop {
+            match rx.recv().await {
+                Ok(message) => yield Ok((channel.clone(), message)),
+                Err(RecvError::Lagged(missed)) => yield Err(missed),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+fn pattern_messages(mut rx: broadcast::Receiver<(String, Bytes)>) -> Messages {
+    Box::pin(async_stream::stream! {
+        loop {
+            match rx.recv().await {
+                Ok(message) => yield Ok(message),
+                Err(RecvError::Lagged(missed)) => yield Err(missed),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+fn bulk(s: &str) -> Frame {
+    Frame::Bulk(Bytes::copy_from_slice(s.as_bytes()))
+}
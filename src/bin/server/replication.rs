@@ -0,0 +1,821 @@
+use crate::aof;
+use crate::cmd::{Command, ReplConf};
+use crate::codec::{self, RespCodec};
+use crate::connection::Connection;
+use crate::db::ShardedDb;
+use crate::frame::Frame;
+use crate::module::CommandTable;
+use crate::shutdown::Shutdown;
+use crate::snapshot;
+use bytes::Bytes;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::{self, JoinHandle};
+use tokio::time::{Interval, MissedTickBehavior};
+use tracing::info;
+
+/// How often a replica tells its primary the offset it has applied.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replication state of the server: its role, and on a primary, the
+/// backlog of recent writes replicas are streamed from.
+///
+/// The writes a primary applies form one stream, in the same commands the
+/// AOF holds. Offsets count bytes of that stream since `replid` began; a
+/// primary starts a new history whenever it changes role, as its offsets
+/// then no longer mean the same thing.
+///
+/// A replica first gets a copy of the whole data set, taken at a known
+/// offset, then every write after it. When its link drops it reconnects
+/// and asks to continue from the offset it reached, which only needs a new
+/// copy if the backlog no longer goes back that far.
+#[derive(Clone)]
+pub struct Replication {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    backlog_size: usize,
+    /// Set once the backlog exists, so that writes skip it until a replica
+    /// first connects.
+    active: AtomicBool,
+    /// Set while following a primary; such a server takes no writes from
+    /// clients.
+    replica: AtomicBool,
+    backlog: Mutex<Backlog>,
+    /// The offset at the end of the backlog, which replica streams wait on.
+    offset: watch::Sender<u64>,
+    state: Mutex<State>,
+}
+
+struct State {
+    replid: String,
+    role: Role,
+    /// Replicas streaming from this server, by an id of their session.
+    replicas: BTreeMap<u64, ReplicaInfo>,
+    next_replica: u64,
+}
+
+enum Role {
+    Primary,
+    Replica {
+        host: String,
+        port: u16,
+        /// The task copying from the primary, aborted on a role change.
+        task: JoinHandle<()>,
+        link: Link,
+    },
+}
+
+/// How a replica's link to its primary is doing, for INFO and resyncs.
+#[derive(Default)]
+struct Link {
+    up: bool,
+    /// The primary's history and the offset applied so far in it, once
+    /// the first sync is done.
+    replid: Option<String>,
+    offset: u64,
+    last_io: Option<Instant>,
+}
+
+struct ReplicaInfo {
+    addr: Option<SocketAddr>,
+    listening_port: Option<u16>,
+    /// Set once the replica has its copy of the data set and is streaming.
+    online: bool,
+    acked: u64,
+    last_ack: Instant,
+}
+
+/// The most recent part of the replication stream.
+#[derive(Default)]
+struct Backlog {
+    buf: VecDeque<u8>,
+    /// Offset of the byte after the last one in `buf`.
+    end: u64,
+    /// The database of the last write, if every replica knows it.
+    selected: Option<usize>,
+}
+
+impl Backlog {
+    fn start(&self) -> u64 {
+        self.end - self.buf.len() as u64
+    }
+
+    /// Appends `data`, dropping the oldest bytes beyond `size`.
+    fn push(&mut self, data: &[u8], size: usize) {
+        self.buf.extend(data);
+        if self.buf.len() > size {
+            self.buf.drain(..self.buf.len() - size);
+        }
+        self.end += data.len() as u64;
+    }
+
+    /// The stream from `offset` on, or `None` if the backlog does not cover
+    /// it.
+    fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.start() || offset > self.end {
+            return None;
+        }
+        let skip = (offset - self.start()) as usize;
+        let (front, back) = self.buf.as_slices();
+        let mut data = Vec::with_capacity(self.buf.len() - skip);
+        if skip < front.len() {
+            data.extend_from_slice(&front[skip..]);
+            data.extend_from_slice(back);
+        } else {
+            data.extend_from_slice(&back[skip - front.len()..]);
+        }
+        Some(data)
+    }
+}
+
+impl Replication {
+    pub fn new(backlog_size: usize) -> Replication {
+        Replication {
+            inner: Arc::new(Inner {
+                backlog_size,
+                active: AtomicBool::new(false),
+                replica: AtomicBool::new(false),
+                backlog: Mutex::new(Backlog::default()),
+                offset: watch::Sender::new(0),
+                state: Mutex::new(State {
+                    replid: random_id(),
+                    role: Role::Primary,
+                    replicas: BTreeMap::new(),
+                    next_replica: 0,
+                }),
+            }),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.inner.replica.load(Ordering::Relaxed)
+    }
+
+    /// Whether writes must be fed to the backlog.
+    pub fn is_active(&self) -> bool {
+        self.inner.active.load(Ordering::Relaxed)
+    }
+
+    /// Records a write to database `db` in the backlog, given as the
+    /// arguments of the command that reproduces it. Like `Aof::feed`, this
+    /// must be called while the shard lock of the key is held.
+    pub fn feed(&self, db: usize, args: &[&[u8]]) {
+        if !self.is_active() {
+            return;
+        }
+        let end = {
+            let mut backlog = self.inner.backlog.lock().unwrap();
+            let mut data = Vec::new();
+            aof::select(&mut data, &mut backlog.selected, db);
+            aof::encode(&mut data, args);
+            backlog.push(&data, self.inner.backlog_size);
+            backlog.end
+        };
+        self.inner
+            .offset
+            .send_modify(|offset| *offset = (*offset).max(end));
+    }
+
+    /// Starts following the primary at `host` and `port`, in `task`,
+    /// which must call back `link_up`, `applied` and `link_down`.
+    ///
+    /// Returns `false`, and aborts `task`, if that primary is already the
+    /// one being followed.
+    pub fn follow(&self, host: String, port: u16, task: JoinHandle<()>) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        if let Role::Replica {
+            host: current,
+            port: current_port,
+            ..
+        } = &state.role
+        {
+            if *current == host && *current_port == port {
+                task.abort();
+                return false;
+            }
+        }
+        info!("Replicating {}:{}", host, port);
+        let link = Link::default();
+        let old = std::mem::replace(
+            &mut state.role,
+            Role::Replica {
+                host,
+                port,
+                task,
+                link,
+            },
+        );
+        if let Role::Replica { task, .. } = old {
+            task.abort();
+        }
+        self.inner.replica.store(true, Ordering::Relaxed);
+        self.new_history(&mut state);
+        true
+    }
+
+    /// Stops following the primary, if any, and starts taking writes from
+    /// clients, keeping the data set as it is.
+    pub fn promote(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        if let Role::Replica { task, .. } = std::mem::replace(&mut state.role, Role::Primary) {
+            info!("No longer a replica, taking writes");
+            task.abort();
+            self.inner.replica.store(false, Ordering::Relaxed);
+            self.new_history(&mut state);
+        }
+    }
+
+    /// Drops the backlog and picks a new replid, which ends the streams of
+    /// any connected replicas.
+    fn new_history(&self, state: &mut State) {
+        state.replid = random_id();
+        self.inner.active.store(false, Ordering::Relaxed);
+        *self.inner.backlog.lock().unwrap() = Backlog::default();
+        self.inner.offset.send_modify(|offset| *offset = 0);
+    }
+
+    /// Where a replica's link should resume: the primary's replid and the
+    /// offset applied so far, or `None` if it needs a full copy.
+    pub fn resume_point(&self) -> Option<(String, u64)> {
+        match &self.inner.state.lock().unwrap().role {
+            Role::Replica { link, .. } => link.replid.clone().map(|id| (id, link.offset)),
+            Role::Primary => None,
+        }
+    }
+
+    pub fn link_up(&self, replid: String, offset: u64) {
+        self.update_link(|link| {
+            link.up = true;
+            link.replid = Some(replid);
+            link.offset = offset;
+            link.last_io = Some(Instant::now());
+        });
+    }
+
+    /// Records that the stream has been applied up to `offset`.
+    pub fn applied(&self, offset: u64) {
+        self.update_link(|link| {
+            link.offset = offset;
+            link.last_io = Some(Instant::now());
+        });
+    }
+
+    pub fn link_down(&self) {
+        self.update_link(|link| link.up = false);
+    }
+
+    fn update_link(&self, f: impl FnOnce(&mut Link)) {
+        if let Role::Replica { link, .. } = &mut self.inner.state.lock().unwrap().role {
+            f(link);
+        }
+    }
+
+    /// The INFO replication section.
+    pub fn info(&self) -> String {
+        let state = self.inner.state.lock().unwrap();
+        let (start, end) = {
+            let backlog = self.inner.backlog.lock().unwrap();
+            (backlog.start(), backlog.end)
+        };
+        let mut out = String::new();
+        match &state.role {
+            Role::Primary => {
+                let _ = write!(
+                    out,
+                    "role:master\r\nconnected_slaves:{}\r\n",
+                    state.replicas.len()
+                );
+                for (i, replica) in state.replicas.values().enumerate() {
+                    let ip = replica
+                        .addr
+                        .map_or("?".to_string(), |addr| addr.ip().to_string());
+                    let port = replica
+                        .listening_port
+                        .or(replica.addr.map(|addr| addr.port()))
+                        .unwrap_or(0);
+                    let _ = write!(
+                        out,
+                        "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                        i,
+                        ip,
+                        port,
+                        if replica.online { "online" } else { "sync" },
+                        replica.acked,
+                        replica.last_ack.elapsed().as_secs(),
+                    );
+                }
+                let _ = write!(
+                    out,
+                    "master_replid:{}\r\nmaster_repl_offset:{}\r\n",
+                    state.replid, end
+                );
+            }
+            Role::Replica {
+                host, port, link, ..
+            } => {
+                let last_io = link
+                    .last_io
+                    .map_or(-1, |when| when.elapsed().as_secs() as i64);
+                let _ = write!(
+                    out,
+                    "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\n\
+                     master_link_status:{}\r\nmaster_last_io_seconds_ago:{}\r\n\
+                     master_sync_in_progress:{}\r\nslave_repl_offset:{}\r\n\
+                     master_replid:{}\r\nmaster_repl_offset:{}\r\n",
+                    host,
+                    port,
+                    if link.up { "up" } else { "down" },
+                    last_io,
+                    (!link.up) as u8,
+                    link.offset,
+                    link.replid.as_deref().unwrap_or("?"),
+                    link.offset,
+                );
+            }
+        }
+        let _ = write!(
+            out,
+            "repl_backlog_active:{}\r\nrepl_backlog_size:{}\r\n\
+             repl_backlog_first_byte_offset:{}\r\nrepl_backlog_histlen:{}\r\n",
+            self.is_active() as u8,
+            self.inner.backlog_size,
+            start,
+            end - start,
+        );
+        out
+    }
+
+    /// Decides how a replica sending PSYNC catches up: with the part of the
+    /// backlog after `offset`, or with a copy of `dbs` and the offset it was
+    /// taken at, still to be encoded.
+    fn start_sync(&self, replid: &str, offset: Option<u64>, dbs: &[ShardedDb]) -> CatchUp {
+        {
+            let state = self.inner.state.lock().unwrap();
+            if let Some(offset) = offset.filter(|_| replid == state.replid && self.is_active()) {
+                if let Some(data) = self.inner.backlog.lock().unwrap().since(offset) {
+                    return CatchUp::Partial {
+                        replid: state.replid.clone(),
+                        offset,
+                        data,
+                    };
+                }
+            }
+        }
+        // Under the pause no write is half applied, so the copy holds
+        // exactly the writes before `offset`, and writes from now on reach
+        // the backlog. The pause comes first: REPLICAOF takes the state
+        // lock while holding a layout. It only lasts as long as copying.
+        let paused: Vec<_> = dbs.iter().map(ShardedDb::pause).collect();
+        let state = self.inner.state.lock().unwrap();
+        self.inner.active.store(true, Ordering::Relaxed);
+        let offset = {
+            let mut backlog = self.inner.backlog.lock().unwrap();
+            // The new replica starts out in database 0, so the next write
+            // must say which one it is for.
+            backlog.selected = None;
+            backlog.end
+        };
+        CatchUp::Full {
+            replid: state.replid.clone(),
+            offset,
+            copy: snapshot::Copied::new(&paused),
+        }
+    }
+
+    /// The stream of `replid` after `offset`, or `None` if that history
+    /// ended or the backlog no longer covers it.
+    fn since(&self, replid: &str, offset: u64) -> Option<Vec<u8>> {
+        if self.inner.state.lock().unwrap().replid != replid {
+            return None;
+        }
+        self.inner.backlog.lock().unwrap().since(offset)
+    }
+
+    fn add_replica(&self, addr: Option<SocketAddr>, listening_port: Option<u16>) -> u64 {
+        let mut state = self.inner.state.lock().unwrap();
+        let id = state.next_replica;
+        state.next_replica += 1;
+        let info = ReplicaInfo {
+            addr,
+            listening_port,
+            online: false,
+            acked: 0,
+            last_ack: Instant::now(),
+        };
+        state.replicas.insert(id, info);
+        id
+    }
+
+    fn update_replica(&self, id: u64, f: impl FnOnce(&mut ReplicaInfo)) {
+        if let Some(replica) = self.inner.state.lock().unwrap().replicas.get_mut(&id) {
+            f(replica);
+        }
+    }
+
+    fn remove_replica(&self, id: u64) {
+        self.inner.state.lock().unwrap().replicas.remove(&id);
+    }
+}
+
+/// How a replica catches up after PSYNC.
+enum CatchUp {
+    /// From a copy of the data set taken at `offset`.
+    Full {
+        replid: String,
+        offset: u64,
+        copy: snapshot::Copied,
+    },
+    /// By continuing from `offset`, with `data` from the backlog.
+    Partial {
+        replid: String,
+        offset: u64,
+        data: Vec<u8>,
+    },
+}
+
+/// Serves a replica that sent PSYNC, until it goes away, falls further
+/// behind than the backlog reaches, or this server changes role.
+///
+/// The reply is `+FULLRESYNC <replid> <offset>` followed by a snapshot as a
+/// bulk string, or `+CONTINUE <replid>`. Either way, the stream of writes
+/// follows, and the replica sends `REPLCONF ACK <offset>` now and then.
+pub async fn serve_replica(
+    connection: &mut Connection,
+    (replication, commands): (&Replication, &CommandTable),
+    dbs: &[ShardedDb],
+    shutdown: &mut Shutdown,
+    addr: Option<SocketAddr>,
+    listening_port: Option<u16>,
+    (replid, offset): (String, Option<u64>),
+) -> mini_redis::Result<()> {
+    if replication.is_replica() {
+        let reply = Frame::Error("ERR a replica cannot be replicated from".to_string());
+        connection.write_frame(&reply).await?;
+        return Ok(());
+    }
+    let id = replication.add_replica(addr, listening_port);
+    let _registered = Registered { replication, id };
+
+    let parse = |frame| Command::from_frame(frame, commands);
+
+    // Subscribe before the backlog is read, so no write can slip between.
+    let mut end = replication.inner.offset.subscribe();
+    let (replid, mut sent) = match replication.start_sync(&replid, offset, dbs) {
+        CatchUp::Full {
+            replid,
+            offset,
+            copy,
+        } => {
+            info!("Full sync of replica {:?} at offset {}", addr, offset);
+            // Encoding walks every key, so it runs off the runtime threads,
+            // as `Snapshotter::dump` does.
+            let data = Bytes::from(task::spawn_blocking(move || copy.encode()).await?);
+            let reply = Frame::Simple(format!("FULLRESYNC {} {}", replid, offset));
+            connection.queue_frame(&reply).await?;
+            connection.write_frame(&Frame::Bulk(data)).await?;
+            (replid, offset)
+        }
+        CatchUp::Partial {
+            replid,
+            offset,
+            data,
+        } => {
+            info!("Partial sync of replica {:?} from offset {}", addr, offset);
+            let reply = Frame::Simple(format!("CONTINUE {}", replid));
+            connection.queue_frame(&reply).await?;
+            connection.write_raw(&data).await?;
+            (replid, offset + data.len() as u64)
+        }
+    };
+    replication.update_replica(id, |replica| replica.online = true);
+
+    loop {
+        end.borrow_and_update();
+        let data = match replication.since(&replid, sent) {
+            Some(data) => data,
+            None => {
+                info!("Dropping replica {:?}: its stream is gone", addr);
+                return Ok(());
+            }
+        };
+        if !data.is_empty() {
+            connection.write_raw(&data).await?;
+            sent += data.len() as u64;
+            continue;
+        }
+        tokio::select! {
+            res = end.changed() => if res.is_err() {
+                return Ok(());
+            },
+            frame = connection.read_frame() => match frame?.map(parse) {
+                Some(Ok(Command::ReplConf(ReplConf::Ack(offset)))) => {
+                    replication.update_replica(id, |replica| {
+                        replica.acked = offset;
+                        replica.last_ack = Instant::now();
+                    });
+                }
+                Some(_) => {}
+                None => return Ok(()),
+            },
+            _ = shutdown.recv() => return Ok(()),
+        }
+    }
+}
+
+/// Removes a replica from INFO once its session ends, however it ends.
+struct Registered<'a> {
+    replication: &'a Replication,
+    id: u64,
+}
+
+impl Drop for Registered<'_> {
+    fn drop(&mut self) {
+        self.replication.remove_replica(self.id);
+    }
+}
+
+/// A replica's connection to its primary, after the handshake.
+pub struct PrimaryLink {
+    connection: Connection,
+    replid: String,
+    /// Offset of the stream at `base`, the bytes read before it began.
+    offset: u64,
+    base: u64,
+    ack: Interval,
+}
+
+impl PrimaryLink {
+    /// Connects to the primary and asks for its stream after `resume`,
+    /// the history and offset this replica already has, if any.
+    ///
+    /// Returns the copy of the data set to load first, if the primary
+    /// could not just continue.
+    pub async fn connect(
+        host: &str,
+        port: u16,
+        listening_port: u16,
+        auth: Option<(&str, &str)>,
+        resume: Option<(String, u64)>,
+    ) -> mini_redis::Result<(PrimaryLink, Option<Bytes>)> {
+        let socket = TcpStream::connect((host, port)).await?;
+        socket.set_nodelay(true)?;
+        // The snapshot comes as one bulk string, as large as the data set.
+        let codec = RespCodec::new(usize::MAX, codec::DEFAULT_MAX_DEPTH);
+        let mut connection = Connection::new(socket, codec);
+
+        if let Some((user, password)) = auth {
+            request(
+                &mut connection,
+                &[b"AUTH", user.as_bytes(), password.as_bytes()],
+            )
+            .await?;
+        }
+
+        let port = listening_port.to_string();
+        request(
+            &mut connection,
+            &[b"REPLCONF", b"listening-port", port.as_bytes()],
+        )
+        .await?;
+        let (replid, offset) = match &resume {
+            Some((replid, offset)) => (replid.as_str(), offset.to_string()),
+            None => ("?", "-1".to_string()),
+        };
+        let reply = request(
+            &mut connection,
+            &[b"PSYNC", replid.as_bytes(), offset.as_bytes()],
+        )
+        .await?;
+        let words: Vec<&str> = reply.split(' ').collect();
+        let (replid, offset, data) = match (&words[..], resume) {
+            (["FULLRESYNC", replid, offset], _) => {
+                let offset = offset.parse()?;
+                let data = match connection.read_frame().await? {
+                    Some(Frame::Bulk(data)) => data,
+                    frame => return Err(format!("expected a snapshot, got {:?}", frame).into()),
+                };
+                (replid.to_string(), offset, Some(data))
+            }
+            (["CONTINUE", replid], Some((_, offset))) => (replid.to_string(), offset, None),
+            _ => return Err(format!("unexpected reply to PSYNC: {}", reply).into()),
+        };
+
+        let mut ack = tokio::time::interval(ACK_INTERVAL);
+        ack.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let link = PrimaryLink {
+            base: connection.bytes_read(),
+            connection,
+            replid,
+            offset,
+            ack,
+        };
+        Ok((link, data))
+    }
+
+    pub fn replid(&self) -> &str {
+        &self.replid
+    }
+
+    /// Offset of the stream up to the last command returned by
+    /// `next_command`.
+    pub fn offset(&self) -> u64 {
+        self.offset + self.connection.bytes_read() - self.base
+    }
+
+    /// Whether the next command has already been received.
+    pub fn has_buffered_command(&self) -> bool {
+        self.connection.has_buffered_frame()
+    }
+
+    /// The next write to apply, or `None` once the primary closes the
+    /// link. Acknowledges the offset reached while waiting.
+    pub async fn next_command(
+        &mut self,
+        commands: &CommandTable,
+    ) -> mini_redis::Result<Option<Command>> {
+        loop {
+            tokio::select! {
+                frame = self.connection.read_frame() => {
+                    return match frame? {
+                        Some(frame) => Ok(Some(Command::from_frame(frame, commands)?)),
+                        None => Ok(None),
+                    };
+                }
+                _ = self.ack.tick() => {
+                    let offset = self.offset().to_string();
+                    let ack = command_frame(&[b"REPLCONF", b"ACK", offset.as_bytes()]);
+                    self.connection.write_frame(&ack).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Sends a command during the handshake and returns its simple string
+/// reply.
+async fn request(connection: &mut Connection, args: &[&[u8]]) -> mini_redis::Result<String> {
+    connection.write_frame(&command_frame(args)).await?;
+    match connection.read_frame().await? {
+        Some(Frame::Simple(reply)) => Ok(reply),
+        Some(Frame::Error(err)) => Err(err.into()),
+        Some(frame) => Err(format!("unexpected reply {:?}", frame).into()),
+        None => Err("connection closed by the primary".into()),
+    }
+}
+
+fn command_frame(args: &[&[u8]]) -> Frame {
+    let args = args
+        .iter()
+        .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg)));
+    Frame::Array(args.collect())
+}
+
+/// 40 random hex digits, like a Redis replication or node id.
+pub fn random_id() -> String {
+    let mut id = String::new();
+    while id.len() < 40 {
+        let _ = write!(id, "{:016x}", RandomState::new().build_hasher().finish());
+    }
+    id.truncate(40);
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::new_test_db;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn backlog_serves_what_it_still_holds() {
+        let mut backlog = Backlog::default();
+        let mut stream = Vec::new();
+        let mut wrapped = false;
+        for i in 0..200u8 {
+            let data = [i; 3];
+            backlog.push(&data, 50);
+            stream.extend_from_slice(&data);
+            wrapped |= !backlog.buf.as_slices().1.is_empty();
+
+            let end = stream.len() as u64;
+            assert_eq!(backlog.end, end);
+            assert_eq!(backlog.start(), end.saturating_sub(50));
+            for offset in backlog.start()..=end {
+                let since = backlog.since(offset);
+                assert_eq!(since.as_deref(), Some(&stream[offset as usize..]));
+            }
+            if let Some(evicted) = backlog.start().checked_sub(1) {
+                assert_eq!(backlog.since(evicted), None);
+            }
+            assert_eq!(backlog.since(end + 1), None);
+        }
+        assert!(wrapped);
+    }
+
+    fn catch_up(replication: &Replication, replid: &str, offset: Option<u64>) -> CatchUp {
+        let dbs = [new_test_db(4, Default::default())];
+        replication.start_sync(replid, offset, &dbs)
+    }
+
+    #[test]
+    fn partial_resync_only_within_the_same_history_and_backlog() {
+        let replication = Replication::new(64);
+        // Nothing is kept until a first replica asks for a copy.
+        replication.feed(0, &[b"SET", b"a", b"1"]);
+        let CatchUp::Full { replid, offset, .. } = catch_up(&replication, "?", None) else {
+            panic!("a first sync is a full one");
+        };
+        assert_eq!(offset, 0);
+
+        replication.feed(0, &[b"DEL", b"a"]);
+        let expected = b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*2\r\n$3\r\nDEL\r\n$1\r\na\r\n";
+        let end = expected.len() as u64;
+        match catch_up(&replication, &replid, Some(0)) {
+            CatchUp::Partial { offset, data, .. } => {
+                assert_eq!(offset, 0);
+                assert_eq!(data, &expected[..]);
+            }
+            CatchUp::Full { .. } => panic!("the backlog covers offset 0"),
+        }
+        assert!(matches!(
+            catch_up(&replication, &replid, Some(end)),
+            CatchUp::Partial { data, .. } if data.is_empty()
+        ));
+        assert!(matches!(
+            catch_up(&replication, "other", Some(end)),
+            CatchUp::Full { .. }
+        ));
+        assert!(matches!(
+            catch_up(&replication, &replid, Some(end + 1)),
+            CatchUp::Full { .. }
+        ));
+
+        replication.feed(0, &[b"SET", b"a", b"2"]);
+        // The full syncs above made the next write select its database
+        // again, so that makes 50 more bytes, more than the backlog holds.
+        match catch_up(&replication, &replid, Some(0)) {
+            CatchUp::Full { offset, .. } => assert_eq!(offset, end + 50),
+            CatchUp::Partial { .. } => panic!("offset 0 was evicted"),
+        }
+    }
+
+    /// Plays a primary that answers the handshake with `psync_reply`, then
+    /// sends `stream`.
+    async fn primary(listener: &TcpListener, psync_reply: &str, stream: &[u8]) {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut connection = Connection::new(socket, RespCodec::default());
+        for reply in ["OK", psync_reply] {
+            connection.read_frame().await.unwrap().unwrap();
+            let reply = Frame::Simple(reply.to_string());
+            connection.write_frame(&reply).await.unwrap();
+        }
+        if psync_reply.starts_with("FULLRESYNC") {
+            let snapshot = Frame::Bulk("not counted in the stream".into());
+            connection.write_frame(&snapshot).await.unwrap();
+        }
+        connection.write_raw(stream).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replica_offset_counts_the_stream_bytes_applied() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let commands = CommandTable::new();
+        let del = b"*2\r\n$3\r\nDEL\r\n$1\r\na\r\n";
+        let stream = [&del[..], &del[..]].concat();
+        let len = del.len() as u64;
+
+        let (link, _) = tokio::join!(
+            PrimaryLink::connect("127.0.0.1", port, 1, None, None),
+            primary(&listener, "FULLRESYNC abc 100", &stream),
+        );
+        let (mut link, snapshot) = link.unwrap();
+        assert!(snapshot.is_some());
+        assert_eq!((link.replid(), link.offset()), ("abc", 100));
+        link.next_command(&commands).await.unwrap().unwrap();
+        assert_eq!(link.offset(), 100 + len);
+        link.next_command(&commands).await.unwrap().unwrap();
+        assert_eq!(link.offset(), 100 + 2 * len);
+
+        let resume = Some(("abc".to_string(), link.offset()));
+        let (link, _) = tokio::join!(
+            PrimaryLink::connect("127.0.0.1", port, 1, None, resume),
+            primary(&listener, "CONTINUE abc", del),
+        );
+        let (mut link, snapshot) = link.unwrap();
+        assert!(snapshot.is_none());
+        assert_eq!(link.offset(), 100 + 2 * len);
+        link.next_command(&commands).await.unwrap().unwrap();
+        assert_eq!(link.offset(), 100 + 3 * len);
+    }
+}
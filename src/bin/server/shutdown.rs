@@ -0,0 +1,42 @@
+use tokio::sync::watch;
+
+/// Listens for the server shutdown notice.
+///
+/// Every connection task owns one. The notice is a `watch` value flipping to
+/// `true`, so tasks that start listening late still see it.
+#[derive(Clone)]
+pub struct Shutdown {
+    notify: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn new(notify: watch::Receiver<bool>) -> Shutdown {
+        Shutdown { notify }
+    }
+
+    /// Waits until shutdown has been requested.
+    pub async fn recv(&mut self) {
+        // An error means the sender is gone, which only happens once main
+        // returns, so it is treated as a shutdown as well.
+        let _ = self.notify.wait_for(|&shutdown| shutdown).await;
+    }
+}
+
+/// Completes on SIGINT (ctrl-c) or, on Unix, SIGTERM.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -0,0 +1,506 @@
+use crate::db::{from_unix_ms, to_unix_ms, Entry, Paused, Shard, ShardedDb};
+use crate::value::{Value, ZSet};
+use bytes::{Buf, BufMut, Bytes};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::task;
+use tracing::{error, info};
+
+/// Snapshot file layout, all integers little endian:
+///
+/// ```text
+/// "TMRD" | version: u16 | (select | entry)* | 0xFF | crc32 of everything before: u32
+/// select: 0xFE | db: u32
+/// entry: type: u8 | expires_at (unix ms, 0 = never): u64 | key: blob | value
+/// blob:  len: u32 | bytes
+/// value: string: blob
+///        hash:   count: u32 | (field: blob | value: blob)*
+///        list:   count: u32 | blob*
+///        set:    count: u32 | blob*
+///        zset:   count: u32 | (score: f64 | member: blob)*
+/// ```
+///
+/// Entries belong to the database of the last select, or to database 0
+/// before the first one.
+///
+/// Version 1 files only have string entries, which are laid out the same
+/// way, and neither version 1 nor 2 has selects, so they are still read.
+const MAGIC: &[u8; 4] = b"TMRD";
+const VERSION: u16 = 3;
+const TYPE_STRING: u8 = 0x00;
+const TYPE_HASH: u8 = 0x01;
+const TYPE_LIST: u8 = 0x02;
+const TYPE_SET: u8 = 0x03;
+const TYPE_ZSET: u8 = 0x04;
+const OP_SELECT_DB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+
+/// Writes and loads point-in-time copies of the databases.
+///
+/// Cloning is cheap; every clone shares the dirty counter and the lock that
+/// keeps two saves from running at once.
+#[derive(Clone)]
+pub struct Snapshotter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    /// Writes since the last successful save.
+    dirty: AtomicU64,
+    saving: Arc<Mutex<()>>,
+}
+
+impl Snapshotter {
+    pub fn new(path: impl Into<PathBuf>) -> Snapshotter {
+        Snapshotter {
+            inner: Arc::new(Inner {
+                path: path.into(),
+                dirty: AtomicU64::new(0),
+                saving: Arc::new(Mutex::new(())),
+            }),
+        }
+    }
+
+    pub fn mark_dirty(&self) {
+        self.inner.dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Dumps `dbs` and waits for the file to be on disk.
+    pub async fn save(&self, dbs: &[ShardedDb]) -> io::Result<()> {
+        let _guard = self.inner.saving.lock().await;
+        self.dump(dbs).await
+    }
+
+    /// Starts a dump in the background. Returns `false` if a save is
+    /// already running.
+    pub fn bgsave(&self, dbs: &[ShardedDb]) -> bool {
+        let guard = match self.inner.saving.clone().try_lock_owned() {
+            Ok(guard) => guard,
+            Err(_) => return false,
+        };
+        let snapshotter = self.clone();
+        let dbs = dbs.to_vec();
+        tokio::spawn(async move {
+            let _guard = guard;
+            match snapshotter.dump(&dbs).await {
+                Ok(()) => info!("Background saving terminated with success"),
+                Err(err) => error!("Background saving error: {}", err),
+            }
+        });
+        true
+    }
+
+    async fn dump(&self, dbs: &[ShardedDb]) -> io::Result<()> {
+        let dirty = self.inner.dirty.load(Ordering::Relaxed);
+        let dbs = dbs.to_vec();
+        let path = self.inner.path.clone();
+        // Encoding walks every key and writing waits on the disk, so
+        // neither holds up the tasks of a runtime thread.
+        task::spawn_blocking(move || {
+            let buf = {
+                let _frozen: Vec<_> = dbs.iter().map(ShardedDb::blocking_freeze).collect();
+                encode(|f| {
+                    for (index, db) in dbs.iter().enumerate() {
+                        db.for_each_shard(|_, shard| f(index, shard));
+                    }
+                })
+            };
+            write_atomically(&path, &buf)
+        })
+        .await??;
+        self.inner.dirty.fetch_sub(dirty, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Loads the snapshot into `dbs`, returning the number of keys read.
+    ///
+    /// A missing file is not an error; the databases simply start empty.
+    pub async fn load(&self, dbs: &[ShardedDb]) -> io::Result<usize> {
+        let dbs = dbs.to_vec();
+        let path = self.inner.path.clone();
+        task::spawn_blocking(move || {
+            let data = match fs::read(path) {
+                Ok(data) => data,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+                Err(err) => return Err(err),
+            };
+            decode(&data, &dbs)
+        })
+        .await?
+    }
+}
+
+/// A copy of every live key of the databases, taken while they were
+/// paused, to be serialized once they are no longer.
+///
+/// Replicas get their first copy of the data set this way, lined up with
+/// the offset of the replication stream at the pause. Copying is much
+/// quicker than encoding, so it keeps the pause short.
+pub struct Copied {
+    /// Database, key, value and expiry in unix ms, 0 if none.
+    entries: Vec<(usize, String, Value, u64)>,
+}
+
+impl Copied {
+    pub fn new(paused: &[Paused<'_>]) -> Copied {
+        let mut entries = Vec::new();
+        for (index, paused) in paused.iter().enumerate() {
+            paused.for_each_shard(|_, shard| {
+                let now = Instant::now();
+                for (key, entry) in shard.iter_live(now) {
+                    let expires_at = entry.expires_at.map_or(0, |when| to_unix_ms(when, now));
+                    entries.push((index, key.clone(), entry.value.clone(), expires_at));
+                }
+            });
+        }
+        Copied { entries }
+    }
+
+    /// Serializes the copy in the snapshot file format.
+    pub fn encode(&self) -> Vec<u8> {
+        encode_entries(|f| {
+            for (db, key, value, expires_at) in &self.entries {
+                f(*db, key, value, *expires_at);
+            }
+        })
+    }
+}
+
+/// Serializes every shard `for_each_shard` visits, with the index of its
+/// database. Going through `ShardedDb::for_each_shard`, only one shard is
+/// locked at a time, and the layout must be frozen so no key is missed
+/// while moving between shards.
+fn encode(for_each_shard: impl FnOnce(&mut dyn FnMut(usize, &mut Shard))) -> Vec<u8> {
+    encode_entries(|f| {
+        for_each_shard(&mut |db, shard| {
+            let now = Instant::now();
+            for (key, entry) in shard.iter_live(now) {
+                let expires_at = entry.expires_at.map_or(0, |when| to_unix_ms(when, now));
+                f(db, key, &entry.value, expires_at);
+            }
+        })
+    })
+}
+
+/// Serializes the entries `for_each_entry` visits, given as their
+/// database, key, value and expiry in unix ms, 0 if none.
+fn encode_entries(
+    for_each_entry: impl FnOnce(&mut dyn FnMut(usize, &str, &Value, u64)),
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.put_slice(MAGIC);
+    buf.put_u16_le(VERSION);
+
+    let mut selected = None;
+    for_each_entry(&mut |db, key, value, expires_at| {
+        if selected != Some(db) {
+            buf.put_u8(OP_SELECT_DB);
+            buf.put_u32_le(db as u32);
+            selected = Some(db);
+        }
+        buf.put_u8(type_tag(value));
+        buf.put_u64_le(expires_at);
+        put_blob(&mut buf, key.as_bytes());
+        encode_value(&mut buf, value);
+    });
+
+    buf.put_u8(OP_EOF);
+    let checksum = crc32fast::hash(&buf);
+    buf.put_u32_le(checksum);
+    buf
+}
+
+/// Loads a snapshot held in memory, such as one sent by a primary, into
+/// `dbs`, returning the number of keys read.
+pub fn decode(data: &[u8], dbs: &[ShardedDb]) -> io::Result<usize> {
+    if data.len() < MAGIC.len() + 2 + 1 + 4 || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+    let (body, checksum) = data.split_at(data.len() - 4);
+    if crc32fast::hash(body) != (&checksum[..]).get_u32_le() {
+        return Err(invalid("checksum mismatch"));
+    }
+
+    let mut src = &body[MAGIC.len()..];
+    let version = src.get_u16_le();
+    if !(1..=VERSION).contains(&version) {
+        return Err(invalid(&format!(
+            "unsupported snapshot version {}",
+            version
+        )));
+    }
+
+    let now = Instant::now();
+    let mut loaded = 0;
+    let mut db = &dbs[0];
+    loop {
+        let tag = match read_u8(&mut src)? {
+            OP_EOF if src.is_empty() => return Ok(loaded),
+            OP_SELECT_DB if version > 2 => {
+                let index = read_u32(&mut src)? as usize;
+                db = dbs.get(index).ok_or_else(|| {
+                    invalid(&format!(
+                        "database {} is out of range, only {} are configured",
+                        index,
+                        dbs.len()
+                    ))
+                })?;
+                continue;
+            }
+            TYPE_STRING => TYPE_STRING,
+            tag if version > 1 && tag <= TYPE_ZSET => tag,
+            op => return Err(invalid(&format!("unexpected opcode {:#x}", op))),
+        };
+        let expires_at = match read_u64(&mut src)? {
+            0 => None,
+            ms => Some(from_unix_ms(ms, now)),
+        };
+        let key = String::from_utf8(read_blob(&mut src)?.to_vec())
+            .map_err(|_| invalid("key is not valid UTF-8"))?;
+        let value = decode_value(tag, &mut src)?;
+        let entry = Entry::new(value, expires_at);
+        if entry.is_expired(now) {
+            continue;
+        }
+        let layout = db.layout();
+        layout.lock([&key]).get(&key).1.insert_entry(key, entry);
+        loaded += 1;
+    }
+}
+
+fn type_tag(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => TYPE_STRING,
+        Value::Hash(_) => TYPE_HASH,
+        Value::List(_) => TYPE_LIST,
+        Value::Set(_) => TYPE_SET,
+        Value::ZSet(_) => TYPE_ZSET,
+    }
+}
+
+fn encode_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(value) => put_blob(buf, value),
+        Value::Hash(hash) => {
+            buf.put_u32_le(hash.len() as u32);
+            for (field, value) in hash {
+                put_blob(buf, field);
+                put_blob(buf, value);
+            }
+        }
+        Value::List(list) => {
+            buf.put_u32_le(list.len() as u32);
+            for item in list {
+                put_blob(buf, item);
+            }
+        }
+        Value::Set(set) => {
+            buf.put_u32_le(set.len() as u32);
+            for member in set {
+                put_blob(buf, member);
+            }
+        }
+        Value::ZSet(zset) => {
+            buf.put_u32_le(zset.len() as u32);
+            for (member, score) in zset.iter() {
+                buf.put_f64_le(score);
+                put_blob(buf, member);
+            }
+        }
+    }
+}
+
+fn decode_value(tag: u8, src: &mut &[u8]) -> io::Result<Value> {
+    if tag == TYPE_STRING {
+        return Ok(Value::String(read_blob(src)?));
+    }
+    let count = read_u32(src)?;
+    // Collections are never empty, so a zero count means a corrupt file.
+    if count == 0 {
+        return Err(invalid("empty collection"));
+    }
+    let mut value = match tag {
+        TYPE_HASH => Value::Hash(Default::default()),
+        TYPE_LIST => Value::List(Default::default()),
+        TYPE_SET => Value::Set(Default::default()),
+        _ => Value::ZSet(ZSet::default()),
+    };
+    for _ in 0..count {
+        match &mut value {
+            Value::Hash(hash) => {
+                hash.insert(read_blob(src)?, read_blob(src)?);
+            }
+            Value::List(list) => list.push_back(read_blob(src)?),
+            Value::Set(set) => {
+                set.insert(read_blob(src)?);
+            }
+            Value::ZSet(zset) => {
+                let score = f64::from_bits(read_u64(src)?);
+                zset.insert(read_blob(src)?, score);
+            }
+            Value::String(_) => unreachable!(),
+        }
+    }
+    Ok(value)
+}
+
+fn put_blob(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.put_u32_le(data.len() as u32);
+    buf.put_slice(data);
+}
+
+/// Writes to a temporary file first so a crash mid-write never replaces
+/// the previous snapshot with a truncated one.
+fn write_atomically(path: &Path, buf: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Background task that saves whenever at least `min_changes` writes
+/// happened during the last `period`.
+pub async fn save_periodically(
+    snapshotter: Snapshotter,
+    dbs: Vec<ShardedDb>,
+    period: Duration,
+    min_changes: u64,
+) {
+    let mut interval = tokio::time::interval(period);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if snapshotter.inner.dirty.load(Ordering::Relaxed) >= min_changes {
+            snapshotter.bgsave(&dbs);
+        }
+    }
+}
+
+fn read_u8(src: &mut &[u8]) -> io::Result<u8> {
+    if src.remaining() < 1 {
+        return Err(invalid("unexpected end of file"));
+    }
+    Ok(src.get_u8())
+}
+
+fn read_u64(src: &mut &[u8]) -> io::Result<u64> {
+    if src.remaining() < 8 {
+        return Err(invalid("unexpected end of file"));
+    }
+    Ok(src.get_u64_le())
+}
+
+fn read_u32(src: &mut &[u8]) -> io::Result<u32> {
+    if src.remaining() < 4 {
+        return Err(invalid("unexpected end of file"));
+    }
+    Ok(src.get_u32_le())
+}
+
+fn read_blob(src: &mut &[u8]) -> io::Result<Bytes> {
+    let len = read_u32(src)? as usize;
+    if src.remaining() < len {
+        return Err(invalid("unexpected end of file"));
+    }
+    Ok(src.copy_to_bytes(len))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{new_test_db, Ttl};
+    use std::collections::{HashMap, HashSet, VecDeque};
+
+    fn new_dbs() -> Vec<ShardedDb> {
+        (0..2).map(|_| new_test_db(4, Default::default())).collect()
+    }
+
+    fn with_key<T>(db: &ShardedDb, key: &str, f: impl FnOnce(&mut Shard) -> T) -> T {
+        let layout = db.layout();
+        let mut shards = layout.lock([key]);
+        f(shards.get(key).1)
+    }
+
+    fn insert(db: &ShardedDb, key: &str, value: Value) {
+        with_key(db, key, |shard| {
+            shard.value_or_insert(key, || value);
+        });
+    }
+
+    #[test]
+    fn every_type_and_deadline_survives_a_round_trip() {
+        let dbs = new_dbs();
+        let ttl = Duration::from_secs(100);
+        with_key(&dbs[0], "string", |shard| {
+            shard.set("string".to_string(), "value".into(), Some(ttl))
+        });
+        let hash = HashMap::from([("field".into(), "value".into())]);
+        insert(&dbs[0], "hash", Value::Hash(hash));
+        insert(
+            &dbs[0],
+            "list",
+            Value::List(VecDeque::from(["a".into(), "b".into()])),
+        );
+        insert(&dbs[1], "set", Value::Set(HashSet::from(["member".into()])));
+        let mut zset = ZSet::default();
+        zset.insert("member".into(), 1.5);
+        insert(&dbs[1], "zset", Value::ZSet(zset));
+
+        let data = encode(|f| {
+            for (index, db) in dbs.iter().enumerate() {
+                db.for_each_shard(|_, shard| f(index, shard));
+            }
+        });
+        let loaded = new_dbs();
+        assert_eq!(decode(&data, &loaded).unwrap(), 5);
+        assert_eq!((loaded[0].len(), loaded[1].len()), (3, 2));
+
+        with_key(&loaded[0], "string", |shard| {
+            assert_eq!(shard.get("string").unwrap(), Some("value".into()));
+            match shard.ttl("string") {
+                Ttl::Remaining(left) => assert!(left <= ttl && left > ttl / 2),
+                ttl => panic!("unexpected {:?}", ttl),
+            }
+        });
+        with_key(&loaded[0], "hash", |shard| {
+            let hash = shard.value("hash").unwrap().as_hash().unwrap();
+            assert_eq!(hash.get(&b"field"[..]), Some(&"value".into()));
+            assert_eq!(shard.ttl("hash"), Ttl::Persistent);
+        });
+        with_key(&loaded[0], "list", |shard| {
+            let list = shard.value("list").unwrap().as_list().unwrap();
+            assert_eq!(list, &["a", "b"].map(Bytes::from));
+        });
+        with_key(&loaded[1], "set", |shard| {
+            let set = shard.value("set").unwrap().as_set().unwrap();
+            assert_eq!(set, &HashSet::from(["member".into()]));
+        });
+        with_key(&loaded[1], "zset", |shard| {
+            let zset = shard.value("zset").unwrap().as_zset().unwrap();
+            assert_eq!(zset.score(b"member"), Some(1.5));
+            assert_eq!(zset.len(), 1);
+        });
+    }
+
+    #[test]
+    fn corrupted_snapshots_are_refused() {
+        let dbs = new_dbs();
+        insert(&dbs[0], "key", Value::String("value".into()));
+        let mut data = encode(|f| dbs[0].for_each_shard(|_, shard| f(0, shard)));
+        let last = data.len() - 5;
+        data[last] ^= 1;
+        assert!(decode(&data, &new_dbs()).is_err());
+        assert!(decode(b"not a snapshot", &new_dbs()).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::error::CommandError;
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::ops::Range;
+
+/// A value stored under a key.
+///
+/// Collections never stay empty: the command that removes their last
+/// element also removes the key, as Redis does.
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(Bytes),
+    Hash(HashMap<Bytes, Bytes>),
+    List(VecDeque<Bytes>),
+    Set(HashSet<Bytes>),
+    ZSet(ZSet),
+}
+
+impl Value {
+    /// The name TYPE replies with.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Hash(_) => "hash",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+        }
+    }
+
+    pub fn as_string(&self) -> Result<&Bytes, CommandError> {
+        match self {
+            Value::String(value) => Ok(value),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_hash(&mut self) -> Result<&mut HashMap<Bytes, Bytes>, CommandError> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_list(&mut self) -> Result<&mut VecDeque<Bytes>, CommandError> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_set(&mut self) -> Result<&mut HashSet<Bytes>, CommandError> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn as_zset(&mut self) -> Result<&mut ZSet, CommandError> {
+        match self {
+            Value::ZSet(zset) => Ok(zset),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    /// Whether this is a collection without elements.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::Hash(hash) => hash.is_empty(),
+            Value::List(list) => list.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::ZSet(zset) => zset.len() == 0,
+        }
+    }
+
+    /// Approximate number of bytes held, counting only the payload.
+    ///
+    /// This runs after every write, so only the first `SIZE_SAMPLE`
+    /// elements of a collection are measured, and the others assumed to be
+    /// of the same size on average.
+    pub fn approx_size(&self) -> usize {
+        match self {
+            Value::String(value) => value.len(),
+            Value::Hash(hash) => estimate(hash.len(), hash.iter().map(|(f, v)| f.len() + v.len())),
+            Value::List(list) => estimate(list.len(), list.iter().map(Bytes::len)),
+            Value::Set(set) => estimate(set.len(), set.iter().map(Bytes::len)),
+            Value::ZSet(zset) => estimate(zset.len(), zset.iter().map(|(m, _)| m.len() + 8)),
+        }
+    }
+}
+
+/// Elements of a collection `approx_size` measures.
+const SIZE_SAMPLE: usize = 64;
+
+/// Scales the total of the first `SIZE_SAMPLE` of `sizes` up to `len`
+/// elements.
+fn estimate(len: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    let sampled: usize = sizes.take(SIZE_SAMPLE).sum();
+    if len <= SIZE_SAMPLE {
+        sampled
+    } else {
+        sampled * len / SIZE_SAMPLE
+    }
+}
+
+/// A sorted set: members ordered by score, then lexicographically.
+#[derive(Debug, Clone, Default)]
+pub struct ZSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: BTreeSet<(Score, Bytes)>,
+}
+
+impl ZSet {
+    /// Adds `member` or updates its score. Returns `true` if it was new.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        // -0.0 and 0.0 compare equal but not under `total_cmp`.
+        let score = if score == 0.0 { 0.0 } else { score };
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.ordered.remove(&(Score(old), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        old.is_none()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    /// Members and scores in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+}
+
+/// A score with the total order sorted sets need. NaN is rejected when
+/// commands are parsed, so it never gets here.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Score) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Score) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Score) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Resolves inclusive `start` and `stop` offsets, which count from the end
+/// when negative, against a sequence of `len` elements, as GETRANGE, LRANGE
+/// and ZRANGE do.
+pub fn index_range(len: usize, start: i64, stop: i64) -> Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if len == 0 || start > stop {
+        return 0..0;
+    }
+    start as usize..stop as usize + 1
+}
@@ -0,0 +1,476 @@
+//! A `tokio_util` codec turning a byte stream into `Frame`s and back.
+
+use crate::frame::{self, Frame, Protocol};
+use bytes::{Buf, Bytes, BytesMut};
+use std::fmt;
+use std::io::{self, Cursor};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Largest bulk string a peer may send unless told otherwise, as in Redis.
+pub const DEFAULT_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Deepest nesting of aggregates a peer may send unless told otherwise.
+/// Commands are flat arrays, so deeper frames only make the parser recurse.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// Longest inline command kept waiting for the end of its line.
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// Decodes RESP2 and RESP3 frames, and encodes them in the protocol the
+/// peer picked with HELLO, for use with `Framed`.
+///
+/// A frame is checked to be whole before it is parsed. The check rejects
+/// bulk strings longer than `max_bulk_len` and aggregates nested deeper
+/// than `max_depth` as soon as their header arrives, so a peer cannot make
+/// the connection buffer them.
+///
+/// A line that does not start with a RESP type byte is an inline command,
+/// as typed over telnet: `SET foo bar\r\n` decodes like the array of its
+/// three words, each held to `max_bulk_len`. Blank lines are skipped.
+#[derive(Debug)]
+pub struct RespCodec {
+    protocol: Protocol,
+    max_bulk_len: usize,
+    max_depth: usize,
+    /// Bytes of every frame decoded so far.
+    read: u64,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// Bytes that are not a frame, or one over the limits.
+    Protocol(String),
+}
+
+impl RespCodec {
+    pub fn new(max_bulk_len: usize, max_depth: usize) -> RespCodec {
+        RespCodec {
+            protocol: Protocol::Resp2,
+            max_bulk_len,
+            max_depth,
+            read: 0,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Switches the encoding of the frames encoded from now on.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Bytes of every frame decoded so far, which is how a replica counts
+    /// its offset in the replication stream.
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+
+    /// Whether `decode` would return a frame or an error from `src`
+    /// without waiting for more bytes.
+    pub fn has_frame(&self, mut src: &[u8]) -> bool {
+        loop {
+            match src.first() {
+                None => return false,
+                Some(&kind) if is_type_byte(kind) => {
+                    let checked = self.check(&mut Cursor::new(src), 1);
+                    return !matches!(checked, Err(frame::Error::Incomplete));
+                }
+                Some(_) => match src.iter().position(|&b| b == b'\n') {
+                    Some(end) if inline_args(&src[..end]).is_empty() => src = &src[end + 1..],
+                    Some(_) => return true,
+                    None => return src.len() > MAX_INLINE_LEN,
+                },
+            }
+        }
+    }
+
+    /// Checks whether a whole frame can be parsed from `src`, leaving
+    /// `src` after it if so. `depth` counts the aggregates the frame is in,
+    /// itself included.
+    fn check(&self, src: &mut Cursor<&[u8]>, depth: usize) -> Result<(), frame::Error> {
+        match frame::get_u8(src)? {
+            b'+' | b'-' | b'(' => frame::get_line(src).map(drop),
+            b':' => frame::get_signed(src).map(drop),
+            b'#' => frame::get_boolean(src).map(drop),
+            b',' => frame::get_double(src).map(drop),
+            b'_' => frame::get_null(src),
+            b'$' | b'!' | b'=' => match frame::get_length(src)? {
+                Some(len) if len > self.max_bulk_len => {
+                    Err("protocol error; invalid bulk length".into())
+                }
+                Some(len) => frame::skip(src, len + 2),
+                None => Ok(()),
+            },
+            kind @ (b'*' | b'~' | b'>' | b'%') => {
+                if depth > self.max_depth {
+                    return Err("protocol error; aggregates nested too deep".into());
+                }
+                let len = frame::get_length(src)?.unwrap_or(0);
+                let len = if kind == b'%' {
+                    len.saturating_mul(2)
+                } else {
+                    len
+                };
+                for _ in 0..len {
+                    self.check(src, depth + 1)?;
+                }
+                Ok(())
+            }
+            actual => Err(format!("protocol error; invalid frame type byte `{}`", actual).into()),
+        }
+    }
+}
+
+impl Default for RespCodec {
+    fn default() -> RespCodec {
+        RespCodec::new(DEFAULT_MAX_BULK_LEN, DEFAULT_MAX_DEPTH)
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Frame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        loop {
+            match src.first() {
+                None => return Ok(None),
+                Some(&kind) if is_type_byte(kind) => break,
+                Some(_) => {}
+            }
+            let end = match src.iter().position(|&b| b == b'\n') {
+                Some(end) => end,
+                None if src.len() > MAX_INLINE_LEN => {
+                    return Err(Error::Protocol(
+                        "protocol error; too big inline request".to_string(),
+                    ));
+                }
+                None => return Ok(None),
+            };
+            let line = src.split_to(end + 1);
+            self.read += line.len() as u64;
+            let args = inline_args(&line[..end]);
+            if args.iter().any(|arg| arg.len() > self.max_bulk_len) {
+                return Err(Error::Protocol(
+                    "protocol error; invalid bulk length".to_string(),
+                ));
+            }
+            if !args.is_empty() {
+                let args = args
+                    .into_iter()
+                    .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg)));
+                return Ok(Some(Frame::Array(args.collect())));
+            }
+        }
+
+        let mut cursor = Cursor::new(&src[..]);
+        match self.check(&mut cursor, 1) {
+            Ok(()) => {}
+            Err(frame::Error::Incomplete) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let len = cursor.position() as usize;
+        cursor.set_position(0);
+        let frame = Frame::parse(&mut cursor)?;
+        src.advance(len);
+        self.read += len as u64;
+        Ok(Some(frame))
+    }
+}
+
+impl Encoder<&Frame> for RespCodec {
+    type Error = Error;
+
+    fn encode(&mut self, frame: &Frame, dst: &mut BytesMut) -> Result<(), Error> {
+        frame.encode(dst, self.protocol);
+        Ok(())
+    }
+}
+
+impl Encoder<Frame> for RespCodec {
+    type Error = Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Error> {
+        self.encode(&frame, dst)
+    }
+}
+
+fn is_type_byte(b: u8) -> bool {
+    b"+-:$*_#,(!=%~>".contains(&b)
+}
+
+/// The words of an inline command, without the CR ending its line.
+fn inline_args(line: &[u8]) -> Vec<&[u8]> {
+    line.split(|b| b.is_ascii_whitespace())
+        .filter(|arg| !arg.is_empty())
+        .collect()
+}
+
+impl From<io::Error> for Error {
+    fn from(src: io::Error) -> Error {
+        Error::Io(src)
+    }
+}
+
+impl From<frame::Error> for Error {
+    fn from(src: frame::Error) -> Error {
+        Error::Protocol(src.to_string())
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(src: Error) -> io::Error {
+        match src {
+            Error::Io(err) => err,
+            Error::Protocol(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => err.fmt(f),
+            Error::Protocol(err) => err.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift64*, enough to generate arbitrary frames from a seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn new(seed: u64) -> Rng {
+            Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+        }
+
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        /// Text without CR or LF, which simple strings cannot hold.
+        fn line(&mut self) -> String {
+            (0..self.below(20))
+                .map(|_| match self.below(10) {
+                    0 => 'é',
+                    _ => (b' ' + self.below(95) as u8) as char,
+                })
+                .collect()
+        }
+
+        fn bytes(&mut self) -> Bytes {
+            (0..self.below(40))
+                .map(|_| match self.below(4) {
+                    0 => b'\r',
+                    1 => b'\n',
+                    _ => self.next() as u8,
+                })
+                .collect()
+        }
+
+        /// An arbitrary frame, made only of RESP2 types unless `resp3`.
+        fn frame(&mut self, resp3: bool, depth: usize) -> Frame {
+            let kinds = if resp3 { 13 } else { 6 };
+            // Aggregates come last, and are left out once deep enough.
+            let kinds = if depth >= 3 {
+                kinds - 1 - 3 * resp3 as u64
+            } else {
+                kinds
+            };
+            let parts = |rng: &mut Rng| -> Vec<Frame> {
+                (0..rng.below(5))
+                    .map(|_| rng.frame(resp3, depth + 1))
+                    .collect()
+            };
+            match (self.below(kinds), resp3) {
+                (0, _) => Frame::Simple(self.line()),
+                (1, _) => Frame::Error(self.line()),
+                (2, _) => Frame::Integer(self.next() as i64 >> self.below(64)),
+                (3, _) => Frame::Bulk(self.bytes()),
+                (4, _) => Frame::Null,
+                (5, false) => Frame::Array(parts(self)),
+                (5, true) => Frame::Double(loop {
+                    let n = f64::from_bits(self.next());
+                    if !n.is_nan() {
+                        break n;
+                    }
+                }),
+                (6, _) => Frame::Boolean(self.below(2) == 1),
+                (7, _) => Frame::BigNumber(format!("-{}{:020}", self.next(), self.next())),
+                (8, _) => Frame::Verbatim {
+                    format: (0..3)
+                        .map(|_| (b'a' + self.below(26) as u8) as char)
+                        .collect(),
+                    text: self.bytes(),
+                },
+                (9, _) => Frame::Array(parts(self)),
+                (10, _) => Frame::Set(parts(self)),
+                (11, _) => Frame::Push(parts(self)),
+                _ => Frame::Map(
+                    (0..self.below(4))
+                        .map(|_| (self.frame(resp3, depth + 1), self.frame(resp3, depth + 1)))
+                        .collect(),
+                ),
+            }
+        }
+    }
+
+    fn encode(frames: &[Frame], protocol: Protocol) -> BytesMut {
+        let mut codec = RespCodec::default();
+        codec.set_protocol(protocol);
+        let mut out = BytesMut::new();
+        for frame in frames {
+            codec.encode(frame, &mut out).unwrap();
+        }
+        out
+    }
+
+    fn decode_all(codec: &mut RespCodec, src: &[u8]) -> Result<Vec<Frame>, Error> {
+        let mut buf = BytesMut::from(src);
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.decode(&mut buf)? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn arbitrary_frames_round_trip() {
+        for (protocol, resp3) in [(Protocol::Resp3, true), (Protocol::Resp2, false)] {
+            for seed in 0..2000 {
+                let frame = Rng::new(seed).frame(resp3, 0);
+                let data = encode(std::slice::from_ref(&frame), protocol);
+                let mut codec = RespCodec::default();
+                let mut buf = data.clone();
+                assert_eq!(codec.decode(&mut buf).unwrap(), Some(frame), "{:?}", data);
+                assert!(buf.is_empty());
+                assert_eq!(codec.bytes_read(), data.len() as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn frames_decode_however_the_stream_is_split() {
+        for seed in 0..500 {
+            let mut rng = Rng::new(seed);
+            let frames: Vec<Frame> = (0..rng.below(6)).map(|_| rng.frame(true, 0)).collect();
+            let data = encode(&frames, Protocol::Resp3);
+
+            let mut codec = RespCodec::default();
+            let mut buf = BytesMut::new();
+            let mut decoded = Vec::new();
+            let mut rest = &data[..];
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(1 + rng.below(rest.len() as u64) as usize);
+                buf.extend_from_slice(chunk);
+                rest = tail;
+                loop {
+                    let whole = codec.has_frame(&buf);
+                    match codec.decode(&mut buf).unwrap() {
+                        Some(frame) => {
+                            assert!(whole);
+                            decoded.push(frame);
+                        }
+                        None => {
+                            assert!(!whole);
+                            break;
+                        }
+                    }
+                }
+            }
+            assert_eq!(decoded, frames);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn garbage_never_panics() {
+        for seed in 0..5000 {
+            let mut rng = Rng::new(seed);
+            let mut data = encode(&[rng.frame(true, 0)], Protocol::Resp3).to_vec();
+            // Corrupt a valid frame, so the parser gets past the first byte.
+            for _ in 0..=rng.below(3) {
+                let at = rng.below(data.len() as u64) as usize;
+                let likely = b"+-:$*_#,(!=%~>\r\n0123456789";
+                data[at] = match rng.below(3) {
+                    0 => likely[rng.below(likely.len() as u64) as usize],
+                    _ => rng.next() as u8,
+                };
+            }
+            data.truncate(1 + rng.below(data.len() as u64) as usize);
+            let mut codec = RespCodec::new(1024, 4);
+            let _ = decode_all(&mut codec, &data);
+        }
+    }
+
+    #[test]
+    fn limits_are_enforced_from_the_header() {
+        let mut codec = RespCodec::new(10, 2);
+        assert!(decode_all(&mut codec, b"$11\r\n").is_err());
+        assert!(decode_all(&mut codec, b"GET 0123456789a\r\n").is_err());
+        assert!(decode_all(&mut codec, b"*1\r\n*1\r\n*0\r\n").is_err());
+        assert!(codec.has_frame(b"$11\r\n"));
+        assert_eq!(
+            decode_all(&mut codec, b"*1\r\n$10\r\n0123456789\r\n").unwrap(),
+            [Frame::Array(vec![Frame::Bulk("0123456789".into())])]
+        );
+        assert_eq!(
+            decode_all(&mut codec, b"*1\r\n%1\r\n:1\r\n:2\r\n").unwrap(),
+            [Frame::Array(vec![Frame::Map(vec![(
+                Frame::Integer(1),
+                Frame::Integer(2)
+            )])])]
+        );
+    }
+
+    #[test]
+    fn inline_commands_are_split_into_words() {
+        let mut codec = RespCodec::default();
+        let command = |args: &[&'static str]| {
+            Frame::Array(args.iter().map(|&arg| Frame::Bulk(arg.into())).collect())
+        };
+        assert_eq!(
+            decode_all(
+                &mut codec,
+                b"PING\r\n\r\n  \nSET  foo\tbar\n*1\r\n$4\r\nPING\r\n"
+            )
+            .unwrap(),
+            [
+                command(&["PING"]),
+                command(&["SET", "foo", "bar"]),
+                command(&["PING"])
+            ]
+        );
+        assert_eq!(codec.bytes_read(), 38);
+
+        assert!(!codec.has_frame(b"\r\n  \nGET fo"));
+        assert!(codec.has_frame(b"\r\n  \nGET foo\n"));
+        let mut buf = BytesMut::from(&b"GET fo"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"o\r\n");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(command(&["GET", "foo"]))
+        );
+
+        let long = vec![b'a'; MAX_INLINE_LEN + 1];
+        assert!(codec.has_frame(&long));
+        assert!(decode_all(&mut codec, &long).is_err());
+    }
+}
@@ -0,0 +1,726 @@
+//! Configuration shared by every binary.
+//!
+//! Settings are resolved in this order, later sources overriding earlier ones:
+//!
+//! 1. the defaults of the binary,
+//! 2. a TOML file given with `--config <path>` or `MINI_REDIS_CONFIG`,
+//! 3. environment variables named `MINI_REDIS_` followed by the setting in
+//!    upper case with `-` replaced by `_`, e.g. `MINI_REDIS_MAX_VALUE_SIZE`,
+//! 4. command line flags, e.g. `--port 7000` or `--appendfsync=always`.
+//!
+//! ```toml
+//! bind = "0.0.0.0"
+//! port = 7000
+//! shards = 64
+//! appendonly = true
+//! ```
+
+use crate::{codec, slot};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use tracing::Level;
+
+const ENV_PREFIX: &str = "MINI_REDIS_";
+
+/// Every setting, as spelled in the config file and on the command line.
+const SETTINGS: &[&str] = &[
+    "bind",
+    "port",
+    "databases",
+    "shards",
+    "shard-hash",
+    "maxclients",
+    "max-value-size",
+    "proto-max-bulk-len",
+    "proto-max-depth",
+    "maxmemory",
+    "maxmemory-policy",
+    "maxmemory-samples",
+    "pubsub-channel-capacity",
+    "pubsub-lag-policy",
+    "dir",
+    "dbfilename",
+    "appendonly",
+    "appendfilename",
+    "appendfsync",
+    "loglevel",
+    "metrics-port",
+    "replicaof",
+    "repl-backlog-size",
+    "masteruser",
+    "masterauth",
+    "requirepass",
+    "cluster-enabled",
+    "cluster-port",
+    "cluster-meet",
+    "cluster-slots",
+];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address to listen on, or to connect to for clients.
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Number of logical databases clients can SELECT.
+    pub databases: usize,
+    /// Number of shards in each database at startup. RESHARD changes it
+    /// for one database while running, until the next restart.
+    pub shards: usize,
+    /// Hash function picking the shard of a key. Unlike the standard
+    /// library's default hasher, each gives the same shard for a key on
+    /// every build.
+    pub shard_hash: ShardHash,
+    /// Maximum number of simultaneous client connections.
+    pub maxclients: usize,
+    /// Largest value, in bytes, a client may store.
+    pub max_value_size: usize,
+    /// Longest bulk string a client may send. Longer ones are refused as
+    /// soon as their length arrives, and the connection closed.
+    pub proto_max_bulk_len: usize,
+    /// Deepest nesting of arrays a client may send.
+    pub proto_max_depth: usize,
+    /// Approximate bytes of keys and values the server may hold across
+    /// every database, 0 for no limit.
+    pub maxmemory: usize,
+    /// What happens to writes once `maxmemory` is reached.
+    pub maxmemory_policy: MaxMemoryPolicy,
+    /// Keys looked at to pick each one to evict.
+    pub maxmemory_samples: usize,
+    /// Messages a channel or pattern holds for subscribers not yet done
+    /// with earlier ones.
+    pub pubsub_channel_capacity: usize,
+    /// What happens to a subscriber that falls further behind.
+    pub pubsub_lag_policy: LagPolicy,
+    /// Directory holding the snapshot and the append-only file.
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: FsyncPolicy,
+    pub loglevel: Level,
+    /// Port of the HTTP listener serving Prometheus metrics, 0 to disable it.
+    pub metrics_port: u16,
+    /// Host and port of the primary to replicate at startup, given as
+    /// `"host port"`. REPLICAOF changes it while running.
+    pub replicaof: Option<(String, u16)>,
+    /// Bytes of recent writes a primary keeps, so replicas that lose their
+    /// link can catch up without copying the whole data set again.
+    pub repl_backlog_size: usize,
+    /// User and password a replica logs in to its primary with.
+    pub masteruser: String,
+    pub masterauth: Option<String>,
+    /// Password of the default user, which needs none if unset.
+    pub requirepass: Option<String>,
+    /// Whether the server is one node of a cluster, serving only the keys
+    /// of its hash slots.
+    pub cluster_enabled: bool,
+    /// Port of the cluster bus nodes gossip over, 0 for `port` + 10000.
+    pub cluster_port: u16,
+    /// Client addresses of nodes to join at startup, as `"host:port ..."`.
+    /// Their bus is expected at the port + 10000.
+    pub cluster_meet: Vec<(String, u16)>,
+    /// Hash slots the node claims at startup, as `"0-5460 6000 ..."`.
+    pub cluster_slots: Vec<(u16, u16)>,
+}
+
+/// When appended commands are forced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every write, before the client gets its reply.
+    Always,
+    /// Once a second from a background task.
+    EverySec,
+    /// Whenever the operating system decides to.
+    No,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FsyncPolicy, String> {
+        match &s.to_lowercase()[..] {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err("expected always, everysec or no".to_string()),
+        }
+    }
+}
+
+/// How the server maps keys to shards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardHash {
+    /// SipHash-2-4 with a fixed key.
+    SipHash,
+    /// 64-bit FNV-1a, faster on short keys.
+    Fnv,
+    /// The CRC16 key slot of Redis Cluster, so keys sharing a `{hashtag}`
+    /// share a shard.
+    Crc16,
+}
+
+impl FromStr for ShardHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ShardHash, String> {
+        match &s.to_lowercase()[..] {
+            "siphash" => Ok(ShardHash::SipHash),
+            "fnv" => Ok(ShardHash::Fnv),
+            "crc16" => Ok(ShardHash::Crc16),
+            _ => Err("expected siphash, fnv or crc16".to_string()),
+        }
+    }
+}
+
+/// What to do with a subscriber that fell more than the channel capacity
+/// behind the publishers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Skip the missed messages and carry on with the oldest one retained.
+    Skip,
+    /// Close the connection, like Redis does once a pub/sub client's output
+    /// buffer limit is hit.
+    Disconnect,
+}
+
+impl FromStr for LagPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LagPolicy, String> {
+        match &s.to_lowercase()[..] {
+            "skip" => Ok(LagPolicy::Skip),
+            "disconnect" => Ok(LagPolicy::Disconnect),
+            _ => Err("expected skip or disconnect".to_string()),
+        }
+    }
+}
+
+/// How room is made for new writes once `maxmemory` is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxMemoryPolicy {
+    /// Writes that need more memory fail with an OOM error.
+    NoEviction,
+    /// Evicts the least recently used keys.
+    AllKeysLru,
+    /// Evicts the least frequently used keys.
+    AllKeysLfu,
+    /// Evicts the least recently used of the keys with a deadline.
+    VolatileLru,
+    /// Evicts the keys with the nearest deadline.
+    VolatileTtl,
+}
+
+impl FromStr for MaxMemoryPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MaxMemoryPolicy, String> {
+        match &s.to_lowercase()[..] {
+            "noeviction" => Ok(MaxMemoryPolicy::NoEviction),
+            "allkeys-lru" => Ok(MaxMemoryPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(MaxMemoryPolicy::AllKeysLfu),
+            "volatile-lru" => Ok(MaxMemoryPolicy::VolatileLru),
+            "volatile-ttl" => Ok(MaxMemoryPolicy::VolatileTtl),
+            _ => Err(
+                "expected noeviction, allkeys-lru, allkeys-lfu, volatile-lru or volatile-ttl"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for MaxMemoryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaxMemoryPolicy::NoEviction => "noeviction",
+            MaxMemoryPolicy::AllKeysLru => "allkeys-lru",
+            MaxMemoryPolicy::AllKeysLfu => "allkeys-lfu",
+            MaxMemoryPolicy::VolatileLru => "volatile-lru",
+            MaxMemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+        .fmt(f)
+    }
+}
+
+/// A setting that could not be applied, or a config file that could not be
+/// read.
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: IpAddr::from([127, 0, 0, 1]),
+            port: 6379,
+            databases: 16,
+            shards: 1000,
+            shard_hash: ShardHash::SipHash,
+            maxclients: 10_000,
+            max_value_size: 512 * 1024 * 1024,
+            proto_max_bulk_len: codec::DEFAULT_MAX_BULK_LEN,
+            proto_max_depth: codec::DEFAULT_MAX_DEPTH,
+            maxmemory: 0,
+            maxmemory_policy: MaxMemoryPolicy::NoEviction,
+            maxmemory_samples: 5,
+            pubsub_channel_capacity: 1024,
+            pubsub_lag_policy: LagPolicy::Disconnect,
+            dir: PathBuf::from("."),
+            dbfilename: "dump.rdb".to_string(),
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: FsyncPolicy::EverySec,
+            loglevel: Level::INFO,
+            metrics_port: 0,
+            replicaof: None,
+            repl_backlog_size: 1024 * 1024,
+            masteruser: "default".to_string(),
+            masterauth: None,
+            requirepass: None,
+            cluster_enabled: false,
+            cluster_port: 0,
+            cluster_meet: Vec::new(),
+            cluster_slots: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from `defaults` and the config file,
+    /// environment and command line of this process.
+    pub fn load(defaults: Config) -> Result<Config, ConfigError> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let env: Vec<(String, String)> = std::env::vars().collect();
+        Config::from_sources(defaults, &args, &env)
+    }
+
+    /// Like `load`, but prints the error and exits the process on failure.
+    pub fn load_or_exit(defaults: Config) -> Config {
+        match Config::load(defaults) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("Invalid configuration: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn from_sources(
+        mut config: Config,
+        args: &[String],
+        env: &[(String, String)],
+    ) -> Result<Config, ConfigError> {
+        let flags = parse_args(args)?;
+        let env_var = |name: &str| {
+            env.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+
+        let file = flags
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| env_var("MINI_REDIS_CONFIG"));
+        if let Some(path) = file {
+            config.apply_file(&path)?;
+        }
+
+        for setting in SETTINGS {
+            let name = format!("{}{}", ENV_PREFIX, setting.to_uppercase().replace('-', "_"));
+            if let Some(value) = env_var(&name) {
+                config
+                    .set(setting, &value)
+                    .map_err(|err| ConfigError(format!("{}: {}", name, err)))?;
+            }
+        }
+
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            config
+                .set(key, value)
+                .map_err(|err| ConfigError(format!("--{}: {}", key, err)))?;
+        }
+
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Checks what no single setting can be checked for on its own.
+    fn check(&self) -> Result<(), ConfigError> {
+        if self.cluster_enabled && self.cluster_port == 0 && default_bus_port(self.port).is_none() {
+            return Err(ConfigError(format!(
+                "cluster-port: must be set, as port {} + 10000 is not a port",
+                self.port
+            )));
+        }
+        let no_bus = self
+            .cluster_meet
+            .iter()
+            .find(|(_, port)| default_bus_port(*port).is_none());
+        if let Some((host, port)) = no_bus {
+            return Err(ConfigError(format!(
+                "cluster-meet: {}:{} has no cluster bus port, as {} + 10000 is not a port",
+                host, port, port
+            )));
+        }
+        Ok(())
+    }
+
+    /// The address to listen on or connect to.
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    pub fn aof_path(&self) -> PathBuf {
+        self.dir.join(&self.appendfilename)
+    }
+
+    /// The port of the cluster bus, which `load` makes sure there is if
+    /// `cluster_enabled` is set.
+    pub fn cluster_bus_port(&self) -> u16 {
+        match self.cluster_port {
+            0 => default_bus_port(self.port).expect("port checked by Config::load"),
+            port => port,
+        }
+    }
+
+    fn apply_file(&mut self, path: &str) -> Result<(), ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError(format!("cannot read config file {}: {}", path, err)))?;
+        let table: toml::Table = contents
+            .parse()
+            .map_err(|err| ConfigError(format!("cannot parse config file {}: {}", path, err)))?;
+
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(_) | toml::Value::Boolean(_) => value.to_string(),
+                _ => {
+                    return Err(ConfigError(format!(
+                        "{}: {}: expected a string, number or boolean",
+                        path, key
+                    )))
+                }
+            };
+            self.set(&key, &value)
+                .map_err(|err| ConfigError(format!("{}: {}: {}", path, key, err)))?;
+        }
+        Ok(())
+    }
+
+    /// Applies one setting given as text. The error does not repeat `key`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match &key.replace('_', "-")[..] {
+            "bind" => self.bind = parse(value, "an IP address")?,
+            "port" => self.port = parse(value, "a port number")?,
+            "databases" => self.databases = positive(value)?,
+            "shards" => self.shards = positive(value)?,
+            "shard-hash" => {
+                self.shard_hash = value
+                    .parse()
+                    .map_err(|err| format!("invalid value '{}', {}", value, err))?
+            }
+            "maxclients" => self.maxclients = positive(value)?,
+            "max-value-size" => self.max_value_size = positive(value)?,
+            "proto-max-bulk-len" => self.proto_max_bulk_len = memory_size(value)?,
+            "proto-max-depth" => self.proto_max_depth = positive(value)?,
+            "maxmemory" => self.maxmemory = memory_size(value)?,
+            "maxmemory-policy" => {
+                self.maxmemory_policy = value
+                    .parse()
+                    .map_err(|err| format!("invalid value '{}', {}", value, err))?
+            }
+            "maxmemory-samples" => self.maxmemory_samples = positive(value)?,
+            "pubsub-channel-capacity" => self.pubsub_channel_capacity = positive(value)?,
+            "pubsub-lag-policy" => {
+                self.pubsub_lag_policy = value
+                    .parse()
+                    .map_err(|err| format!("invalid value '{}', {}", value, err))?
+            }
+            "dir" => self.dir = PathBuf::from(value),
+            "dbfilename" => self.dbfilename = file_name(value)?,
+            "appendonly" => self.appendonly = yes_no(value)?,
+            "appendfilename" => self.appendfilename = file_name(value)?,
+            "appendfsync" => {
+                self.appendfsync = value
+                    .parse()
+                    .map_err(|err| format!("invalid value '{}', {}", value, err))?
+            }
+            "loglevel" => self.loglevel = parse(value, "one of error, warn, info, debug or trace")?,
+            "metrics-port" => self.metrics_port = parse(value, "a port number")?,
+            "replicaof" => self.replicaof = host_port(value)?,
+            "repl-backlog-size" => self.repl_backlog_size = positive(value)?,
+            "masteruser" => self.masteruser = value.to_string(),
+            "masterauth" => self.masterauth = Some(value.to_string()).filter(|s| !s.is_empty()),
+            "requirepass" => self.requirepass = Some(value.to_string()).filter(|s| !s.is_empty()),
+            "cluster-enabled" => self.cluster_enabled = yes_no(value)?,
+            "cluster-port" => self.cluster_port = parse(value, "a port number")?,
+            "cluster-meet" => self.cluster_meet = addresses(value)?,
+            "cluster-slots" => self.cluster_slots = slot_ranges(value)?,
+            _ => return Err(format!("unknown setting '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+/// The cluster bus port of a node serving clients at `port`, when it is not
+/// configured: `port` + 10000, if that is still a port.
+pub fn default_bus_port(port: u16) -> Option<u16> {
+    port.checked_add(10000)
+}
+
+/// Splits `--key value` and `--key=value` flags into pairs.
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let flag = arg
+            .strip_prefix("--")
+            .ok_or_else(|| ConfigError(format!("unexpected argument '{}'", arg)))?;
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError(format!("--{}: missing value", flag)))?;
+                (flag.to_string(), value.clone())
+            }
+        };
+        flags.push((key, value));
+    }
+    Ok(flags)
+}
+
+fn parse<T: FromStr>(value: &str, expected: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{}', expected {}", value, expected))
+}
+
+fn positive(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!(
+            "invalid value '{}', expected a positive number",
+            value
+        )),
+    }
+}
+
+fn yes_no(value: &str) -> Result<bool, String> {
+    match &value.to_lowercase()[..] {
+        "yes" | "true" => Ok(true),
+        "no" | "false" => Ok(false),
+        _ => Err(format!("invalid value '{}', expected yes or no", value)),
+    }
+}
+
+/// Parses `"host port"`, or `"no one"` for none.
+fn host_port(value: &str) -> Result<Option<(String, u16)>, String> {
+    let invalid = || format!("invalid value '{}', expected a host and a port", value);
+    match value.split_whitespace().collect::<Vec<_>>()[..] {
+        [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => Ok(None),
+        [host, port] => Ok(Some((
+            host.to_string(),
+            port.parse().map_err(|_| invalid())?,
+        ))),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses `"host:port"` addresses separated by spaces or commas.
+fn addresses(value: &str) -> Result<Vec<(String, u16)>, String> {
+    let words = value.split([' ', ',']).filter(|word| !word.is_empty());
+    words
+        .map(|addr| match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() => match port.parse() {
+                Ok(port) => Ok((host.to_string(), port)),
+                Err(_) => Err(format!("invalid port in '{}'", addr)),
+            },
+            _ => Err(format!("invalid address '{}', expected host:port", addr)),
+        })
+        .collect()
+}
+
+/// Parses slots and `first-last` ranges of them separated by spaces or
+/// commas.
+fn slot_ranges(value: &str) -> Result<Vec<(u16, u16)>, String> {
+    let words = value.split([' ', ',']).filter(|word| !word.is_empty());
+    words
+        .map(|range| {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            match (first.parse::<u16>(), last.parse::<u16>()) {
+                (Ok(first), Ok(last)) if first <= last && last < slot::KEY_SLOTS => {
+                    Ok((first, last))
+                }
+                _ => Err(format!("invalid slot range '{}'", range)),
+            }
+        })
+        .collect()
+}
+
+/// Parses a number of bytes, optionally followed by a unit as in Redis
+/// configs: `k`, `m` and `g` for powers of 1000, `kb`, `mb` and `gb` for
+/// powers of 1024.
+fn memory_size(value: &str) -> Result<usize, String> {
+    let lower = value.to_lowercase();
+    let digits = lower.trim_end_matches(char::is_alphabetic);
+    let unit: usize = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => {
+            return Err(format!(
+                "invalid value '{}', expected a size such as 100mb",
+                value
+            ))
+        }
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("invalid value '{}', expected a size such as 100mb", value))
+}
+
+fn file_name(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.contains(['/', '\\']) {
+        return Err(format!(
+            "invalid value '{}', expected a file name without directories",
+            value
+        ));
+    }
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// A config file in the temporary directory, unique to the test.
+    fn config_file(test: &str, contents: &str) -> String {
+        let name = format!("config-{}-{}.toml", std::process::id(), test);
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn memory_sizes_take_redis_units() {
+        assert_eq!(memory_size("0"), Ok(0));
+        assert_eq!(memory_size("100"), Ok(100));
+        assert_eq!(memory_size("100b"), Ok(100));
+        assert_eq!(memory_size("1k"), Ok(1000));
+        assert_eq!(memory_size("1kb"), Ok(1024));
+        assert_eq!(memory_size("2M"), Ok(2_000_000));
+        assert_eq!(memory_size("2mb"), Ok(2 * 1024 * 1024));
+        assert_eq!(memory_size("1g"), Ok(1_000_000_000));
+        assert_eq!(memory_size("1GB"), Ok(1024 * 1024 * 1024));
+        for bad in [
+            "",
+            "mb",
+            "-1mb",
+            "1.5mb",
+            "1tb",
+            "1 mb",
+            "99999999999999999999gb",
+        ] {
+            assert!(memory_size(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn file_settings_are_overridden_by_env_and_then_flags() {
+        let file = config_file(
+            "layers",
+            "port = 7000\nshards = 64\nappendonly = true\nmaxmemory = \"1mb\"\n\
+             maxmemory-policy = \"allkeys-lru\"\nreplicaof = \"primary 6380\"\n",
+        );
+        let env = [
+            ("MINI_REDIS_CONFIG".to_string(), file.clone()),
+            ("MINI_REDIS_PORT".to_string(), "7001".to_string()),
+            ("MINI_REDIS_MAX_VALUE_SIZE".to_string(), "10".to_string()),
+        ];
+        let flags = args(&[
+            "--port",
+            "7002",
+            "--shards=8",
+            "--pubsub-lag-policy",
+            "skip",
+        ]);
+        let config = Config::from_sources(Config::default(), &flags, &env).unwrap();
+        assert_eq!(config.port, 7002);
+        assert_eq!(config.shards, 8);
+        assert_eq!(config.max_value_size, 10);
+        assert!(config.appendonly);
+        assert_eq!(config.maxmemory, 1024 * 1024);
+        assert_eq!(config.maxmemory_policy, MaxMemoryPolicy::AllKeysLru);
+        assert_eq!(config.replicaof, Some(("primary".to_string(), 6380)));
+        assert_eq!(config.pubsub_lag_policy, LagPolicy::Skip);
+        // Untouched settings keep the defaults.
+        assert_eq!(config.databases, Config::default().databases);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn a_config_flag_beats_the_env_file() {
+        let file = config_file("flag", "port = 7100\n");
+        let env = [("MINI_REDIS_CONFIG".to_string(), "/no/such/file".to_string())];
+        let flags = args(&["--config", &file]);
+        let config = Config::from_sources(Config::default(), &flags, &env).unwrap();
+        assert_eq!(config.port, 7100);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn bad_settings_name_their_source() {
+        let error = |flags: &[&str], env: &[(&str, &str)]| {
+            let env: Vec<_> = env
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            Config::from_sources(Config::default(), &args(flags), &env)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error(&["--port", "huge"], &[]).starts_with("--port: invalid value 'huge'"));
+        assert!(error(&["--nosuch", "1"], &[]).contains("unknown setting 'nosuch'"));
+        assert!(error(&["--port"], &[]).contains("missing value"));
+        assert!(error(&["port"], &[]).contains("unexpected argument"));
+        assert!(error(&[], &[("MINI_REDIS_SHARDS", "0")]).starts_with("MINI_REDIS_SHARDS:"));
+        let flags = ["--port", "60000", "--cluster-enabled", "yes"];
+        assert!(error(&flags, &[]).starts_with("cluster-port: must be set"));
+        let flags = ["--cluster-meet", "10.0.0.1:7000 10.0.0.2:60000"];
+        assert!(error(&flags, &[]).contains("10.0.0.2:60000 has no cluster bus port"));
+
+        let file = config_file("bad", "dbfilename = \"a/b\"\n");
+        assert!(error(&["--config", &file], &[]).contains(": dbfilename: invalid value"));
+        std::fs::write(&file, "ports = [1, 2]\n").unwrap();
+        assert!(error(&["--config", &file], &[]).contains("expected a string, number or boolean"));
+        std::fs::write(&file, "port = \n").unwrap();
+        assert!(error(&["--config", &file], &[]).contains("cannot parse config file"));
+        std::fs::remove_file(&file).unwrap();
+        assert!(error(&["--config", &file], &[]).contains("cannot read config file"));
+    }
+}
@@ -0,0 +1,356 @@
+//! Frames of the Redis protocol, and how they are spelled on the wire.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt;
+use std::io::Cursor;
+
+/// A frame of the Redis protocol, RESP2 or RESP3.
+///
+/// `mini_redis::Frame` only knows the RESP2 types. The RESP3 ones are
+/// encoded as their closest RESP2 equivalent on connections that did not
+/// switch to RESP3 with HELLO, so commands can reply with them either way.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Frame>),
+    /// Pairs of keys and values, flattened into an array in RESP2.
+    Map(Vec<(Frame, Frame)>),
+    /// An array in RESP2.
+    Set(Vec<Frame>),
+    /// A bulk string of the number in RESP2.
+    Double(f64),
+    /// 1 or 0 in RESP2.
+    Boolean(bool),
+    /// An integer that may not fit in 64 bits, as its decimal digits. A
+    /// bulk string in RESP2.
+    BigNumber(String),
+    /// Text with a hint of its three letter format, such as `txt`. Only
+    /// the text is sent, as a bulk string, in RESP2.
+    Verbatim {
+        format: String,
+        text: Bytes,
+    },
+    /// Data the server sends without a request, such as pub/sub messages.
+    /// An array in RESP2.
+    Push(Vec<Frame>),
+}
+
+/// The protocol version of a connection, picked by the client with HELLO.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    pub fn version(self) -> u8 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Not enough data is available to parse a frame.
+    Incomplete,
+
+    /// Invalid frame encoding.
+    Other(String),
+}
+
+impl Frame {
+    /// Plain text meant to be shown to a user as is, such as the INFO
+    /// report.
+    pub fn text(text: String) -> Frame {
+        Frame::Verbatim {
+            format: "txt".to_string(),
+            text: text.into(),
+        }
+    }
+
+    /// Parses the frame at the start of `src`, which `RespCodec` has already
+    /// checked is whole.
+    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+        match get_u8(src)? {
+            b'+' => Ok(Frame::Simple(get_string(src)?)),
+            b'-' => Ok(Frame::Error(get_string(src)?)),
+            b'(' => Ok(Frame::BigNumber(get_string(src)?)),
+            b':' => Ok(Frame::Integer(get_signed(src)?)),
+            b'#' => Ok(Frame::Boolean(get_boolean(src)?)),
+            b',' => Ok(Frame::Double(get_double(src)?)),
+            b'_' => get_null(src).map(|()| Frame::Null),
+            kind @ (b'$' | b'!' | b'=') => {
+                let data = match get_length(src)? {
+                    Some(len) => get_blob(src, len)?,
+                    None => return Ok(Frame::Null),
+                };
+                match kind {
+                    b'$' => Ok(Frame::Bulk(data)),
+                    b'!' => String::from_utf8(data.to_vec())
+                        .map(Frame::Error)
+                        .map_err(|_| "protocol error; invalid frame format".into()),
+                    _ => match data.get(3) {
+                        Some(b':') => Ok(Frame::Verbatim {
+                            format: String::from_utf8_lossy(&data[..3]).into_owned(),
+                            text: data.slice(4..),
+                        }),
+                        _ => Err("protocol error; invalid verbatim string".into()),
+                    },
+                }
+            }
+            kind @ (b'*' | b'~' | b'>') => {
+                let len = match get_length(src)? {
+                    Some(len) => len,
+                    None => return Ok(Frame::Null),
+                };
+                let mut parts = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    parts.push(Frame::parse(src)?);
+                }
+                Ok(match kind {
+                    b'*' => Frame::Array(parts),
+                    b'~' => Frame::Set(parts),
+                    _ => Frame::Push(parts),
+                })
+            }
+            b'%' => {
+                let len = get_length(src)?.unwrap_or(0);
+                let mut pairs = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    pairs.push((Frame::parse(src)?, Frame::parse(src)?));
+                }
+                Ok(Frame::Map(pairs))
+            }
+            actual => Err(format!("protocol error; invalid frame type byte `{}`", actual).into()),
+        }
+    }
+
+    /// Appends the encoding of the frame in `protocol` to `out`.
+    pub fn encode(&self, out: &mut BytesMut, protocol: Protocol) {
+        let resp3 = protocol == Protocol::Resp3;
+        match self {
+            Frame::Simple(s) => put_line(out, b'+', s.as_bytes()),
+            Frame::Error(s) => put_line(out, b'-', s.as_bytes()),
+            Frame::Integer(n) => put_line(out, b':', n.to_string().as_bytes()),
+            Frame::Bulk(data) => put_blob(out, b'$', data),
+            Frame::Null if resp3 => out.put_slice(b"_\r\n"),
+            Frame::Null => out.put_slice(b"$-1\r\n"),
+            Frame::Array(parts) => put_aggregate(out, b'*', parts, protocol),
+            Frame::Map(pairs) if resp3 => {
+                put_line(out, b'%', pairs.len().to_string().as_bytes());
+                for (key, value) in pairs {
+                    key.encode(out, protocol);
+                    value.encode(out, protocol);
+                }
+            }
+            Frame::Map(pairs) => {
+                put_line(out, b'*', (pairs.len() * 2).to_string().as_bytes());
+                for (key, value) in pairs {
+                    key.encode(out, protocol);
+                    value.encode(out, protocol);
+                }
+            }
+            Frame::Set(parts) if resp3 => put_aggregate(out, b'~', parts, protocol),
+            Frame::Push(parts) if resp3 => put_aggregate(out, b'>', parts, protocol),
+            Frame::Set(parts) | Frame::Push(parts) => put_aggregate(out, b'*', parts, protocol),
+            Frame::Double(n) if resp3 => put_line(out, b',', format_double(*n).as_bytes()),
+            Frame::Double(n) => put_blob(out, b'$', format_double(*n).as_bytes()),
+            Frame::Boolean(b) if resp3 => put_line(out, b'#', if *b { b"t" } else { b"f" }),
+            Frame::Boolean(b) => put_line(out, b':', if *b { b"1" } else { b"0" }),
+            Frame::BigNumber(n) if resp3 => put_line(out, b'(', n.as_bytes()),
+            Frame::BigNumber(n) => put_blob(out, b'$', n.as_bytes()),
+            Frame::Verbatim { format, text } if resp3 => {
+                let mut data = Vec::with_capacity(4 + text.len());
+                data.extend_from_slice(format.as_bytes());
+                data.push(b':');
+                data.extend_from_slice(text);
+                put_blob(out, b'=', &data);
+            }
+            Frame::Verbatim { text, .. } => put_blob(out, b'$', text),
+        }
+    }
+
+    /// Size of the frame once encoded in `protocol`.
+    pub fn encoded_len(&self, protocol: Protocol) -> u64 {
+        fn digits(n: usize) -> u64 {
+            n.to_string().len() as u64
+        }
+        fn blob(len: usize) -> u64 {
+            1 + digits(len) + 2 + len as u64 + 2
+        }
+        fn aggregate(len: usize, parts: u64) -> u64 {
+            1 + digits(len) + 2 + parts
+        }
+        let resp3 = protocol == Protocol::Resp3;
+        let all = |parts: &[Frame]| parts.iter().map(|part| part.encoded_len(protocol)).sum();
+        match self {
+            Frame::Simple(s) | Frame::Error(s) => 1 + s.len() as u64 + 2,
+            Frame::Integer(n) => 1 + n.to_string().len() as u64 + 2,
+            Frame::Bulk(data) => blob(data.len()),
+            Frame::Null if resp3 => 3,
+            Frame::Null => 5,
+            Frame::Array(parts) | Frame::Set(parts) | Frame::Push(parts) => {
+                aggregate(parts.len(), all(parts))
+            }
+            Frame::Map(pairs) => {
+                let len = if resp3 { pairs.len() } else { pairs.len() * 2 };
+                let parts = pairs
+                    .iter()
+                    .map(|(key, value)| key.encoded_len(protocol) + value.encoded_len(protocol));
+                aggregate(len, parts.sum())
+            }
+            Frame::Double(n) if resp3 => 1 + format_double(*n).len() as u64 + 2,
+            Frame::Double(n) => blob(format_double(*n).len()),
+            Frame::Boolean(_) => 4,
+            Frame::BigNumber(n) if resp3 => 1 + n.len() as u64 + 2,
+            Frame::BigNumber(n) => blob(n.len()),
+            Frame::Verbatim { format, text } if resp3 => blob(format.len() + 1 + text.len()),
+            Frame::Verbatim { text, .. } => blob(text.len()),
+        }
+    }
+}
+
+/// Formats a double the way RESP3 spells infinities and NaN.
+pub fn format_double(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_string()
+    } else {
+        n.to_string()
+    }
+}
+
+fn put_line(out: &mut BytesMut, kind: u8, line: &[u8]) {
+    out.put_u8(kind);
+    out.put_slice(line);
+    out.put_slice(b"\r\n");
+}
+
+fn put_blob(out: &mut BytesMut, kind: u8, data: &[u8]) {
+    put_line(out, kind, data.len().to_string().as_bytes());
+    out.put_slice(data);
+    out.put_slice(b"\r\n");
+}
+
+fn put_aggregate(out: &mut BytesMut, kind: u8, parts: &[Frame], protocol: Protocol) {
+    put_line(out, kind, parts.len().to_string().as_bytes());
+    for part in parts {
+        part.encode(out, protocol);
+    }
+}
+
+pub(crate) fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+    Ok(src.get_u8())
+}
+
+pub(crate) fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+    src.advance(n);
+    Ok(())
+}
+
+/// Reads up to the next CRLF, and moves past it.
+pub(crate) fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+    let data = *src.get_ref();
+    match data[start..].windows(2).position(|pair| pair == b"\r\n") {
+        Some(len) => {
+            src.set_position((start + len + 2) as u64);
+            Ok(&data[start..start + len])
+        }
+        None => Err(Error::Incomplete),
+    }
+}
+
+fn get_string(src: &mut Cursor<&[u8]>) -> Result<String, Error> {
+    String::from_utf8(get_line(src)?.to_vec())
+        .map_err(|_| "protocol error; invalid frame format".into())
+}
+
+pub(crate) fn get_signed(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
+    let line = get_line(src)?;
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(|| "protocol error; invalid frame format".into())
+}
+
+/// Reads the length of a blob or aggregate, `None` for the RESP2 nulls
+/// `$-1` and `*-1`.
+pub(crate) fn get_length(src: &mut Cursor<&[u8]>) -> Result<Option<usize>, Error> {
+    match get_signed(src)? {
+        -1 => Ok(None),
+        len => usize::try_from(len)
+            .map(Some)
+            .map_err(|_| "protocol error; invalid frame format".into()),
+    }
+}
+
+fn get_blob(src: &mut Cursor<&[u8]>, len: usize) -> Result<Bytes, Error> {
+    if src.remaining() < len + 2 {
+        return Err(Error::Incomplete);
+    }
+    let data = Bytes::copy_from_slice(&src.chunk()[..len]);
+    if &src.chunk()[len..len + 2] != b"\r\n" {
+        return Err("protocol error; invalid frame format".into());
+    }
+    src.advance(len + 2);
+    Ok(data)
+}
+
+pub(crate) fn get_boolean(src: &mut Cursor<&[u8]>) -> Result<bool, Error> {
+    match get_line(src)? {
+        b"t" => Ok(true),
+        b"f" => Ok(false),
+        _ => Err("protocol error; invalid boolean".into()),
+    }
+}
+
+pub(crate) fn get_double(src: &mut Cursor<&[u8]>) -> Result<f64, Error> {
+    let line = get_line(src)?;
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(|| "protocol error; invalid double".into())
+}
+
+pub(crate) fn get_null(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
+    match get_line(src)? {
+        b"" => Ok(()),
+        _ => Err("protocol error; invalid null".into()),
+    }
+}
+
+impl From<String> for Error {
+    fn from(src: String) -> Error {
+        Error::Other(src)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(src: &str) -> Error {
+        src.to_string().into()
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Incomplete => "stream ended early".fmt(f),
+            Error::Other(err) => err.fmt(f),
+        }
+    }
+}
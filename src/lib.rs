@@ -0,0 +1,6 @@
+//! Code shared by the binaries in `src/bin`.
+
+pub mod codec;
+pub mod config;
+pub mod frame;
+pub mod slot;
@@ -0,0 +1,58 @@
+//! Hash slots, as Redis Cluster splits the keyspace between nodes.
+
+/// Number of hash slots.
+pub const KEY_SLOTS: u16 = 16384;
+
+/// CRC16 (XMODEM) of the hashtag of `key`, or of the whole key if it has
+/// none, modulo `KEY_SLOTS`.
+///
+/// The hashtag is what lies between the first `{` and the first `}` after
+/// it, if that is not empty. Keys with the same hashtag get the same slot,
+/// so a client can keep the keys of one MSET or transaction together.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tagged = key.iter().position(|&b| b == b'{').and_then(|open| {
+        let tag = &key[open + 1..];
+        let close = tag.iter().position(|&b| b == b'}')?;
+        (close > 0).then(|| &tag[..close])
+    });
+    crc16(tagged.unwrap_or(key)) % KEY_SLOTS
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        let mut crc = crc ^ (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_slots_match_redis_cluster() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b"hello"), 866);
+    }
+
+    #[test]
+    fn key_slots_follow_hashtags() {
+        let slot = key_slot(b"user1000");
+        assert_eq!(key_slot(b"{user1000}.following"), slot);
+        assert_eq!(key_slot(b"{user1000}.followers"), slot);
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+        // An empty or unclosed tag means the whole key is hashed.
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % KEY_SLOTS);
+        assert_eq!(key_slot(b"foo{bar"), crc16(b"foo{bar") % KEY_SLOTS);
+    }
+}